@@ -0,0 +1,787 @@
+use crate::cpu::instruction::{self, Instruction};
+use crate::cpu::machine::Machine;
+use crate::error::EmulatorError;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Deepest chain of includes, or of constants defined in terms of each other,
+// before giving up on a cycle
+const MAX_DEPTH: usize = 32;
+
+// Builds ROM images from the Cowgod-style mnemonics the disassembler prints:
+//
+//   ; Draw a sprite in the middle of the screen
+//   WIDTH  equ 64
+//   start:
+//       LD I, sprite
+//       LD V0, WIDTH - 32
+//       DRW V0, V0, 2
+//   loop:
+//       JP loop
+//   sprite:
+//       db 0x3C, 0x42
+//
+// Besides instructions, a line can hold a label (`name:`), a constant
+// (`name equ value`), data (`db` for bytes, `dw` for big-endian words) or an
+// `include "file"` of further source. Values are numbers in decimal, hex
+// (`0x1F`) or binary (`0b0110`), names of labels and constants, and sums and
+// differences of those. Comments start with `;`.
+#[derive(Clone, Debug)]
+pub struct Assembler {
+    // Instructions beyond this machine are rejected
+    pub machine: Machine,
+    // Address the first byte of output is loaded at, which labels count from
+    pub load_address: u16,
+}
+
+impl Default for Assembler {
+    fn default() -> Assembler {
+        Assembler {
+            machine: Machine::default(),
+            load_address: 0x200,
+        }
+    }
+}
+
+// Where a line of source came from, for error messages
+#[derive(Clone, Debug)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+enum Item {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<String>,
+    },
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+struct Statement {
+    location: Location,
+    item: Item,
+}
+
+// Everything gathered in the first pass: the statements to encode, and the
+// values of labels and constants that the second pass refers to
+#[derive(Default)]
+struct Program {
+    statements: Vec<Statement>,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, String>,
+    size: usize,
+}
+
+impl Assembler {
+    // Assemble source read from a file. Includes are found relative to it.
+    pub fn assemble_file(&self, filename: &str) -> Result<Vec<u8>, EmulatorError> {
+        let source = fs::read_to_string(filename)?;
+
+        self.assemble_from(&source, Path::new(filename))
+    }
+
+    // Assemble source held in memory. Includes are found relative to the
+    // current directory.
+    pub fn assemble(&self, source: &str) -> Result<Vec<u8>, EmulatorError> {
+        self.assemble_from(source, Path::new("<source>"))
+    }
+
+    fn assemble_from(&self, source: &str, path: &Path) -> Result<Vec<u8>, EmulatorError> {
+        let mut program = Program::default();
+        self.read(source, path, &mut program, 0)?;
+
+        let mut output = Vec::with_capacity(program.size);
+
+        for statement in &program.statements {
+            let at = |message: String| error(&statement.location, message);
+
+            match &statement.item {
+                Item::Instruction { mnemonic, operands } => {
+                    let (instruction, long_address) = self
+                        .parse_instruction(mnemonic, operands, &program)
+                        .map_err(at)?;
+
+                    if instruction.machine() > self.machine {
+                        return Err(at(format!(
+                            "{} needs a newer machine than {:?}",
+                            mnemonic, self.machine
+                        )));
+                    }
+
+                    output.extend_from_slice(&instruction.opcode().to_be_bytes());
+
+                    if let Some(address) = long_address {
+                        output.extend_from_slice(&address.to_be_bytes());
+                    }
+
+                    // Every encoding must decode to what was written
+                    debug_assert_eq!(
+                        instruction::lookup(instruction.opcode()).ok(),
+                        Some(instruction)
+                    );
+                }
+                Item::Bytes(values) => {
+                    for value in values {
+                        let value = program.evaluate(value, 0).map_err(at)?;
+                        output.push(fit(value, 0xFF, "byte").map_err(at)? as u8);
+                    }
+                }
+                Item::Words(values) => {
+                    for value in values {
+                        let value = program.evaluate(value, 0).map_err(at)?;
+                        output.extend_from_slice(
+                            &fit(value, 0xFFFF, "word").map_err(at)?.to_be_bytes(),
+                        );
+                    }
+                }
+            }
+        }
+
+        let capacity = self
+            .machine
+            .memory_size()
+            .saturating_sub(self.load_address as usize);
+        if output.len() > capacity {
+            return Err(EmulatorError::RomTooLarge {
+                size: output.len(),
+                capacity,
+            });
+        }
+
+        Ok(output)
+    }
+
+    // First pass: split the source into statements, following includes, and
+    // work out the address of every label.
+    fn read(
+        &self,
+        source: &str,
+        path: &Path,
+        program: &mut Program,
+        depth: usize,
+    ) -> Result<(), EmulatorError> {
+        for (index, line) in source.lines().enumerate() {
+            let location = Location {
+                file: path.display().to_string(),
+                line: index + 1,
+            };
+            let at = |message: String| error(&location, message);
+
+            let mut line = strip_comment(line).trim();
+
+            // Any number of labels can start a line
+            while let Some(position) = line.find(':') {
+                let label = line[..position].trim();
+
+                if !is_name(label) {
+                    break;
+                }
+
+                let address = self.load_address as usize + program.size;
+                if program
+                    .labels
+                    .insert(label.to_string(), address as u16)
+                    .is_some()
+                {
+                    return Err(at(format!("{} is defined more than once", label)));
+                }
+
+                line = line[position + 1..].trim();
+            }
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (first, rest) = split_word(line);
+
+            if first.eq_ignore_ascii_case("include") {
+                if depth >= MAX_DEPTH {
+                    return Err(at(String::from("includes are nested too deeply")));
+                }
+
+                let filename = rest.trim().trim_matches('"');
+                let included: PathBuf = match path.parent() {
+                    Some(directory) => directory.join(filename),
+                    None => PathBuf::from(filename),
+                };
+                let source = fs::read_to_string(&included)
+                    .map_err(|e| at(format!("cannot include {}: {}", included.display(), e)))?;
+
+                self.read(&source, &included, program, depth + 1)?;
+                continue;
+            }
+
+            // `name equ value` and `name = value` define constants
+            let (second, value) = split_word(rest);
+            if second.eq_ignore_ascii_case("equ") || second == "=" {
+                if !is_name(first) {
+                    return Err(at(format!("{} is not a valid name", first)));
+                }
+
+                if program
+                    .constants
+                    .insert(first.to_string(), value.to_string())
+                    .is_some()
+                {
+                    return Err(at(format!("{} is defined more than once", first)));
+                }
+
+                continue;
+            }
+
+            let operands: Vec<String> = if rest.is_empty() {
+                Vec::new()
+            } else {
+                rest.split(',')
+                    .map(|operand| operand.trim().to_string())
+                    .collect()
+            };
+
+            let (item, size) = match first.to_ascii_lowercase().as_str() {
+                "db" => (Item::Bytes(operands.clone()), operands.len()),
+                "dw" => (Item::Words(operands.clone()), operands.len() * 2),
+                _ => {
+                    // Only the long index load is followed by an address
+                    let size = match operands.get(1) {
+                        Some(operand) if split_word(operand).0.eq_ignore_ascii_case("long") => 4,
+                        _ => 2,
+                    };
+
+                    (
+                        Item::Instruction {
+                            mnemonic: first.to_ascii_uppercase(),
+                            operands,
+                        },
+                        size,
+                    )
+                }
+            };
+
+            program.statements.push(Statement { location, item });
+            program.size += size;
+        }
+
+        Ok(())
+    }
+
+    // Turn a mnemonic and its operands into an instruction, and the address
+    // that follows it for the long index load.
+    fn parse_instruction(
+        &self,
+        mnemonic: &str,
+        operands: &[String],
+        program: &Program,
+    ) -> Result<(Instruction, Option<u16>), String> {
+        use Instruction::*;
+        use Operand::*;
+
+        let operands: Vec<Operand> = operands.iter().map(|text| Operand::parse(text)).collect();
+        let value =
+            |text: &str, limit: i64, kind: &str| fit(program.evaluate(text, 0)?, limit, kind);
+        let address = |text: &str| value(text, 0xFFF, "address");
+        let byte = |text: &str| value(text, 0xFF, "byte").map(|value| value as u8);
+        let nibble = |text: &str| value(text, 0xF, "nibble").map(|value| value as u8);
+
+        let instruction = match (mnemonic, operands.as_slice()) {
+            ("NOP", []) => Noop,
+            ("CLS", []) => ClearScreen,
+            ("RET", []) => Return,
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => LowResolution,
+            ("HIGH", []) => HighResolution,
+            ("AUDIO", []) => LoadAudio,
+            ("SCD", [Value(n)]) => ScrollDown { n: nibble(n)? },
+            ("SCU", [Value(n)]) => ScrollUp { n: nibble(n)? },
+            ("PLANE", [Value(n)]) => SelectPlanes { n: nibble(n)? },
+            ("JP", [Value(nnn)]) => Jump { nnn: address(nnn)? },
+            ("JP", [V(0), Value(nnn)]) => JumpOffset { nnn: address(nnn)? },
+            ("CALL", [Value(nnn)]) => Call { nnn: address(nnn)? },
+            ("SE", [V(x), V(y)]) => SkipIfRegistersEqual { x: *x, y: *y },
+            ("SE", [V(x), Value(nn)]) => SkipIfEqual {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("SNE", [V(x), V(y)]) => SkipIfRegistersNotEqual { x: *x, y: *y },
+            ("SNE", [V(x), Value(nn)]) => SkipIfNotEqual {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("LD", [V(x), V(y)]) => Assign { x: *x, y: *y },
+            ("LD", [V(x), Value(nn)]) => SetConstant {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("LD", [I, Value(nnn)]) => SetIndex { nnn: address(nnn)? },
+            ("LD", [I, Long(nnnn)]) => {
+                return Ok((LongLoad, Some(value(nnnn, 0xFFFF, "address")?)));
+            }
+            ("LD", [V(x), DelayTimer]) => GetDelayTimer { x: *x },
+            ("LD", [V(x), Key]) => AwaitKey { x: *x },
+            ("LD", [DelayTimer, V(x)]) => SetDelayTimer { x: *x },
+            ("LD", [SoundTimer, V(x)]) => SetSoundTimer { x: *x },
+            ("LD", [Font, V(x)]) => FontCharacter { x: *x },
+            ("LD", [LargeFont, V(x)]) => LargeFontCharacter { x: *x },
+            ("LD", [Bcd, V(x)]) => BinaryCodedDecimal { x: *x },
+            ("LD", [Indirect, V(x)]) => StoreRegisters { x: *x },
+            ("LD", [V(x), Indirect]) => LoadRegisters { x: *x },
+            ("LD", [Flags, V(x)]) => StoreFlags { x: *x },
+            ("LD", [V(x), Flags]) => LoadFlags { x: *x },
+            ("LD", [Indirect, Range(x, y)]) => StoreRange { x: *x, y: *y },
+            ("LD", [Range(x, y), Indirect]) => LoadRange { x: *x, y: *y },
+            ("ADD", [V(x), V(y)]) => Add { x: *x, y: *y },
+            ("ADD", [V(x), Value(nn)]) => AddConstant {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("ADD", [I, V(x)]) => AddIndex { x: *x },
+            ("OR", [V(x), V(y)]) => Or { x: *x, y: *y },
+            ("AND", [V(x), V(y)]) => And { x: *x, y: *y },
+            ("XOR", [V(x), V(y)]) => Xor { x: *x, y: *y },
+            ("SUB", [V(x), V(y)]) => Subtract { x: *x, y: *y },
+            ("SUBN", [V(x), V(y)]) => SubtractReversed { x: *x, y: *y },
+            ("SHR", [V(x)]) => ShiftRight { x: *x, y: *x },
+            ("SHR", [V(x), V(y)]) => ShiftRight { x: *x, y: *y },
+            ("SHL", [V(x)]) => ShiftLeft { x: *x, y: *x },
+            ("SHL", [V(x), V(y)]) => ShiftLeft { x: *x, y: *y },
+            ("RND", [V(x), Value(nn)]) => Random {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("DRW", [V(x), V(y), Value(n)]) => Draw {
+                x: *x,
+                y: *y,
+                n: nibble(n)?,
+            },
+            ("SKP", [V(x)]) => SkipIfKeyPressed { x: *x },
+            ("SKNP", [V(x)]) => SkipIfKeyNotPressed { x: *x },
+            ("PITCH", [V(x)]) => SetPitch { x: *x },
+            _ => {
+                let operands: Vec<String> =
+                    operands.iter().map(|operand| operand.to_string()).collect();
+                return Err(format!(
+                    "invalid instruction: {} {}",
+                    mnemonic,
+                    operands.join(", ")
+                ));
+            }
+        };
+
+        Ok((instruction, None))
+    }
+}
+
+// An operand as written, before any value in it is worked out.
+enum Operand<'a> {
+    V(u8),
+    // Vx-Vy
+    Range(u8, u8),
+    I,
+    // [I]
+    Indirect,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    LargeFont,
+    Bcd,
+    Flags,
+    Long(&'a str),
+    Value(&'a str),
+}
+
+impl<'a> Operand<'a> {
+    fn parse(text: &'a str) -> Operand<'a> {
+        let register = |text: &str| match text.strip_prefix(['V', 'v']) {
+            Some(digit) if digit.len() == 1 => u8::from_str_radix(digit, 16).ok(),
+            _ => None,
+        };
+
+        if let Some(x) = register(text) {
+            return Operand::V(x);
+        }
+
+        if let Some(position) = text.find('-') {
+            if let (Some(x), Some(y)) = (
+                register(text[..position].trim()),
+                register(text[position + 1..].trim()),
+            ) {
+                return Operand::Range(x, y);
+            }
+        }
+
+        let (first, rest) = split_word(text);
+        if first.eq_ignore_ascii_case("long") && !rest.is_empty() {
+            return Operand::Long(rest);
+        }
+
+        match text.to_ascii_uppercase().as_str() {
+            "I" => Operand::I,
+            "[I]" => Operand::Indirect,
+            "DT" => Operand::DelayTimer,
+            "ST" => Operand::SoundTimer,
+            "K" => Operand::Key,
+            "F" => Operand::Font,
+            "HF" => Operand::LargeFont,
+            "B" => Operand::Bcd,
+            "R" => Operand::Flags,
+            _ => Operand::Value(text),
+        }
+    }
+}
+
+impl<'a> std::fmt::Display for Operand<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Operand::V(x) => write!(f, "V{:X}", x),
+            Operand::Range(x, y) => write!(f, "V{:X}-V{:X}", x, y),
+            Operand::I => write!(f, "I"),
+            Operand::Indirect => write!(f, "[I]"),
+            Operand::DelayTimer => write!(f, "DT"),
+            Operand::SoundTimer => write!(f, "ST"),
+            Operand::Key => write!(f, "K"),
+            Operand::Font => write!(f, "F"),
+            Operand::LargeFont => write!(f, "HF"),
+            Operand::Bcd => write!(f, "B"),
+            Operand::Flags => write!(f, "R"),
+            Operand::Long(value) => write!(f, "LONG {}", value),
+            Operand::Value(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl Program {
+    // Work out a sum or difference of numbers, labels and constants.
+    fn evaluate(&self, text: &str, depth: usize) -> Result<i64, String> {
+        if depth >= MAX_DEPTH {
+            return Err(String::from("constants are defined in terms of each other"));
+        }
+
+        let mut total: i64 = 0;
+        let mut sign = 1;
+        let mut expecting_term = true;
+        let mut rest = text.trim();
+
+        if rest.is_empty() {
+            return Err(String::from("missing value"));
+        }
+
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('+') {
+                rest = after.trim_start();
+                continue;
+            }
+
+            if let Some(after) = rest.strip_prefix('-') {
+                sign = -sign;
+                rest = after.trim_start();
+                continue;
+            }
+
+            if !expecting_term {
+                return Err(format!("expected + or - in {}", text));
+            }
+
+            let end = rest.find(['+', '-', ' ']).unwrap_or(rest.len());
+            let term = &rest[..end];
+
+            let value = match parse_number(term) {
+                Some(value) => value,
+                None => match self.labels.get(term) {
+                    Some(&address) => address as i64,
+                    None => match self.constants.get(term) {
+                        Some(definition) => self.evaluate(definition, depth + 1)?,
+                        None => return Err(format!("unknown name: {}", term)),
+                    },
+                },
+            };
+
+            total = value
+                .checked_mul(sign)
+                .and_then(|value| total.checked_add(value))
+                .ok_or_else(|| format!("value out of range: {}", text))?;
+            sign = 1;
+            expecting_term = false;
+            rest = rest[end..].trim_start();
+
+            // Another term may follow after an operator
+            if rest.starts_with(['+', '-']) {
+                expecting_term = true;
+            }
+        }
+
+        if expecting_term {
+            return Err(format!("missing value after an operator in {}", text));
+        }
+
+        Ok(total)
+    }
+}
+
+// Check a value fits in a field, allowing negative numbers down to the
+// two's complement of the field.
+fn fit(value: i64, limit: i64, kind: &str) -> Result<u16, String> {
+    if value > limit || value < -(limit + 1) / 2 {
+        return Err(format!("{:#X} does not fit in a {}", value, kind));
+    }
+
+    Ok((value & limit) as u16)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    let mut characters = text.chars();
+
+    matches!(characters.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && characters.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find(';') {
+        Some(position) => &line[..position],
+        None => line,
+    }
+}
+
+// Split off the first whitespace-separated word.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+
+    match text.find(char::is_whitespace) {
+        Some(position) => (&text[..position], text[position..].trim()),
+        None => (text, ""),
+    }
+}
+
+fn error(location: &Location, message: String) -> EmulatorError {
+    EmulatorError::InvalidSource {
+        file: location.file.clone(),
+        line: location.line,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::{self, Syntax};
+    use Instruction::*;
+
+    fn xo_chip() -> Assembler {
+        Assembler {
+            machine: Machine::XoChip,
+            ..Assembler::default()
+        }
+    }
+
+    // The line and message of an error assembling `source`
+    fn failure(assembler: &Assembler, source: &str) -> (usize, String) {
+        match assembler.assemble(source) {
+            Err(EmulatorError::InvalidSource { line, message, .. }) => (line, message),
+            result => panic!("expected a source error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn assembles_every_mnemonic() {
+        let cases = [
+            ("NOP", Noop),
+            ("CLS", ClearScreen),
+            ("RET", Return),
+            ("JP 0x345", Jump { nnn: 0x345 }),
+            ("CALL 0x345", Call { nnn: 0x345 }),
+            ("SE V1, 0x22", SkipIfEqual { x: 1, nn: 0x22 }),
+            ("SNE V1, 0x22", SkipIfNotEqual { x: 1, nn: 0x22 }),
+            ("SE V1, V2", SkipIfRegistersEqual { x: 1, y: 2 }),
+            ("LD V1, 0x22", SetConstant { x: 1, nn: 0x22 }),
+            ("ADD V1, 0x22", AddConstant { x: 1, nn: 0x22 }),
+            ("LD V1, V2", Assign { x: 1, y: 2 }),
+            ("OR V1, V2", Or { x: 1, y: 2 }),
+            ("AND V1, V2", And { x: 1, y: 2 }),
+            ("XOR V1, V2", Xor { x: 1, y: 2 }),
+            ("ADD V1, V2", Add { x: 1, y: 2 }),
+            ("SUB V1, V2", Subtract { x: 1, y: 2 }),
+            ("SHR V1, V2", ShiftRight { x: 1, y: 2 }),
+            ("SHR V1", ShiftRight { x: 1, y: 1 }),
+            ("SUBN V1, V2", SubtractReversed { x: 1, y: 2 }),
+            ("SHL V1, V2", ShiftLeft { x: 1, y: 2 }),
+            ("SHL V1", ShiftLeft { x: 1, y: 1 }),
+            ("SNE V1, V2", SkipIfRegistersNotEqual { x: 1, y: 2 }),
+            ("LD I, 0x345", SetIndex { nnn: 0x345 }),
+            ("JP V0, 0x345", JumpOffset { nnn: 0x345 }),
+            ("RND V1, 0x22", Random { x: 1, nn: 0x22 }),
+            ("DRW V1, V2, 3", Draw { x: 1, y: 2, n: 3 }),
+            ("SKP V1", SkipIfKeyPressed { x: 1 }),
+            ("SKNP V1", SkipIfKeyNotPressed { x: 1 }),
+            ("LD V1, DT", GetDelayTimer { x: 1 }),
+            ("LD V1, K", AwaitKey { x: 1 }),
+            ("LD DT, V1", SetDelayTimer { x: 1 }),
+            ("LD ST, V1", SetSoundTimer { x: 1 }),
+            ("ADD I, V1", AddIndex { x: 1 }),
+            ("LD F, V1", FontCharacter { x: 1 }),
+            ("LD B, V1", BinaryCodedDecimal { x: 1 }),
+            ("LD [I], V1", StoreRegisters { x: 1 }),
+            ("LD V1, [I]", LoadRegisters { x: 1 }),
+            ("SCD 3", ScrollDown { n: 3 }),
+            ("SCR", ScrollRight),
+            ("SCL", ScrollLeft),
+            ("EXIT", Exit),
+            ("LOW", LowResolution),
+            ("HIGH", HighResolution),
+            ("LD HF, V1", LargeFontCharacter { x: 1 }),
+            ("LD R, V1", StoreFlags { x: 1 }),
+            ("LD V1, R", LoadFlags { x: 1 }),
+            ("SCU 3", ScrollUp { n: 3 }),
+            ("LD [I], V1-V2", StoreRange { x: 1, y: 2 }),
+            ("LD V1-V2, [I]", LoadRange { x: 1, y: 2 }),
+            ("PLANE 3", SelectPlanes { n: 3 }),
+            ("AUDIO", LoadAudio),
+            ("PITCH V1", SetPitch { x: 1 }),
+        ];
+
+        for (source, expected) in cases.iter() {
+            let bytes = xo_chip().assemble(source).unwrap();
+            let encoded = (bytes[0] as u16) << 8 | bytes[1] as u16;
+
+            assert_eq!(bytes.len(), 2, "{}", source);
+            assert_eq!(
+                instruction::lookup(encoded).ok(),
+                Some(*expected),
+                "{}",
+                source
+            );
+        }
+
+        assert_eq!(
+            xo_chip().assemble("ld i, long 0x1234").unwrap(),
+            [0xF0, 0x00, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn assembles_what_the_disassembler_prints() {
+        let name = |address: u16, digits: usize| format!("0x{:0width$X}", address, width = digits);
+
+        for opcode in 0..=0xFFFF {
+            let instruction = match instruction::lookup(opcode) {
+                Ok(instruction) if instruction.opcode() == opcode => instruction,
+                _ => continue,
+            };
+            let source = disassembler::mnemonic(instruction, 0x1234, Syntax::Cowgod, &name);
+            let bytes = xo_chip().assemble(&source).unwrap();
+
+            assert_eq!(bytes[..2], opcode.to_be_bytes(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn resolves_labels_constants_and_data() {
+        let source = "
+            ; Draw a sprite in the middle of the screen
+            WIDTH  equ 64
+            HALF = WIDTH - 32
+            start:
+                LD I, sprite
+                LD V0, HALF + 2
+                DRW V0, V0, 2
+            loop: JP loop
+            sprite:
+                db 0x3C, 0b01000010, -1
+                dw start
+        ";
+
+        assert_eq!(
+            Assembler::default().assemble(source).unwrap(),
+            [0xA2, 0x08, 0x60, 0x22, 0xD0, 0x02, 0x12, 0x06, 0x3C, 0x42, 0xFF, 0x02, 0x00]
+        );
+    }
+
+    #[test]
+    fn reports_errors_by_line() {
+        let assembler = Assembler::default();
+
+        assert_eq!(
+            failure(&assembler, "CLS\nJP nowhere"),
+            (2, String::from("unknown name: nowhere"))
+        );
+        assert_eq!(
+            failure(&assembler, "a:\na: CLS"),
+            (2, String::from("a is defined more than once"))
+        );
+        assert_eq!(
+            failure(&assembler, "\n\nLD V0, 0x100"),
+            (3, String::from("0x100 does not fit in a byte"))
+        );
+        assert_eq!(
+            failure(&assembler, "MOV V0, V1"),
+            (1, String::from("invalid instruction: MOV V0, V1"))
+        );
+        assert_eq!(
+            failure(&assembler, "HIGH"),
+            (1, String::from("HIGH needs a newer machine than Chip8"))
+        );
+        assert_eq!(
+            failure(&assembler, "CLS\nLD V0, 0x7FFFFFFFFFFFFFFF + 1"),
+            (
+                2,
+                String::from("value out of range: 0x7FFFFFFFFFFFFFFF + 1")
+            )
+        );
+        assert_eq!(
+            failure(&assembler, "BIG equ 0x7FFFFFFFFFFFFFFF\nLD V0, -BIG - 2"),
+            (2, String::from("value out of range: -BIG - 2"))
+        );
+        assert_eq!(
+            failure(&assembler, "A equ B\nB equ A\nLD V0, A"),
+            (
+                3,
+                String::from("constants are defined in terms of each other")
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_programs_too_large_to_load() {
+        let assembler = Assembler {
+            load_address: 0xFFE,
+            ..Assembler::default()
+        };
+        assert!(assembler.assemble("CLS").is_ok());
+        assert!(matches!(
+            assembler.assemble("CLS\nCLS"),
+            Err(EmulatorError::RomTooLarge {
+                size: 4,
+                capacity: 2
+            })
+        ));
+
+        // A load address past the end of memory leaves no room at all
+        let assembler = Assembler {
+            load_address: 0x2000,
+            ..Assembler::default()
+        };
+        assert!(matches!(
+            assembler.assemble("CLS"),
+            Err(EmulatorError::RomTooLarge {
+                size: 2,
+                capacity: 0
+            })
+        ));
+    }
+}
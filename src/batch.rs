@@ -0,0 +1,442 @@
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use crate::hash;
+use crate::input::Keypad;
+use crate::scheduler::Scheduler;
+use std::fmt;
+use std::fs;
+
+// A keypad key held down for a number of frames, starting at the beginning
+// of a frame. Frames are counted from the start of the run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyPress {
+    pub frame: u64,
+    pub key: u8,
+    pub frames: u64,
+}
+
+impl KeyPress {
+    // Parse a press written as `FRAME KEY [FRAMES]` or `FRAME:KEY[:FRAMES]`,
+    // with the key as a hex digit. Keys are held for one frame unless told
+    // otherwise.
+    pub fn parse(text: &str) -> Result<KeyPress, String> {
+        let fields: Vec<&str> = text
+            .split(|c: char| c == ':' || c.is_whitespace())
+            .filter(|field| !field.is_empty())
+            .collect();
+
+        let number = |text: &str| {
+            text.parse::<u64>()
+                .map_err(|_| format!("expected a frame count, got {}", text))
+        };
+
+        let (frame, key, frames) = match fields.as_slice() {
+            [frame, key] => (number(frame)?, key, 1),
+            [frame, key, frames] => (number(frame)?, key, number(frames)?),
+            _ => return Err(String::from("expected `frame key [frames]`")),
+        };
+
+        let key = match u8::from_str_radix(key, 16) {
+            Ok(key) if key <= 0xF => key,
+            _ => return Err(String::from("key must be a hex digit from 0 to F")),
+        };
+
+        Ok(KeyPress { frame, key, frames })
+    }
+
+    fn is_held(&self, frame: u64) -> bool {
+        frame >= self.frame && frame - self.frame < self.frames
+    }
+}
+
+// Key presses to play back during a run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    pub presses: Vec<KeyPress>,
+}
+
+impl KeyScript {
+    pub fn load(filename: &str) -> Result<KeyScript, EmulatorError> {
+        KeyScript::parse(&fs::read_to_string(filename)?)
+    }
+
+    // Parse a script with one press per line, as accepted by
+    // `KeyPress::parse`:
+    //
+    //   # Start the game, then hold right for half a second
+    //   30 5
+    //   90 6 30
+    //
+    // Blank lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> Result<KeyScript, EmulatorError> {
+        let mut script = KeyScript::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let press = KeyPress::parse(line).map_err(|message| EmulatorError::InvalidConfig {
+                line: index + 1,
+                message,
+            })?;
+            script.presses.push(press);
+        }
+
+        Ok(script)
+    }
+
+    // Hold the keys pressed during a frame, and release the rest.
+    fn update(&self, frame: u64, keypad: &mut Keypad) {
+        for key in 0..16 {
+            keypad.release(key);
+        }
+
+        for press in self.presses.iter().filter(|press| press.is_held(frame)) {
+            keypad.press(press.key);
+        }
+    }
+}
+
+// Run a program for a number of frames, or until it ends or reaches the
+// scheduler's cycle limit, playing back the key script. Without a frame
+// count only the program ending or the cycle limit stops it.
+pub fn run(
+    cpu: &mut CPU,
+    scheduler: &mut Scheduler,
+    frames: Option<u64>,
+    script: &KeyScript,
+) -> Result<Report, EmulatorError> {
+    let mut keypad = Keypad::default();
+    let mut finished = false;
+
+    for frame in 0.. {
+        if frames.is_some_and(|frames| frame >= frames) {
+            break;
+        }
+
+        script.update(frame, &mut keypad);
+        keypad.apply(cpu);
+
+        if scheduler.run_frame(cpu)? {
+            finished = true;
+            break;
+        }
+    }
+
+    Ok(Report::capture(cpu, scheduler, finished))
+}
+
+// The state of a machine at the end of a run, as checked by tests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    // Whether the program ended or hit the cycle limit before the frames ran
+    // out
+    pub finished: bool,
+    pub frames: u64,
+    pub cycles: u64,
+    pub pc: u16,
+    pub i: u16,
+    pub sp: u16,
+    pub v: [u8; 16],
+    pub delay_timer: u16,
+    pub sound_timer: u16,
+    pub hires: bool,
+    // Hash of the framebuffer being displayed
+    pub display: u64,
+}
+
+impl Report {
+    pub fn capture(cpu: &CPU, scheduler: &Scheduler, finished: bool) -> Report {
+        let (gfx, _, _) = cpu.display();
+
+        Report {
+            finished,
+            frames: scheduler.frame,
+            cycles: scheduler.cycles,
+            pc: cpu.pc,
+            i: cpu.i,
+            sp: cpu.sp,
+            v: cpu.v,
+            delay_timer: cpu.delay_timer,
+            sound_timer: cpu.sound_timer,
+            hires: cpu.hires,
+            display: hash::fnv1a(gfx),
+        }
+    }
+
+    // Every value in the report by name, in the order they are written
+    pub fn fields(&self) -> Vec<(String, u64)> {
+        let mut fields = vec![
+            (String::from("finished"), self.finished as u64),
+            (String::from("frames"), self.frames),
+            (String::from("cycles"), self.cycles),
+            (String::from("pc"), self.pc as u64),
+            (String::from("i"), self.i as u64),
+            (String::from("sp"), self.sp as u64),
+        ];
+
+        for (x, value) in self.v.iter().enumerate() {
+            fields.push((format!("v{:x}", x), *value as u64));
+        }
+
+        fields.extend(vec![
+            (String::from("dt"), self.delay_timer as u64),
+            (String::from("st"), self.sound_timer as u64),
+            (String::from("hires"), self.hires as u64),
+            (String::from("display"), self.display),
+        ]);
+
+        fields
+    }
+
+    // Compare the report with expected values, describing every one that
+    // differs.
+    pub fn check(&self, expectations: &[Expectation]) -> Vec<String> {
+        let fields = self.fields();
+
+        expectations
+            .iter()
+            .filter_map(|expected| {
+                let found = fields
+                    .iter()
+                    .find(|(name, _)| *name == expected.name)
+                    .map(|(_, value)| *value);
+
+                match found {
+                    Some(value) if value == expected.value => None,
+                    Some(value) => Some(format!(
+                        "{}: expected {}, found {}",
+                        expected.name,
+                        format_value(&expected.name, expected.value),
+                        format_value(&expected.name, value)
+                    )),
+                    None => Some(format!("{}: no such value", expected.name)),
+                }
+            })
+            .collect()
+    }
+}
+
+// Writes one `name=value` line per field, which `parse_expectations` reads
+// back, so a report saved from a good run can be checked against later ones.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in self.fields() {
+            writeln!(f, "{}={}", name, format_value(&name, value))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn format_value(name: &str, value: u64) -> String {
+    match name {
+        "pc" | "i" => format!("{:#06X}", value),
+        "display" => format!("{:#018X}", value),
+        _ if name.starts_with('v') => format!("{:#04X}", value),
+        _ => value.to_string(),
+    }
+}
+
+// A value a report is expected to hold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expectation {
+    pub name: String,
+    pub value: u64,
+}
+
+impl Expectation {
+    // Parse `name=value`, with the value in decimal or in hex after `0x`.
+    pub fn parse(text: &str) -> Result<Expectation, String> {
+        let (name, value) = match text.find('=') {
+            Some(position) => (text[..position].trim(), text[position + 1..].trim()),
+            None => return Err(String::from("expected `name=value`")),
+        };
+
+        let parsed = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => value.parse(),
+        };
+
+        match parsed {
+            Ok(value) => Ok(Expectation {
+                name: name.to_ascii_lowercase(),
+                value,
+            }),
+            Err(_) => Err(format!("expected a number, got {}", value)),
+        }
+    }
+}
+
+// Parse expected values, one per line as accepted by `Expectation::parse`.
+// Blank lines and lines starting with `#` are ignored.
+pub fn parse_expectations(text: &str) -> Result<Vec<Expectation>, EmulatorError> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            Expectation::parse(line).map_err(|message| EmulatorError::InvalidConfig {
+                line: index + 1,
+                message,
+            })
+        })
+        .collect()
+}
+
+// Draw the framebuffer being displayed as text, one character per pixel.
+// Pixels are `.` when off, and `#`, `+` or `@` when lit in the first, second
+// or both XO-CHIP planes.
+pub fn screen(cpu: &CPU) -> String {
+    let (gfx, width, _) = cpu.display();
+
+    gfx.chunks(width)
+        .map(|row| {
+            let mut line: String = row
+                .iter()
+                .map(|&pixel| ['.', '#', '+', '@'][(pixel & 0b11) as usize])
+                .collect();
+            line.push('\n');
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::cpu::machine::Machine;
+    use crate::cpu::quirks::Quirks;
+
+    // Waits for key 5, then sets V0 and exits
+    const SOURCE: &str = "
+            LD V2, 5
+        wait:
+            SKP V2
+            JP wait
+            LD V0, 0x2A
+            EXIT
+    ";
+
+    fn waiting_cpu() -> CPU {
+        let assembler = Assembler {
+            machine: Machine::SuperChip,
+            ..Assembler::default()
+        };
+        let mut cpu = CPU {
+            machine: Machine::SuperChip,
+            quirks: Quirks::super_chip(),
+            ..CPU::default()
+        };
+        cpu.load_program_bytes(&assembler.assemble(SOURCE).unwrap())
+            .unwrap();
+        cpu
+    }
+
+    fn config_error(result: Result<impl fmt::Debug, EmulatorError>) -> (usize, String) {
+        match result {
+            Err(EmulatorError::InvalidConfig { line, message }) => (line, message),
+            result => panic!("expected a configuration error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn plays_back_key_scripts() {
+        let mut cpu = waiting_cpu();
+        let mut scheduler = Scheduler::new(600);
+        let script = KeyScript::parse("# Press 5 on frame 30\n30 5\n").unwrap();
+
+        let report = run(&mut cpu, &mut scheduler, Some(100), &script).unwrap();
+        let expectations = parse_expectations("finished=1\nframes=30\npc=0x020A\nv0=0x2a").unwrap();
+
+        assert!(report.check(&expectations).is_empty());
+        assert_eq!(
+            report.check(&[Expectation::parse("v0=1").unwrap()]),
+            ["v0: expected 0x01, found 0x2A"]
+        );
+        assert_eq!(
+            report.check(&[Expectation::parse("vz=1").unwrap()]),
+            ["vz: no such value"]
+        );
+    }
+
+    #[test]
+    fn stops_after_the_frames_run_out() {
+        let mut cpu = waiting_cpu();
+        let mut scheduler = Scheduler::new(600);
+
+        let report = run(&mut cpu, &mut scheduler, Some(20), &KeyScript::default()).unwrap();
+
+        assert!(!report.finished);
+        assert_eq!((report.frames, report.cycles, report.v[0]), (20, 200, 0));
+    }
+
+    #[test]
+    fn reads_back_written_reports() {
+        let mut cpu = waiting_cpu();
+        let mut scheduler = Scheduler::new(600);
+        let report = run(&mut cpu, &mut scheduler, Some(5), &KeyScript::default()).unwrap();
+
+        let expectations = parse_expectations(&report.to_string()).unwrap();
+
+        assert_eq!(expectations.len(), report.fields().len());
+        assert!(report.check(&expectations).is_empty());
+    }
+
+    #[test]
+    fn parses_key_presses() {
+        assert_eq!(
+            KeyScript::parse("10 a\n\n20:F:3").unwrap().presses,
+            [
+                KeyPress {
+                    frame: 10,
+                    key: 0xA,
+                    frames: 1
+                },
+                KeyPress {
+                    frame: 20,
+                    key: 0xF,
+                    frames: 3
+                }
+            ]
+        );
+        assert_eq!(
+            config_error(KeyScript::parse("# Start\n\n10 G")),
+            (3, String::from("key must be a hex digit from 0 to F"))
+        );
+        assert_eq!(
+            config_error(KeyScript::parse("10 1\nsoon 1")),
+            (2, String::from("expected a frame count, got soon"))
+        );
+        assert_eq!(
+            config_error(KeyScript::parse("10")),
+            (1, String::from("expected `frame key [frames]`"))
+        );
+    }
+
+    #[test]
+    fn parses_expectations() {
+        assert_eq!(
+            Expectation::parse(" PC = 0x200 ").unwrap(),
+            Expectation {
+                name: String::from("pc"),
+                value: 0x200
+            }
+        );
+        assert_eq!(
+            config_error(parse_expectations("pc=0x200\n# Registers\nv0")),
+            (3, String::from("expected `name=value`"))
+        );
+        assert_eq!(
+            config_error(parse_expectations("v0=ten")),
+            (1, String::from("expected a number, got ten"))
+        );
+    }
+}
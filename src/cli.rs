@@ -0,0 +1,695 @@
+use chip8::batch::{Expectation, KeyPress};
+use chip8::cpu::machine::Machine;
+use chip8::cpu::quirks::Quirks;
+use chip8::disassembler::Syntax;
+use chip8::scheduler::DEFAULT_INSTRUCTIONS_PER_SECOND;
+use chip8::screenshot::{Format, Palette, DEFAULT_SCALE, MAX_SCALE};
+use std::path::Path;
+
+pub const USAGE: &str = "Usage: chip8 [run] [OPTIONS] <ROM>
+       chip8 disasm [OPTIONS] <ROM>
+       chip8 asm [OPTIONS] <SOURCE>
+       chip8 test [OPTIONS] <ROM>
+
+Options:
+  -s, --ips <N>            Instructions to execute per second (default 700)
+  -f, --frontend <NAME>    terminal, braille or headless (default terminal)
+      --headless           Run without a frontend, as fast as possible
+  -d, --debug              Run under the interactive debugger on stdin
+      --gdb <PORT>         Wait for a GDB remote debugger on localhost PORT
+  -m, --machine <NAME>     chip8, schip or xochip instruction set (default chip8)
+  -q, --quirks <PROFILE>   vip, chip48, schip or xochip interpreter behaviour
+                           (default: the one matching the machine)
+      --flags-dir <DIR>    Keep SUPER-CHIP RPL flags in DIR (default
+                           $XDG_DATA_HOME/chip8/flags)
+  -k, --keymap <FILE>      Read host key to keypad bindings from FILE
+      --state <FILE>       Save and restore state in FILE with Ctrl-S and Ctrl-L
+                           (default: the ROM path followed by .state)
+  -r, --resume             Restore the state file before starting
+      --rewind <SECONDS>   Keep SECONDS of history to rewind with Backspace
+                           (default 10, 0 to disable)
+      --screenshot <FILE>  Save the display to FILE with Ctrl-P, or when a
+                           headless run ends (default: the ROM path followed
+                           by .png); .png, .pbm and .ppm are supported
+      --record <FILE>      Record the display to FILE as an animated GIF
+      --scale <N>          Enlarge screenshot and recording pixels N times
+                           (default 4)
+      --palette <COLOURS>  Two or four RRGGBB screenshot and recording colours,
+                           separated by commas (default: white on black, with
+                           greys for XO-CHIP's second plane)
+  -t, --trace              Log every executed instruction to stderr
+      --trace-json <FILE>  Write every executed instruction to FILE as JSON lines
+  -n, --max-cycles <N>     Stop after executing N instructions
+  -h, --help               Print this message";
+
+pub const DISASSEMBLE_USAGE: &str = "Usage: chip8 disasm [OPTIONS] <ROM>
+
+Options:
+  -m, --machine <NAME>     chip8, schip or xochip instruction set (default chip8)
+      --syntax <NAME>      cowgod or octo mnemonics (default cowgod)
+  -l, --labels             Name jump, call and data targets
+      --source             Leave out addresses and raw bytes, giving source
+                           that can be assembled again
+  -o, --output <FILE>      Write to FILE instead of stdout
+  -h, --help               Print this message";
+
+pub const ASSEMBLE_USAGE: &str = "Usage: chip8 asm [OPTIONS] <SOURCE>
+
+Options:
+  -m, --machine <NAME>     chip8, schip or xochip instruction set (default chip8)
+      --syntax <NAME>      cowgod mnemonics or octo source (default: octo for
+                           .8o files, cowgod otherwise)
+  -o, --output <FILE>      Write the program to FILE (default: the source path
+                           with a .ch8 extension)
+  -h, --help               Print this message";
+
+pub const TEST_USAGE: &str = "Usage: chip8 test [OPTIONS] <ROM>
+
+Runs a program without a frontend and prints its final state as name=value
+lines, which --expect-file accepts to check later runs against.
+
+Options:
+      --frames <N>         Run for N frames of 1/60th of a second (default 600,
+                           or unlimited with --max-cycles)
+  -n, --max-cycles <N>     Stop after executing N instructions
+  -s, --ips <N>            Instructions to execute per second (default 700)
+  -m, --machine <NAME>     chip8, schip or xochip instruction set (default chip8)
+  -q, --quirks <PROFILE>   vip, chip48, schip or xochip interpreter behaviour
+                           (default: the one matching the machine)
+      --press <F:KEY[:N]>  Hold hex KEY for N frames (default 1) from frame F
+      --keys <FILE>        Read presses from FILE, one `F KEY [N]` per line
+      --expect <NAME=VALUE>
+                           Fail unless the final state has NAME equal to VALUE
+      --expect-file <FILE> Read expected NAME=VALUE lines from FILE
+      --screen             Also print the display as text
+      --screenshot <FILE>  Save the final display to FILE as .png, .pbm or .ppm
+      --scale <N>          Enlarge screenshot pixels N times (default 4)
+      --palette <COLOURS>  Two or four RRGGBB screenshot colours, separated by
+                           commas
+  -h, --help               Print this message";
+
+pub enum Command {
+    Run(Options),
+    Disassemble(DisassembleOptions),
+    Assemble(AssembleOptions),
+    Test(TestOptions),
+}
+
+// A command line that could not be parsed, and the usage to show with it
+pub struct UsageError {
+    pub message: String,
+    pub usage: &'static str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Frontend {
+    Terminal,
+    Braille,
+    Headless,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub rom: String,
+    pub instructions_per_second: u32,
+    pub frontend: Frontend,
+    pub debug: bool,
+    pub gdb_port: Option<u16>,
+    pub machine: Machine,
+    pub quirks: Option<Quirks>,
+    pub keymap: Option<String>,
+    pub flags_dir: Option<String>,
+    pub state: Option<String>,
+    pub resume: bool,
+    pub rewind_seconds: u32,
+    pub screenshot: Option<String>,
+    pub record: Option<String>,
+    pub scale: usize,
+    pub palette: Option<Palette>,
+    pub trace: bool,
+    pub trace_json: Option<String>,
+    pub max_cycles: Option<u64>,
+    pub help: bool,
+}
+
+#[derive(Debug)]
+pub struct DisassembleOptions {
+    pub rom: String,
+    pub machine: Machine,
+    pub syntax: Syntax,
+    pub labels: bool,
+    pub source: bool,
+    pub output: Option<String>,
+    pub help: bool,
+}
+
+#[derive(Debug)]
+pub struct AssembleOptions {
+    pub source: String,
+    pub machine: Machine,
+    pub syntax: Option<Syntax>,
+    pub output: Option<String>,
+    pub help: bool,
+}
+
+#[derive(Debug)]
+pub struct TestOptions {
+    pub rom: String,
+    pub frames: Option<u64>,
+    pub max_cycles: Option<u64>,
+    pub instructions_per_second: u32,
+    pub machine: Machine,
+    pub quirks: Option<Quirks>,
+    pub presses: Vec<KeyPress>,
+    pub keys: Option<String>,
+    pub expectations: Vec<Expectation>,
+    pub expect_file: Option<String>,
+    pub screen: bool,
+    pub screenshot: Option<String>,
+    pub scale: usize,
+    pub palette: Option<Palette>,
+    pub help: bool,
+}
+
+// Parse the command line arguments, not including the program name. Without a
+// command name the arguments are for `run`.
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, UsageError> {
+    let mut args = args.peekable();
+
+    let (parsed, usage) = match args.peek().map(String::as_str) {
+        Some("disasm") => {
+            args.next();
+            (
+                parse_disassemble(Arguments::new(args)).map(Command::Disassemble),
+                DISASSEMBLE_USAGE,
+            )
+        }
+        Some("asm") => {
+            args.next();
+            (
+                parse_assemble(Arguments::new(args)).map(Command::Assemble),
+                ASSEMBLE_USAGE,
+            )
+        }
+        Some("test") => {
+            args.next();
+            (
+                parse_test(Arguments::new(args)).map(Command::Test),
+                TEST_USAGE,
+            )
+        }
+        Some("run") => {
+            args.next();
+            (parse_run(Arguments::new(args)).map(Command::Run), USAGE)
+        }
+        _ => (parse_run(Arguments::new(args)).map(Command::Run), USAGE),
+    };
+
+    parsed.map_err(|message| UsageError { message, usage })
+}
+
+fn parse_run<I: Iterator<Item = String>>(mut args: Arguments<I>) -> Result<Options, String> {
+    let mut rom = None;
+    let mut options = Options {
+        rom: String::new(),
+        instructions_per_second: DEFAULT_INSTRUCTIONS_PER_SECOND,
+        frontend: Frontend::Terminal,
+        debug: false,
+        gdb_port: None,
+        machine: Machine::default(),
+        quirks: None,
+        keymap: None,
+        flags_dir: None,
+        state: None,
+        resume: false,
+        rewind_seconds: 10,
+        screenshot: None,
+        record: None,
+        scale: DEFAULT_SCALE,
+        palette: None,
+        trace: false,
+        trace_json: None,
+        max_cycles: None,
+        help: false,
+    };
+
+    while let Some((arg, name)) = args.next() {
+        let mut value = || args.value(&name);
+
+        match name.as_str() {
+            "-s" | "--ips" => options.instructions_per_second = parse_rate(&value()?)?,
+            "-f" | "--frontend" => {
+                options.frontend = match value()?.as_str() {
+                    "terminal" => Frontend::Terminal,
+                    "braille" => Frontend::Braille,
+                    "headless" => Frontend::Headless,
+                    other => return Err(format!("Unknown frontend: {}", other)),
+                }
+            }
+            "--headless" => options.frontend = Frontend::Headless,
+            "-d" | "--debug" => options.debug = true,
+            "--gdb" => options.gdb_port = Some(parse_number(&value()?)?),
+            "-m" | "--machine" => {
+                let name = value()?;
+                options.machine = Machine::from_name(&name)
+                    .ok_or_else(|| format!("Unknown machine: {}", name))?;
+            }
+            "-q" | "--quirks" => {
+                let profile = value()?;
+                options.quirks = Some(
+                    Quirks::preset(&profile)
+                        .ok_or_else(|| format!("Unknown quirk profile: {}", profile))?,
+                );
+            }
+            "-k" | "--keymap" => options.keymap = Some(value()?),
+            "--flags-dir" => options.flags_dir = Some(value()?),
+            "--state" => options.state = Some(value()?),
+            "-r" | "--resume" => options.resume = true,
+            "--rewind" => options.rewind_seconds = parse_number(&value()?)?,
+            "--screenshot" => options.screenshot = Some(parse_screenshot(value()?)?),
+            "--record" => options.record = Some(value()?),
+            "--scale" => options.scale = parse_scale(&value()?)?,
+            "--palette" => options.palette = Some(parse_palette(&value()?)?),
+            "-t" | "--trace" => options.trace = true,
+            "--trace-json" => options.trace_json = Some(value()?),
+            "-n" | "--max-cycles" => options.max_cycles = Some(parse_number(&value()?)?),
+            "-h" | "--help" => options.help = true,
+            _ if name.starts_with('-') && name.len() > 1 => {
+                return Err(format!("Unknown option: {}", name))
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    match rom {
+        Some(rom) => options.rom = rom,
+        None if options.help => (),
+        None => return Err(String::from("No ROM given")),
+    }
+
+    Ok(options)
+}
+
+fn parse_disassemble<I: Iterator<Item = String>>(
+    mut args: Arguments<I>,
+) -> Result<DisassembleOptions, String> {
+    let mut rom = None;
+    let mut options = DisassembleOptions {
+        rom: String::new(),
+        machine: Machine::default(),
+        syntax: Syntax::default(),
+        labels: false,
+        source: false,
+        output: None,
+        help: false,
+    };
+
+    while let Some((arg, name)) = args.next() {
+        let mut value = || args.value(&name);
+
+        match name.as_str() {
+            "-m" | "--machine" => {
+                let name = value()?;
+                options.machine = Machine::from_name(&name)
+                    .ok_or_else(|| format!("Unknown machine: {}", name))?;
+            }
+            "--syntax" => {
+                let name = value()?;
+                options.syntax =
+                    Syntax::from_name(&name).ok_or_else(|| format!("Unknown syntax: {}", name))?;
+            }
+            "-l" | "--labels" => options.labels = true,
+            "--source" => options.source = true,
+            "-o" | "--output" => options.output = Some(value()?),
+            "-h" | "--help" => options.help = true,
+            _ if name.starts_with('-') && name.len() > 1 => {
+                return Err(format!("Unknown option: {}", name))
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    match rom {
+        Some(rom) => options.rom = rom,
+        None if options.help => (),
+        None => return Err(String::from("No ROM given")),
+    }
+
+    Ok(options)
+}
+
+fn parse_assemble<I: Iterator<Item = String>>(
+    mut args: Arguments<I>,
+) -> Result<AssembleOptions, String> {
+    let mut source = None;
+    let mut options = AssembleOptions {
+        source: String::new(),
+        machine: Machine::default(),
+        syntax: None,
+        output: None,
+        help: false,
+    };
+
+    while let Some((arg, name)) = args.next() {
+        let mut value = || args.value(&name);
+
+        match name.as_str() {
+            "-m" | "--machine" => {
+                let name = value()?;
+                options.machine = Machine::from_name(&name)
+                    .ok_or_else(|| format!("Unknown machine: {}", name))?;
+            }
+            "--syntax" => {
+                let name = value()?;
+                options.syntax = Some(
+                    Syntax::from_name(&name).ok_or_else(|| format!("Unknown syntax: {}", name))?,
+                );
+            }
+            "-o" | "--output" => options.output = Some(value()?),
+            "-h" | "--help" => options.help = true,
+            _ if name.starts_with('-') && name.len() > 1 => {
+                return Err(format!("Unknown option: {}", name))
+            }
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    match source {
+        Some(source) => options.source = source,
+        None if options.help => (),
+        None => return Err(String::from("No source given")),
+    }
+
+    Ok(options)
+}
+
+fn parse_test<I: Iterator<Item = String>>(mut args: Arguments<I>) -> Result<TestOptions, String> {
+    let mut rom = None;
+    let mut options = TestOptions {
+        rom: String::new(),
+        frames: None,
+        max_cycles: None,
+        instructions_per_second: DEFAULT_INSTRUCTIONS_PER_SECOND,
+        machine: Machine::default(),
+        quirks: None,
+        presses: Vec::new(),
+        keys: None,
+        expectations: Vec::new(),
+        expect_file: None,
+        screen: false,
+        screenshot: None,
+        scale: DEFAULT_SCALE,
+        palette: None,
+        help: false,
+    };
+
+    while let Some((arg, name)) = args.next() {
+        let mut value = || args.value(&name);
+
+        match name.as_str() {
+            "--frames" => options.frames = Some(parse_number(&value()?)?),
+            "-n" | "--max-cycles" => options.max_cycles = Some(parse_number(&value()?)?),
+            "-s" | "--ips" => options.instructions_per_second = parse_rate(&value()?)?,
+            "-m" | "--machine" => {
+                let name = value()?;
+                options.machine = Machine::from_name(&name)
+                    .ok_or_else(|| format!("Unknown machine: {}", name))?;
+            }
+            "-q" | "--quirks" => {
+                let profile = value()?;
+                options.quirks = Some(
+                    Quirks::preset(&profile)
+                        .ok_or_else(|| format!("Unknown quirk profile: {}", profile))?,
+                );
+            }
+            "--press" => {
+                let press = value()?;
+                options.presses.push(
+                    KeyPress::parse(&press)
+                        .map_err(|message| format!("Invalid key press {}: {}", press, message))?,
+                );
+            }
+            "--keys" => options.keys = Some(value()?),
+            "--expect" => {
+                let expected = value()?;
+                options.expectations.push(
+                    Expectation::parse(&expected).map_err(|message| {
+                        format!("Invalid expectation {}: {}", expected, message)
+                    })?,
+                );
+            }
+            "--expect-file" => options.expect_file = Some(value()?),
+            "--screen" => options.screen = true,
+            "--screenshot" => options.screenshot = Some(parse_screenshot(value()?)?),
+            "--scale" => options.scale = parse_scale(&value()?)?,
+            "--palette" => options.palette = Some(parse_palette(&value()?)?),
+            "-h" | "--help" => options.help = true,
+            _ if name.starts_with('-') && name.len() > 1 => {
+                return Err(format!("Unknown option: {}", name))
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    match rom {
+        Some(rom) => options.rom = rom,
+        None if options.help => (),
+        None => return Err(String::from("No ROM given")),
+    }
+
+    // Something has to stop the run
+    if options.frames.is_none() && options.max_cycles.is_none() {
+        options.frames = Some(600);
+    }
+
+    Ok(options)
+}
+
+// Walks the command line, accepting both `--option value` and
+// `--option=value`.
+struct Arguments<I: Iterator<Item = String>> {
+    args: I,
+    inline_value: Option<String>,
+}
+
+impl<I: Iterator<Item = String>> Arguments<I> {
+    fn new(args: I) -> Arguments<I> {
+        Arguments {
+            args,
+            inline_value: None,
+        }
+    }
+
+    // The next argument as given, and its name with any inline value removed
+    fn next(&mut self) -> Option<(String, String)> {
+        let arg = self.args.next()?;
+
+        self.inline_value = None;
+
+        match arg.find('=') {
+            Some(position) if arg.starts_with("--") => {
+                self.inline_value = Some(arg[position + 1..].to_string());
+                let name = arg[..position].to_string();
+                Some((arg, name))
+            }
+            _ => Some((arg.clone(), arg)),
+        }
+    }
+
+    // The value of the option `name` just returned by `next`
+    fn value(&mut self, name: &str) -> Result<String, String> {
+        self.inline_value
+            .take()
+            .or_else(|| self.args.next())
+            .ok_or_else(|| format!("Missing value for {}", name))
+    }
+}
+
+impl Options {
+    // Where save states for the ROM are kept
+    pub fn state_file(&self) -> String {
+        match &self.state {
+            Some(filename) => filename.clone(),
+            None => format!("{}.state", self.rom),
+        }
+    }
+
+    // Where screenshots of the ROM are saved
+    pub fn screenshot_file(&self) -> String {
+        match &self.screenshot {
+            Some(filename) => filename.clone(),
+            None => format!("{}.png", self.rom),
+        }
+    }
+}
+
+impl AssembleOptions {
+    // The syntax the source is written in
+    pub fn syntax(&self) -> Syntax {
+        match self.syntax {
+            Some(syntax) => syntax,
+            None if is_octo(&self.source) => Syntax::Octo,
+            None => Syntax::Cowgod,
+        }
+    }
+
+    // Where the assembled program is written
+    pub fn output_file(&self) -> String {
+        match &self.output {
+            Some(filename) => filename.clone(),
+            None => Path::new(&self.source)
+                .with_extension("ch8")
+                .display()
+                .to_string(),
+        }
+    }
+}
+
+// Octo source files, which are compiled rather than loaded as they are
+pub fn is_octo(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .is_some_and(|extension| extension == "8o")
+}
+
+fn parse_screenshot(filename: String) -> Result<String, String> {
+    match Format::from_filename(&filename) {
+        Some(_) => Ok(filename),
+        None => Err(format!(
+            "Screenshots must end in .png, .pbm or .ppm: {}",
+            filename
+        )),
+    }
+}
+
+fn parse_palette(text: &str) -> Result<Palette, String> {
+    Palette::parse(text).map_err(|message| format!("Invalid palette: {}", message))
+}
+
+fn parse_rate(text: &str) -> Result<u32, String> {
+    match parse_number(text)? {
+        0 => Err(String::from("Instructions per second must be at least 1")),
+        rate => Ok(rate),
+    }
+}
+
+fn parse_scale(text: &str) -> Result<usize, String> {
+    match parse_number(text)? {
+        scale @ 1..=MAX_SCALE => Ok(scale),
+        _ => Err(format!("Scale must be between 1 and {}", MAX_SCALE)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("Expected a number, got: {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|arg| arg.to_string())).map_err(|e| e.message)
+    }
+
+    fn run_options(args: &[&str]) -> Options {
+        match parse_args(args) {
+            Ok(Command::Run(options)) => options,
+            _ => panic!("{:?} did not parse as a run", args),
+        }
+    }
+
+    #[test]
+    fn runs_without_a_command_name() {
+        let options = run_options(&["pong.ch8"]);
+
+        assert_eq!(options.rom, "pong.ch8");
+        assert_eq!(options.instructions_per_second, 700);
+        assert_eq!(options.frontend, Frontend::Terminal);
+        assert_eq!(options.state_file(), "pong.ch8.state");
+    }
+
+    #[test]
+    fn accepts_inline_values() {
+        let options = run_options(&["run", "--ips=1000", "-m", "xochip", "--headless", "a.ch8"]);
+
+        assert_eq!(options.instructions_per_second, 1000);
+        assert_eq!(options.machine, Machine::XoChip);
+        assert_eq!(options.frontend, Frontend::Headless);
+    }
+
+    #[test]
+    fn rejects_a_rate_of_zero() {
+        for args in &[
+            &["--ips", "0", "a.ch8"][..],
+            &["test", "-s", "0", "a.ch8"][..],
+        ] {
+            assert_eq!(
+                parse_args(args).err().as_deref(),
+                Some("Instructions per second must be at least 1")
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_scales_a_gif_cannot_hold() {
+        for args in &[
+            &["--scale", "0", "a.ch8"][..],
+            &["--scale", "512", "a.ch8"][..],
+            &["test", "--scale", "100000000000", "a.ch8"][..],
+        ] {
+            assert_eq!(
+                parse_args(args).err().as_deref(),
+                Some("Scale must be between 1 and 511")
+            );
+        }
+
+        assert_eq!(run_options(&["--scale", "511", "a.ch8"]).scale, MAX_SCALE);
+    }
+
+    #[test]
+    fn reports_bad_command_lines() {
+        let error = |args: &[&str]| parse_args(args).err().unwrap();
+
+        assert_eq!(error(&[]), "No ROM given");
+        assert_eq!(error(&["--bogus", "a.ch8"]), "Unknown option: --bogus");
+        assert_eq!(error(&["a.ch8", "b.ch8"]), "Unexpected argument: b.ch8");
+        assert_eq!(error(&["a.ch8", "--ips"]), "Missing value for --ips");
+        assert_eq!(error(&["--ips", "fast"]), "Expected a number, got: fast");
+        assert_eq!(error(&["-m", "vic20", "a.ch8"]), "Unknown machine: vic20");
+
+        // Help needs no ROM
+        assert!(run_options(&["--help"]).help);
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        match parse_args(&["asm", "game.8o"]) {
+            Ok(Command::Assemble(options)) => {
+                assert_eq!(options.syntax(), Syntax::Octo);
+                assert_eq!(options.output_file(), "game.ch8");
+            }
+            _ => panic!("expected asm options"),
+        }
+
+        match parse_args(&["test", "--frames", "60", "--press", "10:5", "a.ch8"]) {
+            Ok(Command::Test(options)) => {
+                assert_eq!(options.frames, Some(60));
+                assert_eq!(
+                    options.presses,
+                    vec![KeyPress {
+                        frame: 10,
+                        key: 5,
+                        frames: 1
+                    }]
+                );
+            }
+            _ => panic!("expected test options"),
+        }
+    }
+}
@@ -1,4 +1,17 @@
-mod instruction;
+mod executor;
+pub mod font;
+pub mod instruction;
+pub mod machine;
+pub mod quirks;
+pub mod state;
+
+use crate::error::EmulatorError;
+use crate::hash;
+use crate::trace::{MemoryAccess, NullSink, Registers, TraceEvent, TraceSink};
+use font::Font;
+use machine::Machine;
+use quirks::Quirks;
+use std::io::Read;
 
 pub struct CPU {
     // General-purpose registers
@@ -15,11 +28,13 @@ pub struct CPU {
     // in big-endian. List: https://en.wikipedia.org/wiki/CHIP-8#Opcode_table
     pub opcode: u16,
 
-    // System memory map (4KB memory)
+    // System memory map (4KB memory, 64KB on XO-CHIP)
     // 0x000 -> 0x1FF - Chip 8 interpreter (contains font set in emu)
     // 0x050 -> 0x0A0 - Used for the built-in 4x5 pixel font set (0->F)
+    // 0x0A0 -> 0x140 - Used for the SUPER-CHIP 8x10 pixel font set (0->F)
     // 0x200 -> 0xFFF - Program ROM and work RAM
-    pub memory: [u8; 4096],
+    // Memory past 0xFFF is only addressable on XO-CHIP.
+    pub memory: [u8; 0x10000],
 
     // Graphics system
     // The chip 8 has one instruction that draws a sprite to the screen. Drawing
@@ -29,6 +44,16 @@ pub struct CPU {
     // pixels (64x32).
     pub gfx: [u8; 64 * 32],
 
+    // SUPER-CHIP adds a 128x64 high resolution mode. While `hires` is set,
+    // drawing and scrolling happen here instead of in `gfx`.
+    pub hires_gfx: [u8; 128 * 64],
+    pub hires: bool,
+
+    // XO-CHIP draws to two bitplanes, giving four colours. Each pixel in the
+    // framebuffers holds one bit per plane, and `planes` is the bitmask of
+    // planes that drawing, clearing and scrolling affect.
+    pub planes: u8,
+
     // The Chip 8 has no interrupts or hardware registers, but there are two
     // timer registers that count at 60Hz. When set above zero they will count
     // down to zero. The system's buzzer sounds whenever the sound timer reaches
@@ -36,6 +61,11 @@ pub struct CPU {
     pub delay_timer: u16,
     pub sound_timer: u16,
 
+    // XO-CHIP plays a 1-bit, 128 sample pattern while the sound timer is
+    // running, at a rate set by the pitch register.
+    pub audio_pattern: [u8; 16],
+    pub pitch: u8,
+
     // The stack
     pub stack: [u16; 16],
     pub sp: u16,
@@ -43,6 +73,36 @@ pub struct CPU {
     // HEX-based keypad (0x0 -> 0xF)
     // This array stores the current state of each key in the keypad.
     pub key: [u16; 16],
+
+    // Keys released since the keypad was last polled. FX0A waits for one of
+    // these rather than for a key to be held.
+    pub key_released: [bool; 16],
+
+    // SUPER-CHIP RPL user flags, named after the HP48 registers they lived in
+    pub rpl: [u8; 16],
+
+    // Hash of the loaded program, identifying it in saved files
+    pub rom_hash: u64,
+
+    // Set once the program has run the SUPER-CHIP exit instruction
+    pub halted: bool,
+
+    // Address programs are loaded at and start executing from. This is 0x200
+    // on nearly every interpreter, but ETI-660 programs start at 0x600.
+    pub load_address: u16,
+
+    // Instruction set and interpreter behaviours to emulate
+    pub machine: Machine,
+    pub quirks: Quirks,
+
+    // Receives an event for every executed instruction
+    pub tracer: Box<dyn TraceSink>,
+
+    // Memory accesses made by instructions are appended here while it is set
+    pub memory_accesses: Option<Vec<MemoryAccess>>,
+
+    // State of the xorshift generator backing the random number instruction
+    pub rng_state: u32,
 }
 
 impl Default for CPU {
@@ -52,13 +112,28 @@ impl Default for CPU {
             i: 0,
             pc: 0x200, // Program data starts at 0x200 or 512
             opcode: 0,
-            memory: [0; 4096],
+            memory: [0; 0x10000],
             gfx: [0; 64 * 32],
+            hires_gfx: [0; 128 * 64],
+            hires: false,
+            planes: 1,
             delay_timer: 0,
             sound_timer: 0,
+            audio_pattern: [0; 16],
+            pitch: 64,
             stack: [0; 16],
             sp: 0,
             key: [0; 16],
+            key_released: [false; 16],
+            rpl: [0; 16],
+            rom_hash: 0,
+            halted: false,
+            load_address: 0x200,
+            machine: Machine::default(),
+            quirks: Quirks::default(),
+            tracer: Box::new(NullSink),
+            memory_accesses: None,
+            rng_state: 0x2545_F491,
         }
     }
 }
@@ -66,41 +141,123 @@ impl Default for CPU {
 impl CPU {
     pub fn initialize(&mut self) {
         // Clear display
+        self.clear_display();
+
         // Load fontset
+        self.load_font(Font::default());
+        self.memory[font::LARGE_FONT_ADDRESS..font::LARGE_FONT_ADDRESS + font::LARGE_FONT_SIZE]
+            .copy_from_slice(&font::LARGE_FONT);
+    }
+
+    // The framebuffer currently being displayed, with its width and height.
+    pub fn display(&self) -> (&[u8], usize, usize) {
+        if self.hires {
+            (&self.hires_gfx, 128, 64)
+        } else {
+            (&self.gfx, 64, 32)
+        }
     }
 
-    pub fn load_program(&mut self, filename: &str) -> std::result::Result<(), std::io::Error> {
+    // Rate in samples per second the XO-CHIP audio pattern plays back at
+    pub fn audio_sample_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+
+    pub fn clear_display(&mut self) {
+        self.gfx = [0; 64 * 32];
+        self.hires_gfx = [0; 128 * 64];
+    }
+
+    // Replace the hexadecimal font with one of the built-in variants.
+    pub fn load_font(&mut self, font: Font) {
+        self.load_custom_font(font.glyphs());
+    }
+
+    // Replace the hexadecimal font with user-supplied glyphs, laid out as 16
+    // characters of 5 bytes each.
+    pub fn load_custom_font(&mut self, glyphs: &[u8; font::FONT_SIZE]) {
+        self.memory[font::FONT_ADDRESS..font::FONT_ADDRESS + font::FONT_SIZE]
+            .copy_from_slice(glyphs);
+    }
+
+    pub fn load_program(&mut self, filename: &str) -> Result<(), EmulatorError> {
         let buffer = std::fs::read(filename)?;
 
-        for (index, value) in buffer.iter().enumerate() {
-            // println!("Setting index {:0>4X} to value {:0>2X}", index + 0x200, *value);
-            self.memory[index + 0x200] = *value;
+        self.load_program_bytes(&buffer)
+    }
+
+    pub fn load_program_reader<R: Read>(&mut self, mut reader: R) -> Result<(), EmulatorError> {
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+
+        self.load_program_bytes(&buffer)
+    }
+
+    // Copy a program into memory at the load address and point the program
+    // counter at its first instruction.
+    pub fn load_program_bytes(&mut self, program: &[u8]) -> Result<(), EmulatorError> {
+        let start = self.load_address as usize;
+        let capacity = self.machine.memory_size().saturating_sub(start);
+
+        if program.len() > capacity {
+            return Err(EmulatorError::RomTooLarge {
+                size: program.len(),
+                capacity,
+            });
         }
 
+        self.memory[start..start + program.len()].copy_from_slice(program);
+        self.pc = self.load_address;
+        self.rom_hash = hash::fnv1a(program);
+
         Ok(())
     }
 
-    pub fn fetch_decode_execute(&mut self) -> Result<bool, String> {
+    pub fn fetch_decode_execute(&mut self) -> Result<bool, EmulatorError> {
+        if self.halted {
+            return Ok(true);
+        }
+
         // Fetch
-        if self.pc < 4096 {
-            self.opcode = read_word(self.memory, self.pc);
-            self.pc += 2;
+        if (self.pc as usize) < self.machine.memory_size() {
+            let pc = self.pc;
+            self.opcode = self.read_word(pc)?;
+            self.pc = self.pc.wrapping_add(2);
 
             // Decode
             match instruction::lookup(self.opcode) {
-                Ok(i) => {
+                Ok(instruction) if instruction.machine() > self.machine => {
+                    return Err(EmulatorError::UnknownOpcode {
+                        opcode: self.opcode,
+                        pc: Some(pc),
+                    });
+                }
+                Ok(instruction) => {
                     // Execute
-                    let instruction: instruction::Instruction = i;
-                    if instruction.opcode != 0x0000 {
-                        println!(
-                            "Executing opcode: {:#06X} - {:.100}",
-                            instruction.opcode, instruction.description
-                        );
-
-                        (instruction.definition)(self);
-                        println!("");
+                    if instruction != instruction::Instruction::Noop {
+                        if self.tracer.enabled() {
+                            let before = Registers::capture(self);
+                            self.execute(instruction)?;
+
+                            let event = TraceEvent {
+                                pc,
+                                opcode: self.opcode,
+                                instruction,
+                                next_pc: self.pc,
+                                changes: before.changes(self),
+                            };
+                            self.tracer.record(&event)?;
+                        } else {
+                            self.execute(instruction)?;
+                        }
                     }
                 }
+                Err(EmulatorError::UnknownOpcode { opcode, .. }) => {
+                    return Err(EmulatorError::UnknownOpcode {
+                        opcode,
+                        pc: Some(pc),
+                    });
+                }
                 Err(e) => {
                     return Err(e);
                 }
@@ -111,10 +268,114 @@ impl CPU {
             Ok(true)
         }
     }
+
+    // Count both timers down by one. Called at 60Hz.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    // Fetch a single word from memory by fetching a byte at an index, fetching
+    // the next byte, and ORing the results after a bit shift.
+    pub fn read_word(&self, index: u16) -> Result<u16, EmulatorError> {
+        let index = index as usize;
+
+        if index + 1 >= self.machine.memory_size() {
+            return Err(EmulatorError::MemoryOutOfBounds { address: index + 1 });
+        }
+
+        Ok((self.memory[index] as u16) << 8 | (self.memory[index + 1] as u16))
+    }
+
+    // Produce the next pseudo-random byte using a 32-bit xorshift generator.
+    pub fn random_byte(&mut self) -> u8 {
+        let mut state = self.rng_state;
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        self.rng_state = state;
+
+        (state >> 24) as u8
+    }
 }
 
-// Fetch a single word from memory by fetching a byte at an index, fetching the
-// next byte, and ORing the results after a bit shift.
-fn read_word(memory: [u8; 4096], index: u16) -> u16 {
-    (memory[index as usize] as u16) << 8 | (memory[index as usize + 1] as u16)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initialize_loads_both_fonts() {
+        let mut cpu = CPU::default();
+        cpu.initialize();
+
+        let small = &cpu.memory[font::FONT_ADDRESS..font::FONT_ADDRESS + font::FONT_SIZE];
+        assert_eq!(small, &font::Font::Standard.glyphs()[..]);
+        assert_eq!(&small[..5], &[0xF0, 0x90, 0x90, 0x90, 0xF0]);
+
+        let large = font::LARGE_FONT_ADDRESS..font::LARGE_FONT_ADDRESS + font::LARGE_FONT_SIZE;
+        assert_eq!(&cpu.memory[large], &font::LARGE_FONT[..]);
+    }
+
+    #[test]
+    fn fonts_can_be_replaced() {
+        let mut cpu = CPU::default();
+        cpu.initialize();
+
+        cpu.load_font(Font::CosmacVip);
+        let glyphs = &cpu.memory[font::FONT_ADDRESS..font::FONT_ADDRESS + font::FONT_SIZE];
+        assert_eq!(glyphs, &Font::CosmacVip.glyphs()[..]);
+        assert_ne!(glyphs, &Font::Standard.glyphs()[..]);
+
+        cpu.load_custom_font(&[0xAA; font::FONT_SIZE]);
+        assert_eq!(cpu.memory[font::FONT_ADDRESS + font::FONT_SIZE - 1], 0xAA);
+        // The large font is left alone
+        assert_eq!(cpu.memory[font::LARGE_FONT_ADDRESS], font::LARGE_FONT[0]);
+    }
+
+    #[test]
+    fn loads_programs_at_the_load_address() {
+        let mut cpu = CPU {
+            load_address: 0x600,
+            ..CPU::default()
+        };
+        cpu.load_program_reader(&[0x12, 0x34][..]).unwrap();
+
+        assert_eq!(&cpu.memory[0x600..0x602], &[0x12, 0x34]);
+        assert_eq!(cpu.pc, 0x600);
+        assert_eq!(cpu.rom_hash, hash::fnv1a(&[0x12, 0x34]));
+    }
+
+    #[test]
+    fn rejects_programs_that_do_not_fit() {
+        let mut cpu = CPU::default();
+
+        // Exactly filling memory is fine
+        cpu.load_program_bytes(&[0; 0xE00]).unwrap();
+
+        match cpu.load_program_bytes(&[0; 0xE01]) {
+            Err(EmulatorError::RomTooLarge {
+                size: 0xE01,
+                capacity: 0xE00,
+            }) => (),
+            result => panic!("expected the program to be too large, got {:?}", result),
+        }
+
+        // XO-CHIP has room for far larger programs
+        cpu.machine = Machine::XoChip;
+        cpu.load_program_bytes(&[0; 0xE01]).unwrap();
+    }
+
+    #[test]
+    fn stops_at_the_end_of_memory() {
+        let mut cpu = CPU {
+            pc: 0x1000,
+            ..CPU::default()
+        };
+        assert!(cpu.fetch_decode_execute().unwrap());
+
+        match cpu.read_word(0xFFF) {
+            Err(EmulatorError::MemoryOutOfBounds { address: 0x1000 }) => (),
+            result => panic!("expected an out of bounds read, got {:?}", result),
+        }
+    }
 }
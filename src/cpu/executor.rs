@@ -0,0 +1,967 @@
+use crate::cpu::font;
+use crate::cpu::instruction::Instruction;
+use crate::cpu::machine::Machine;
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use crate::trace::{Access, MemoryAccess};
+
+impl CPU {
+    // Run a single decoded instruction against the machine state. The program
+    // counter has already been moved past the instruction being executed.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), EmulatorError> {
+        match instruction {
+            Instruction::Noop => (),
+            Instruction::ClearScreen => {
+                let planes = self.active_planes();
+
+                for pixel in self.gfx.iter_mut().chain(self.hires_gfx.iter_mut()) {
+                    *pixel &= !planes;
+                }
+            }
+            Instruction::Return => {
+                if self.sp == 0 {
+                    return Err(EmulatorError::StackUnderflow {
+                        pc: self.pc.wrapping_sub(2),
+                    });
+                }
+
+                self.sp -= 1;
+                self.pc = self.stack[self.sp as usize];
+            }
+            Instruction::Jump { nnn } => {
+                self.pc = nnn;
+            }
+            Instruction::Call { nnn } => {
+                if self.sp as usize >= self.stack.len() {
+                    return Err(EmulatorError::StackOverflow {
+                        pc: self.pc.wrapping_sub(2),
+                    });
+                }
+
+                // The current program counter is the return address
+                self.stack[self.sp as usize] = self.pc;
+                self.sp += 1;
+                self.pc = nnn;
+            }
+            Instruction::SkipIfEqual { x, nn } => {
+                if self.v[x as usize] == nn {
+                    self.skip_next_instruction();
+                }
+            }
+            Instruction::SkipIfNotEqual { x, nn } => {
+                if self.v[x as usize] != nn {
+                    self.skip_next_instruction();
+                }
+            }
+            Instruction::SkipIfRegistersEqual { x, y } => {
+                if self.v[x as usize] == self.v[y as usize] {
+                    self.skip_next_instruction();
+                }
+            }
+            Instruction::SetConstant { x, nn } => {
+                self.v[x as usize] = nn;
+            }
+            Instruction::AddConstant { x, nn } => {
+                self.v[x as usize] = self.v[x as usize].wrapping_add(nn);
+            }
+            Instruction::Assign { x, y } => {
+                self.v[x as usize] = self.v[y as usize];
+            }
+            Instruction::Or { x, y } => {
+                self.v[x as usize] |= self.v[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Instruction::And { x, y } => {
+                self.v[x as usize] &= self.v[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Instruction::Xor { x, y } => {
+                self.v[x as usize] ^= self.v[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Instruction::Add { x, y } => {
+                let (sum, carry) = self.v[x as usize].overflowing_add(self.v[y as usize]);
+
+                self.v[x as usize] = sum;
+                self.v[0xF] = carry as u8;
+            }
+            Instruction::Subtract { x, y } => {
+                let (difference, borrow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
+
+                self.v[x as usize] = difference;
+                self.v[0xF] = !borrow as u8;
+            }
+            Instruction::ShiftRight { x, y } => {
+                let source = self.shift_source(x, y);
+                let lsb = source & 0b0000_0001;
+
+                self.v[x as usize] = source >> 1;
+                self.v[0xF] = lsb;
+            }
+            Instruction::SubtractReversed { x, y } => {
+                let (difference, borrow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
+
+                self.v[x as usize] = difference;
+                self.v[0xF] = !borrow as u8;
+            }
+            Instruction::ShiftLeft { x, y } => {
+                let source = self.shift_source(x, y);
+                let msb = (source & 0b1000_0000) >> 7;
+
+                self.v[x as usize] = source << 1;
+                self.v[0xF] = msb;
+            }
+            Instruction::SkipIfRegistersNotEqual { x, y } => {
+                if self.v[x as usize] != self.v[y as usize] {
+                    self.skip_next_instruction();
+                }
+            }
+            Instruction::SetIndex { nnn } => {
+                self.i = nnn;
+            }
+            Instruction::JumpOffset { nnn } => {
+                // With the quirk enabled the opcode is read as BXNN
+                let offset = if self.quirks.jump_offset_uses_vx {
+                    self.v[(nnn >> 8) as usize]
+                } else {
+                    self.v[0]
+                };
+
+                self.pc = nnn + offset as u16;
+            }
+            Instruction::Random { x, nn } => {
+                self.v[x as usize] = self.random_byte() & nn;
+            }
+            Instruction::Draw { x, y, n } => {
+                // SUPER-CHIP draws a 16x16 sprite, two bytes per row, when N
+                // is 0.
+                let (sprite_width, rows) = if n == 0 && self.machine >= Machine::SuperChip {
+                    (16, 16)
+                } else {
+                    (8, n as usize)
+                };
+                let bytes_per_row = sprite_width / 8;
+                let planes = self.active_planes();
+
+                // On XO-CHIP the sprite data for each selected plane follows
+                // on from the previous plane's.
+                let mut address = self.i as usize;
+                self.access_memory(
+                    address,
+                    rows * bytes_per_row * planes.count_ones() as usize,
+                    Access::Read,
+                )?;
+
+                let (gfx, width, height): (&mut [u8], usize, usize) = if self.hires {
+                    (&mut self.hires_gfx, 128, 64)
+                } else {
+                    (&mut self.gfx, 64, 32)
+                };
+
+                // The starting coordinate always wraps around the screen
+                let origin_x = self.v[x as usize] as usize % width;
+                let origin_y = self.v[y as usize] as usize % height;
+
+                let mut collision = false;
+
+                for plane in [0b01, 0b10].iter().filter(|&&plane| planes & plane != 0) {
+                    for row in 0..rows {
+                        // Left-align the row in 16 bits so both sprite widths
+                        // are read the same way
+                        let sprite_row = if bytes_per_row == 2 {
+                            (self.memory[address] as u16) << 8 | self.memory[address + 1] as u16
+                        } else {
+                            (self.memory[address] as u16) << 8
+                        };
+                        address += bytes_per_row;
+
+                        for column in 0..sprite_width {
+                            if sprite_row & (0x8000 >> column) == 0 {
+                                continue;
+                            }
+
+                            let (mut pixel_x, mut pixel_y) = (origin_x + column, origin_y + row);
+
+                            if pixel_x >= width || pixel_y >= height {
+                                if self.quirks.clip_sprites {
+                                    continue;
+                                }
+
+                                pixel_x %= width;
+                                pixel_y %= height;
+                            }
+
+                            let index = pixel_y * width + pixel_x;
+
+                            if gfx[index] & plane != 0 {
+                                collision = true;
+                            }
+
+                            gfx[index] ^= plane;
+                        }
+                    }
+                }
+
+                self.v[0xF] = collision as u8;
+            }
+            Instruction::SkipIfKeyPressed { x } => {
+                let key = self.v[x as usize] & 0x0F;
+
+                if self.key[key as usize] != 0 {
+                    self.skip_next_instruction();
+                }
+            }
+            Instruction::SkipIfKeyNotPressed { x } => {
+                let key = self.v[x as usize] & 0x0F;
+
+                if self.key[key as usize] == 0 {
+                    self.skip_next_instruction();
+                }
+            }
+            Instruction::GetDelayTimer { x } => {
+                self.v[x as usize] = self.delay_timer as u8;
+            }
+            Instruction::AwaitKey { x } => {
+                // Block by rewinding the program counter so this instruction
+                // runs again until a key is pressed and then released.
+                match self.key_released.iter().position(|&released| released) {
+                    Some(key) => {
+                        self.key_released[key] = false;
+                        self.v[x as usize] = key as u8;
+                    }
+                    None => self.pc = self.pc.wrapping_sub(2),
+                }
+            }
+            Instruction::SetDelayTimer { x } => {
+                self.delay_timer = self.v[x as usize] as u16;
+            }
+            Instruction::SetSoundTimer { x } => {
+                self.sound_timer = self.v[x as usize] as u16;
+            }
+            Instruction::AddIndex { x } => {
+                if self.quirks.index_overflow_sets_vf {
+                    let limit = self.machine.memory_size() - 1;
+                    let sum = self.i as usize + self.v[x as usize] as usize;
+
+                    self.i = (sum & limit) as u16;
+                    self.v[0xF] = (sum > limit) as u8;
+                } else {
+                    self.i = self.i.wrapping_add(self.v[x as usize] as u16);
+                }
+            }
+            Instruction::FontCharacter { x } => {
+                let character = self.v[x as usize] & 0x0F;
+
+                self.i = (font::FONT_ADDRESS + character as usize * font::GLYPH_HEIGHT) as u16;
+            }
+            Instruction::BinaryCodedDecimal { x } => {
+                let value = self.v[x as usize];
+                let i = self.i as usize;
+
+                self.access_memory(i, 3, Access::Write)?;
+
+                self.memory[i] = value / 100;
+                self.memory[i + 1] = (value / 10) % 10;
+                self.memory[i + 2] = value % 10;
+            }
+            Instruction::StoreRegisters { x } => {
+                let i = self.i as usize;
+
+                self.access_memory(i, x as usize + 1, Access::Write)?;
+
+                for offset in 0..=x as usize {
+                    self.memory[i + offset] = self.v[offset];
+                }
+
+                self.increment_index_after_load_store(x);
+            }
+            Instruction::LoadRegisters { x } => {
+                let i = self.i as usize;
+
+                self.access_memory(i, x as usize + 1, Access::Read)?;
+
+                for offset in 0..=x as usize {
+                    self.v[offset] = self.memory[i + offset];
+                }
+
+                self.increment_index_after_load_store(x);
+            }
+            Instruction::ScrollDown { n } => {
+                self.scroll(0, n as isize);
+            }
+            Instruction::ScrollRight => {
+                self.scroll(4, 0);
+            }
+            Instruction::ScrollLeft => {
+                self.scroll(-4, 0);
+            }
+            Instruction::Exit => {
+                self.halted = true;
+            }
+            Instruction::LowResolution => {
+                self.hires = false;
+                self.clear_display();
+            }
+            Instruction::HighResolution => {
+                self.hires = true;
+                self.clear_display();
+            }
+            Instruction::LargeFontCharacter { x } => {
+                let character = self.v[x as usize] & 0x0F;
+
+                self.i = (font::LARGE_FONT_ADDRESS + character as usize * font::LARGE_GLYPH_HEIGHT)
+                    as u16;
+            }
+            Instruction::StoreFlags { x } => {
+                self.check_flag_range(x)?;
+                self.rpl[..=x as usize].copy_from_slice(&self.v[..=x as usize]);
+            }
+            Instruction::LoadFlags { x } => {
+                self.check_flag_range(x)?;
+                self.v[..=x as usize].copy_from_slice(&self.rpl[..=x as usize]);
+            }
+            Instruction::ScrollUp { n } => {
+                self.scroll(0, -(n as isize));
+            }
+            Instruction::StoreRange { x, y } => {
+                let i = self.i as usize;
+                let registers = register_range(x, y);
+
+                self.access_memory(i, registers.len(), Access::Write)?;
+
+                for (offset, &register) in registers.iter().enumerate() {
+                    self.memory[i + offset] = self.v[register];
+                }
+            }
+            Instruction::LoadRange { x, y } => {
+                let i = self.i as usize;
+                let registers = register_range(x, y);
+
+                self.access_memory(i, registers.len(), Access::Read)?;
+
+                for (offset, &register) in registers.iter().enumerate() {
+                    self.v[register] = self.memory[i + offset];
+                }
+            }
+            Instruction::LongLoad => {
+                self.i = self.read_word(self.pc)?;
+                self.pc = self.pc.wrapping_add(2);
+            }
+            Instruction::SelectPlanes { n } => {
+                self.planes = n & 0b11;
+            }
+            Instruction::LoadAudio => {
+                let i = self.i as usize;
+
+                self.access_memory(i, self.audio_pattern.len(), Access::Read)?;
+                self.audio_pattern.copy_from_slice(&self.memory[i..i + 16]);
+            }
+            Instruction::SetPitch { x } => {
+                self.pitch = self.v[x as usize];
+            }
+        }
+
+        Ok(())
+    }
+
+    // Move the selected planes of the active framebuffer by the given number of
+    // pixels. Pixels moved off one edge are lost, and the space left behind is
+    // cleared.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let planes = self.active_planes();
+        let (gfx, width, height): (&mut [u8], isize, isize) = if self.hires {
+            (&mut self.hires_gfx, 128, 64)
+        } else {
+            (&mut self.gfx, 64, 32)
+        };
+
+        let previous = gfx.to_vec();
+
+        for y in 0..height {
+            for x in 0..width {
+                let (source_x, source_y) = (x - dx, y - dy);
+                let inside = (0..width).contains(&source_x) && (0..height).contains(&source_y);
+
+                let moved = if inside {
+                    previous[(source_y * width + source_x) as usize]
+                } else {
+                    0
+                };
+                let index = (y * width + x) as usize;
+
+                gfx[index] = (previous[index] & !planes) | (moved & planes);
+            }
+        }
+    }
+
+    // Advance past the next instruction for the conditional skips. XO-CHIP's
+    // F000 NNNN is twice as long as every other instruction, so it has to be
+    // skipped as a whole.
+    fn skip_next_instruction(&mut self) {
+        let long_load =
+            self.machine >= Machine::XoChip && self.read_word(self.pc).ok() == Some(0xF000);
+
+        self.pc = self.pc.wrapping_add(if long_load { 4 } else { 2 });
+    }
+
+    // Bitplanes affected by drawing, clearing and scrolling. Only XO-CHIP has
+    // more than one.
+    fn active_planes(&self) -> u8 {
+        if self.machine >= Machine::XoChip {
+            self.planes
+        } else {
+            1
+        }
+    }
+
+    // The value 8XY6 and 8XYE shift, which depends on the interpreter.
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_in_place {
+            self.v[x as usize]
+        } else {
+            self.v[y as usize]
+        }
+    }
+
+    // Move I on past the registers FX55 and FX65 stored or loaded, by as far
+    // as the interpreter did.
+    fn increment_index_after_load_store(&mut self, x: u8) {
+        if self.quirks.load_store_increments_i {
+            let registers = if self.quirks.load_store_increments_i_by_x {
+                x
+            } else {
+                x + 1
+            };
+
+            self.i = self.i.wrapping_add(registers as u16);
+        }
+    }
+
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
+    }
+
+    // FX75 and FX85 can only reach as many flags as the machine has.
+    fn check_flag_range(&self, x: u8) -> Result<(), EmulatorError> {
+        if x as usize >= self.machine.flag_count() {
+            return Err(EmulatorError::UnknownOpcode {
+                opcode: self.opcode,
+                pc: Some(self.pc.wrapping_sub(2)),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Ensure `length` bytes starting at `start` all lie within memory, and
+    // note the access for anyone watching memory.
+    fn access_memory(
+        &mut self,
+        start: usize,
+        length: usize,
+        access: Access,
+    ) -> Result<(), EmulatorError> {
+        let size = self.machine.memory_size();
+
+        if start + length > size {
+            // Report the first address that falls outside of memory
+            return Err(EmulatorError::MemoryOutOfBounds {
+                address: start.max(size),
+            });
+        }
+
+        // Drawing zero rows touches no memory, so there is nothing to record
+        if length == 0 {
+            return Ok(());
+        }
+
+        if let Some(accesses) = &mut self.memory_accesses {
+            accesses.push(MemoryAccess {
+                access,
+                start: start as u16,
+                length: length as u16,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// Registers X through Y inclusive, counting down when X is the larger.
+fn register_range(x: u8, y: u8) -> Vec<usize> {
+    let (x, y) = (x as usize, y as usize);
+
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::quirks::Quirks;
+
+    // A CPU with its font loaded and a program at 0x200
+    fn load(program: &[u8]) -> CPU {
+        let mut cpu = CPU::default();
+        cpu.initialize();
+        cpu.load_program_bytes(program).unwrap();
+        cpu
+    }
+
+    fn run(cpu: &mut CPU, instructions: usize) {
+        for _ in 0..instructions {
+            assert!(!cpu.fetch_decode_execute().unwrap());
+        }
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        // 0x200: call 0x206, 0x202: jump 0x202, 0x206: return
+        let mut cpu = load(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x00, 0xEE]);
+
+        run(&mut cpu, 1);
+        assert_eq!((cpu.pc, cpu.sp, cpu.stack[0]), (0x206, 1, 0x202));
+
+        run(&mut cpu, 2);
+        assert_eq!((cpu.pc, cpu.sp), (0x202, 0));
+    }
+
+    #[test]
+    fn reports_stack_errors_at_the_instruction() {
+        let mut cpu = load(&[0x00, 0xEE]);
+        match cpu.fetch_decode_execute() {
+            Err(EmulatorError::StackUnderflow { pc: 0x200 }) => (),
+            result => panic!("expected a stack underflow, got {:?}", result),
+        }
+
+        // 0x200: call 0x200, forever
+        let mut cpu = load(&[0x22, 0x00]);
+        run(&mut cpu, 16);
+        match cpu.fetch_decode_execute() {
+            Err(EmulatorError::StackOverflow { pc: 0x200 }) => (),
+            result => panic!("expected a stack overflow, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn reports_memory_errors() {
+        let mut cpu = load(&[]);
+        cpu.i = 0xFFE;
+
+        match cpu.execute(Instruction::BinaryCodedDecimal { x: 0 }) {
+            Err(EmulatorError::MemoryOutOfBounds { address: 0x1000 }) => (),
+            result => panic!("expected an out of bounds write, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn errors_after_the_program_counter_wraps() {
+        // XO-CHIP's program counter wraps from the end of memory to 0
+        let mut cpu = CPU {
+            machine: Machine::XoChip,
+            ..CPU::default()
+        };
+        cpu.memory[0xFFFE] = 0x00;
+        cpu.memory[0xFFFF] = 0xEE;
+        cpu.pc = 0xFFFE;
+
+        match cpu.fetch_decode_execute() {
+            Err(EmulatorError::StackUnderflow { pc: 0xFFFE }) => (),
+            result => panic!("expected a stack underflow, got {:?}", result),
+        }
+
+        cpu.pc = 0;
+        cpu.execute(Instruction::AwaitKey { x: 0 }).unwrap();
+        assert_eq!(cpu.pc, 0xFFFE);
+    }
+
+    #[test]
+    fn skips() {
+        let mut cpu = load(&[0x60, 0x05, 0x30, 0x05, 0x00, 0x00, 0x40, 0x05, 0x61, 0x05]);
+
+        run(&mut cpu, 2);
+        assert_eq!(cpu.pc, 0x206);
+
+        // 4XNN does not skip when VX equals NN
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x208);
+
+        // 5XY0 and 9XY0 compare registers
+        run(&mut cpu, 1);
+        cpu.execute(Instruction::SkipIfRegistersEqual { x: 0, y: 1 })
+            .unwrap();
+        assert_eq!(cpu.pc, 0x20C);
+        cpu.execute(Instruction::SkipIfRegistersNotEqual { x: 0, y: 1 })
+            .unwrap();
+        assert_eq!(cpu.pc, 0x20C);
+    }
+
+    #[test]
+    fn arithmetic_sets_carry_and_borrow() {
+        let mut cpu = load(&[]);
+        cpu.v[0] = 0xF0;
+        cpu.v[1] = 0x20;
+
+        cpu.execute(Instruction::Add { x: 0, y: 1 }).unwrap();
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0x10, 1));
+
+        cpu.execute(Instruction::Subtract { x: 0, y: 1 }).unwrap();
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0xF0, 0));
+
+        cpu.execute(Instruction::SubtractReversed { x: 1, y: 0 })
+            .unwrap();
+        assert_eq!((cpu.v[1], cpu.v[0xF]), (0xD0, 1));
+
+        cpu.execute(Instruction::AddConstant { x: 1, nn: 0x40 })
+            .unwrap();
+        assert_eq!((cpu.v[1], cpu.v[0xF]), (0x10, 1));
+    }
+
+    #[test]
+    fn shifts_move_the_lost_bit_into_vf() {
+        let mut cpu = load(&[]);
+        cpu.v[1] = 0b1000_0001;
+
+        cpu.execute(Instruction::ShiftRight { x: 0, y: 1 }).unwrap();
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0b0100_0000, 1));
+
+        cpu.execute(Instruction::ShiftLeft { x: 0, y: 1 }).unwrap();
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0b0000_0010, 1));
+    }
+
+    #[test]
+    fn draws_with_xor_and_reports_collisions() {
+        let mut cpu = load(&[]);
+        cpu.i = font::FONT_ADDRESS as u16;
+        cpu.v[0] = 62;
+
+        cpu.execute(Instruction::Draw { x: 0, y: 1, n: 5 }).unwrap();
+        assert_eq!(cpu.v[0xF], 0);
+        assert_eq!(&cpu.gfx[62..64], &[1, 1]);
+        // The VIP clips sprites at the edge of the screen
+        assert_eq!(cpu.gfx[0], 0);
+
+        cpu.execute(Instruction::Draw { x: 0, y: 1, n: 5 }).unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+        assert!(cpu.gfx.iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn increments_index_by_interpreter() {
+        let mut cpu = load(&[]);
+        cpu.i = 0x300;
+
+        cpu.quirks = Quirks::chip48();
+        cpu.execute(Instruction::StoreRegisters { x: 2 }).unwrap();
+        assert_eq!(cpu.i, 0x302);
+
+        cpu.quirks = Quirks::super_chip();
+        cpu.execute(Instruction::LoadRegisters { x: 2 }).unwrap();
+        assert_eq!(cpu.i, 0x302);
+    }
+
+    #[test]
+    fn only_sets_vf_on_index_overflow_when_asked() {
+        let mut cpu = load(&[]);
+        cpu.i = 0xFFF;
+        cpu.v[0] = 2;
+        cpu.v[0xF] = 9;
+
+        cpu.execute(Instruction::AddIndex { x: 0 }).unwrap();
+        assert_eq!((cpu.i, cpu.v[0xF]), (0x1001, 9));
+
+        cpu.i = 0xFFF;
+        cpu.quirks.index_overflow_sets_vf = true;
+        cpu.execute(Instruction::AddIndex { x: 0 }).unwrap();
+        assert_eq!((cpu.i, cpu.v[0xF]), (0x001, 1));
+
+        cpu.execute(Instruction::AddIndex { x: 0 }).unwrap();
+        assert_eq!((cpu.i, cpu.v[0xF]), (0x003, 0));
+    }
+
+    #[test]
+    fn applies_vip_quirks() {
+        let mut cpu = load(&[]);
+        cpu.v[1] = 0b11;
+        cpu.v[0xF] = 1;
+
+        // Logic resets VF, and shifts read VY
+        cpu.execute(Instruction::Or { x: 0, y: 1 }).unwrap();
+        assert_eq!(cpu.v[0xF], 0);
+        cpu.execute(Instruction::ShiftLeft { x: 2, y: 1 }).unwrap();
+        assert_eq!(cpu.v[2], 0b110);
+
+        // BNNN adds V0
+        cpu.v[0] = 4;
+        cpu.execute(Instruction::JumpOffset { nnn: 0x300 }).unwrap();
+        assert_eq!(cpu.pc, 0x304);
+    }
+
+    #[test]
+    fn applies_super_chip_quirks() {
+        let mut cpu = load(&[]);
+        cpu.quirks = Quirks::super_chip();
+        cpu.v[0] = 0b11;
+        cpu.v[3] = 0x10;
+        cpu.v[0xF] = 1;
+
+        cpu.execute(Instruction::Or { x: 0, y: 1 }).unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+        cpu.execute(Instruction::ShiftLeft { x: 0, y: 1 }).unwrap();
+        assert_eq!(cpu.v[0], 0b110);
+
+        // BXNN adds VX
+        cpu.execute(Instruction::JumpOffset { nnn: 0x300 }).unwrap();
+        assert_eq!(cpu.pc, 0x310);
+    }
+
+    #[test]
+    fn wraps_sprites_without_clipping() {
+        let mut cpu = load(&[]);
+        cpu.quirks.clip_sprites = false;
+        cpu.i = font::FONT_ADDRESS as u16;
+        cpu.v[0] = 62;
+        cpu.v[1] = 30;
+
+        cpu.execute(Instruction::Draw { x: 0, y: 1, n: 5 }).unwrap();
+        assert_eq!(&cpu.gfx[30 * 64 + 62..30 * 64 + 64], &[1, 1]);
+        assert_eq!(&cpu.gfx[30 * 64..30 * 64 + 2], &[1, 1]);
+        assert_eq!(&cpu.gfx[..2], &[0, 1]);
+    }
+
+    #[test]
+    fn stores_binary_coded_decimal() {
+        let mut cpu = load(&[]);
+        cpu.v[3] = 254;
+        cpu.i = 0x300;
+
+        cpu.execute(Instruction::BinaryCodedDecimal { x: 3 })
+            .unwrap();
+        assert_eq!(&cpu.memory[0x300..0x303], &[2, 5, 4]);
+    }
+
+    #[test]
+    fn stores_and_loads_registers() {
+        let mut cpu = load(&[]);
+        cpu.v[..3].copy_from_slice(&[1, 2, 3]);
+        cpu.i = 0x300;
+
+        cpu.execute(Instruction::StoreRegisters { x: 2 }).unwrap();
+        assert_eq!(&cpu.memory[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(cpu.i, 0x303);
+
+        cpu.i = 0x301;
+        cpu.execute(Instruction::LoadRegisters { x: 1 }).unwrap();
+        assert_eq!(&cpu.v[..3], &[2, 3, 3]);
+    }
+
+    #[test]
+    fn skips_on_keys_and_waits_for_release() {
+        let mut cpu = load(&[0xF2, 0x0A]);
+        cpu.v[0] = 7;
+        cpu.key[7] = 1;
+
+        // FX0A runs again until a key is released, however long it is held
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x200);
+
+        cpu.key_released[7] = true;
+        run(&mut cpu, 1);
+        assert_eq!((cpu.pc, cpu.v[2]), (0x202, 7));
+
+        cpu.execute(Instruction::SkipIfKeyPressed { x: 0 }).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+        cpu.execute(Instruction::SkipIfKeyNotPressed { x: 0 })
+            .unwrap();
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn timers_and_font() {
+        let mut cpu = load(&[]);
+        cpu.v[0] = 0xA;
+
+        cpu.execute(Instruction::SetDelayTimer { x: 0 }).unwrap();
+        cpu.tick_timers();
+        cpu.execute(Instruction::GetDelayTimer { x: 1 }).unwrap();
+        assert_eq!(cpu.v[1], 9);
+
+        cpu.execute(Instruction::FontCharacter { x: 0 }).unwrap();
+        assert_eq!(cpu.i as usize, font::FONT_ADDRESS + 0xA * 5);
+    }
+
+    fn super_chip(program: &[u8]) -> CPU {
+        let mut cpu = CPU {
+            machine: Machine::SuperChip,
+            quirks: Quirks::super_chip(),
+            ..CPU::default()
+        };
+        cpu.initialize();
+        cpu.load_program_bytes(program).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn rejects_super_chip_instructions_on_chip8() {
+        let mut cpu = load(&[0x00, 0xFF]);
+
+        match cpu.fetch_decode_execute() {
+            Err(EmulatorError::UnknownOpcode {
+                opcode: 0x00FF,
+                pc: Some(0x200),
+            }) => (),
+            result => panic!("expected an unknown opcode, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn draws_large_sprites_in_high_resolution() {
+        let mut cpu = super_chip(&[0x00, 0xFF]);
+        cpu.gfx[0] = 1;
+        run(&mut cpu, 1);
+        assert!(cpu.hires);
+        assert_eq!(cpu.display().1, 128);
+        assert_eq!(cpu.gfx[0], 0);
+
+        cpu.memory[0x300..0x320].copy_from_slice(&[0xFF; 32]);
+        cpu.i = 0x300;
+        cpu.v[0] = 120;
+        cpu.execute(Instruction::Draw { x: 0, y: 1, n: 0 }).unwrap();
+
+        // The sprite is clipped at the right edge
+        let lit = cpu.hires_gfx.iter().filter(|&&pixel| pixel != 0).count();
+        assert_eq!(lit, 8 * 16);
+        assert_eq!(cpu.hires_gfx[15 * 128 + 127], 1);
+    }
+
+    #[test]
+    fn scrolls_the_display() {
+        let mut cpu = super_chip(&[]);
+        cpu.gfx[0] = 1;
+
+        cpu.execute(Instruction::ScrollDown { n: 2 }).unwrap();
+        assert_eq!(cpu.gfx[2 * 64], 1);
+
+        cpu.execute(Instruction::ScrollRight).unwrap();
+        assert_eq!(cpu.gfx[2 * 64 + 4], 1);
+
+        cpu.execute(Instruction::ScrollLeft).unwrap();
+        cpu.execute(Instruction::ScrollLeft).unwrap();
+        assert!(cpu.gfx.iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn exits() {
+        let mut cpu = super_chip(&[0x00, 0xFD, 0x12, 0x00]);
+
+        assert!(!cpu.fetch_decode_execute().unwrap());
+        assert!(cpu.halted);
+        assert!(cpu.fetch_decode_execute().unwrap());
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn points_at_large_font_characters() {
+        let mut cpu = super_chip(&[]);
+        cpu.v[0] = 3;
+
+        cpu.execute(Instruction::LargeFontCharacter { x: 0 })
+            .unwrap();
+        assert_eq!(cpu.i as usize, font::LARGE_FONT_ADDRESS + 30);
+    }
+
+    #[test]
+    fn stores_only_the_flags_the_machine_has() {
+        let mut cpu = super_chip(&[0xF8, 0x75]);
+        cpu.v[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        cpu.execute(Instruction::StoreFlags { x: 7 }).unwrap();
+        cpu.v = [0; 16];
+        cpu.execute(Instruction::LoadFlags { x: 7 }).unwrap();
+        assert_eq!(&cpu.v[..8], &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert!(cpu.fetch_decode_execute().is_err());
+    }
+
+    fn xo_chip(program: &[u8]) -> CPU {
+        let mut cpu = CPU {
+            machine: Machine::XoChip,
+            quirks: Quirks::xo_chip(),
+            ..CPU::default()
+        };
+        cpu.initialize();
+        cpu.load_program_bytes(program).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn loads_long_addresses_and_skips_them_whole() {
+        // 0x200: i := long 0xABCD, 0x204: skip if v0 == 0, 0x206: i := long
+        let mut cpu = xo_chip(&[
+            0xF0, 0x00, 0xAB, 0xCD, 0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x00, 0xE0,
+        ]);
+
+        run(&mut cpu, 2);
+        assert_eq!((cpu.i, cpu.pc), (0xABCD, 0x20A));
+    }
+
+    #[test]
+    fn draws_to_both_planes() {
+        let mut cpu = xo_chip(&[]);
+        cpu.memory[0x300] = 0x80;
+        cpu.memory[0x301] = 0xC0;
+        cpu.i = 0x300;
+
+        cpu.execute(Instruction::SelectPlanes { n: 3 }).unwrap();
+        cpu.execute(Instruction::Draw { x: 0, y: 0, n: 1 }).unwrap();
+        assert_eq!(&cpu.gfx[..2], &[0b11, 0b10]);
+
+        // Clearing only affects the selected planes
+        cpu.execute(Instruction::SelectPlanes { n: 2 }).unwrap();
+        cpu.execute(Instruction::ClearScreen).unwrap();
+        assert_eq!(&cpu.gfx[..2], &[0b01, 0b00]);
+    }
+
+    #[test]
+    fn stores_and_loads_register_ranges() {
+        let mut cpu = xo_chip(&[]);
+        cpu.v[2..5].copy_from_slice(&[1, 2, 3]);
+        cpu.i = 0x8000;
+
+        // Ranges can run backwards, and leave I alone
+        cpu.execute(Instruction::StoreRange { x: 4, y: 2 }).unwrap();
+        assert_eq!(&cpu.memory[0x8000..0x8003], &[3, 2, 1]);
+        assert_eq!(cpu.i, 0x8000);
+
+        cpu.execute(Instruction::LoadRange { x: 7, y: 9 }).unwrap();
+        assert_eq!(&cpu.v[7..10], &[3, 2, 1]);
+    }
+
+    #[test]
+    fn scrolls_up() {
+        let mut cpu = xo_chip(&[]);
+        cpu.gfx[3 * 64] = 1;
+
+        cpu.execute(Instruction::ScrollUp { n: 3 }).unwrap();
+        assert_eq!((cpu.gfx[0], cpu.gfx[3 * 64]), (1, 0));
+    }
+
+    #[test]
+    fn loads_audio_and_sets_the_pitch() {
+        let mut cpu = xo_chip(&[]);
+        cpu.memory[0x300..0x310].copy_from_slice(&[0xAA; 16]);
+        cpu.i = 0x300;
+        cpu.v[0] = 112;
+
+        cpu.execute(Instruction::LoadAudio).unwrap();
+        cpu.execute(Instruction::SetPitch { x: 0 }).unwrap();
+
+        assert_eq!(cpu.audio_pattern, [0xAA; 16]);
+        assert!((cpu.audio_sample_rate() - 8000.0).abs() < 1e-6);
+    }
+}
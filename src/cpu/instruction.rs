@@ -1,346 +1,611 @@
-use crate::cpu;
-use std::string::String;
+use crate::cpu::machine::Machine;
+use crate::error::EmulatorError;
 
-pub struct Instruction {
-    pub opcode: u16,
-    pub category: String,
-    pub description: String,
-    pub definition: Box<dyn Fn(&mut cpu::CPU) -> ()>,
+// A decoded opcode. Operands are pulled out of the raw opcode once at decode
+// time, following the usual naming: X and Y are register indices, N is a
+// 4-bit constant, NN an 8-bit constant and NNN a 12-bit address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Noop,                                     // 0000
+    ClearScreen,                              // 00E0
+    Return,                                   // 00EE
+    Jump { nnn: u16 },                        // 1NNN
+    Call { nnn: u16 },                        // 2NNN
+    SkipIfEqual { x: u8, nn: u8 },            // 3XNN
+    SkipIfNotEqual { x: u8, nn: u8 },         // 4XNN
+    SkipIfRegistersEqual { x: u8, y: u8 },    // 5XY0
+    SetConstant { x: u8, nn: u8 },            // 6XNN
+    AddConstant { x: u8, nn: u8 },            // 7XNN
+    Assign { x: u8, y: u8 },                  // 8XY0
+    Or { x: u8, y: u8 },                      // 8XY1
+    And { x: u8, y: u8 },                     // 8XY2
+    Xor { x: u8, y: u8 },                     // 8XY3
+    Add { x: u8, y: u8 },                     // 8XY4
+    Subtract { x: u8, y: u8 },                // 8XY5
+    ShiftRight { x: u8, y: u8 },              // 8XY6
+    SubtractReversed { x: u8, y: u8 },        // 8XY7
+    ShiftLeft { x: u8, y: u8 },               // 8XYE
+    SkipIfRegistersNotEqual { x: u8, y: u8 }, // 9XY0
+    SetIndex { nnn: u16 },                    // ANNN
+    JumpOffset { nnn: u16 },                  // BNNN
+    Random { x: u8, nn: u8 },                 // CXNN
+    Draw { x: u8, y: u8, n: u8 },             // DXYN
+    SkipIfKeyPressed { x: u8 },               // EX9E
+    SkipIfKeyNotPressed { x: u8 },            // EXA1
+    GetDelayTimer { x: u8 },                  // FX07
+    AwaitKey { x: u8 },                       // FX0A
+    SetDelayTimer { x: u8 },                  // FX15
+    SetSoundTimer { x: u8 },                  // FX18
+    AddIndex { x: u8 },                       // FX1E
+    FontCharacter { x: u8 },                  // FX29
+    BinaryCodedDecimal { x: u8 },             // FX33
+    StoreRegisters { x: u8 },                 // FX55
+    LoadRegisters { x: u8 },                  // FX65
+
+    // SUPER-CHIP 1.1
+    ScrollDown { n: u8 },         // 00CN
+    ScrollRight,                  // 00FB
+    ScrollLeft,                   // 00FC
+    Exit,                         // 00FD
+    LowResolution,                // 00FE
+    HighResolution,               // 00FF
+    LargeFontCharacter { x: u8 }, // FX30
+    StoreFlags { x: u8 },         // FX75
+    LoadFlags { x: u8 },          // FX85
+
+    // XO-CHIP
+    ScrollUp { n: u8 },          // 00DN
+    StoreRange { x: u8, y: u8 }, // 5XY2
+    LoadRange { x: u8, y: u8 },  // 5XY3
+    LongLoad,                    // F000 NNNN
+    SelectPlanes { n: u8 },      // FN01
+    LoadAudio,                   // F002
+    SetPitch { x: u8 },          // FX3A
 }
 
-pub fn lookup(opcode: u16) -> Result<Instruction, String> {
-    match opcode {
-        0x0000 => Ok(Instruction {
-            opcode,
-            category: String::from("NOOP"),
-            description: String::from("Perform no operation."),
-            definition: Box::new(|_cpu| ()),
-        }),
-        0x00E0 => Ok(Instruction {
-            opcode,
-            category: String::from("Display"),
-            description: String::from("Clear the screen."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0x00EE => Ok(Instruction {
-            opcode,
-            category: String::from("Flow"),
-            description: String::from("Return from a subroutine."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0x1000..=0x1FFF => Ok(Instruction {
-            opcode,
-            category: String::from("Flow"),
-            description: String::from("Jump to address."),
-            definition: Box::new(|cpu| {
-                // Format: 1NNN
-                cpu.pc = cpu.opcode & 0x0FFF;
-            }),
-        }),
-        0x2000..=0x2FFF => Ok(Instruction {
-            opcode,
-            category: String::from("Flow"),
-            description: String::from("Call subroutine."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0x3000..=0x3FFF => Ok(Instruction {
-            opcode,
-            category: String::from("Conditional"),
-            description: String::from("Skip next instruction if VX equals NN."),
-            definition: Box::new(|cpu|  {
-                // Format: 3XNN
-                let x = (cpu.opcode & 0x0F00) >> 8;
-                let n = cpu.opcode & 0x00FF;
+// Every category returned by `Instruction::category`
+pub const CATEGORIES: [&str; 15] = [
+    "NOOP",
+    "Display",
+    "Flow",
+    "Conditional",
+    "Constant",
+    "Assignment",
+    "Bitwise operation",
+    "Math",
+    "Memory",
+    "Flags",
+    "Random",
+    "Key operation",
+    "Timer",
+    "Sound",
+    "Binary-coded decimal",
+];
 
-                if cpu.v[x as usize] == n as u8 {
-                    cpu.pc += 2;
-                }
-            }),
-        }),
-        0x4000..=0x4FFF => Ok(Instruction {
-            opcode,
-            category: String::from("Conditional"),
-            description: String::from("Skip next instruction if VX not equal to NN."),
-            definition: Box::new(|cpu|  {
-                // Format: 4XNN
-                let x = (cpu.opcode & 0x0F00) >> 8;
-                let n = cpu.opcode & 0x00FF;
+impl Instruction {
+    // The earliest machine that understands this instruction.
+    pub fn machine(&self) -> Machine {
+        match self {
+            Instruction::ScrollDown { .. }
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::LowResolution
+            | Instruction::HighResolution
+            | Instruction::LargeFontCharacter { .. }
+            | Instruction::StoreFlags { .. }
+            | Instruction::LoadFlags { .. } => Machine::SuperChip,
+            Instruction::ScrollUp { .. }
+            | Instruction::StoreRange { .. }
+            | Instruction::LoadRange { .. }
+            | Instruction::LongLoad
+            | Instruction::SelectPlanes { .. }
+            | Instruction::LoadAudio
+            | Instruction::SetPitch { .. } => Machine::XoChip,
+            _ => Machine::Chip8,
+        }
+    }
 
-                if cpu.v[x as usize] != n as u8 {
-                    cpu.pc += 2;
-                }
-            }),
-        }),
-        0x5000..=0x5FFF => Ok(Instruction {
-            opcode,
-            category: String::from("Conditional"),
-            description: String::from("Skip next instruction if VX equals VY."),
-            definition: Box::new(|cpu|  {
-                // Format: 5XY0
-                let x = (cpu.opcode & 0x0F00) >> 8;
-                let y = (cpu.opcode & 0x00F0) >> 4;
+    // Encode the instruction as the opcode `lookup` decodes it from. Operands
+    // are masked to the width of their field. XO-CHIP's F000 is followed by
+    // the address it loads, which is not part of the instruction.
+    pub fn opcode(&self) -> u16 {
+        let vx = |x: u8| ((x & 0xF) as u16) << 8;
+        let vxy = |x: u8, y: u8| vx(x) | ((y & 0xF) as u16) << 4;
+        let vxnn = |x: u8, nn: u8| vx(x) | nn as u16;
 
-                if cpu.v[x as usize] == cpu.v[y as usize] {
-                    cpu.pc += 2;
-                }
-            }),
-        }),
-        0x6000..=0x6FFF => Ok(Instruction {
-            opcode,
-            category: String::from("Constant"),
-            description: String::from("Set VX to NN."),
-            definition: Box::new(|cpu|  {
-                // Format: 6XNN
-                let x = (cpu.opcode & 0x0F00) >> 8;
-                let n = cpu.opcode & 0x00FF;
+        match *self {
+            Instruction::Noop => 0x0000,
+            Instruction::ClearScreen => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::Jump { nnn } => 0x1000 | nnn & 0x0FFF,
+            Instruction::Call { nnn } => 0x2000 | nnn & 0x0FFF,
+            Instruction::SkipIfEqual { x, nn } => 0x3000 | vxnn(x, nn),
+            Instruction::SkipIfNotEqual { x, nn } => 0x4000 | vxnn(x, nn),
+            Instruction::SkipIfRegistersEqual { x, y } => 0x5000 | vxy(x, y),
+            Instruction::SetConstant { x, nn } => 0x6000 | vxnn(x, nn),
+            Instruction::AddConstant { x, nn } => 0x7000 | vxnn(x, nn),
+            Instruction::Assign { x, y } => 0x8000 | vxy(x, y),
+            Instruction::Or { x, y } => 0x8001 | vxy(x, y),
+            Instruction::And { x, y } => 0x8002 | vxy(x, y),
+            Instruction::Xor { x, y } => 0x8003 | vxy(x, y),
+            Instruction::Add { x, y } => 0x8004 | vxy(x, y),
+            Instruction::Subtract { x, y } => 0x8005 | vxy(x, y),
+            Instruction::ShiftRight { x, y } => 0x8006 | vxy(x, y),
+            Instruction::SubtractReversed { x, y } => 0x8007 | vxy(x, y),
+            Instruction::ShiftLeft { x, y } => 0x800E | vxy(x, y),
+            Instruction::SkipIfRegistersNotEqual { x, y } => 0x9000 | vxy(x, y),
+            Instruction::SetIndex { nnn } => 0xA000 | nnn & 0x0FFF,
+            Instruction::JumpOffset { nnn } => 0xB000 | nnn & 0x0FFF,
+            Instruction::Random { x, nn } => 0xC000 | vxnn(x, nn),
+            Instruction::Draw { x, y, n } => 0xD000 | vxy(x, y) | (n & 0xF) as u16,
+            Instruction::SkipIfKeyPressed { x } => 0xE09E | vx(x),
+            Instruction::SkipIfKeyNotPressed { x } => 0xE0A1 | vx(x),
+            Instruction::GetDelayTimer { x } => 0xF007 | vx(x),
+            Instruction::AwaitKey { x } => 0xF00A | vx(x),
+            Instruction::SetDelayTimer { x } => 0xF015 | vx(x),
+            Instruction::SetSoundTimer { x } => 0xF018 | vx(x),
+            Instruction::AddIndex { x } => 0xF01E | vx(x),
+            Instruction::FontCharacter { x } => 0xF029 | vx(x),
+            Instruction::BinaryCodedDecimal { x } => 0xF033 | vx(x),
+            Instruction::StoreRegisters { x } => 0xF055 | vx(x),
+            Instruction::LoadRegisters { x } => 0xF065 | vx(x),
+            Instruction::ScrollDown { n } => 0x00C0 | (n & 0xF) as u16,
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowResolution => 0x00FE,
+            Instruction::HighResolution => 0x00FF,
+            Instruction::LargeFontCharacter { x } => 0xF030 | vx(x),
+            Instruction::StoreFlags { x } => 0xF075 | vx(x),
+            Instruction::LoadFlags { x } => 0xF085 | vx(x),
+            Instruction::ScrollUp { n } => 0x00D0 | (n & 0xF) as u16,
+            Instruction::StoreRange { x, y } => 0x5002 | vxy(x, y),
+            Instruction::LoadRange { x, y } => 0x5003 | vxy(x, y),
+            Instruction::LongLoad => 0xF000,
+            Instruction::SelectPlanes { n } => 0xF001 | vx(n),
+            Instruction::LoadAudio => 0xF002,
+            Instruction::SetPitch { x } => 0xF03A | vx(x),
+        }
+    }
 
-                cpu.v[x as usize] = n as u8;
-            }),
-        }),
-        0x7000..=0x7FFF => Ok(Instruction {
-            opcode,
-            category: String::from("Constant"),
-            description: String::from("Add NN to VX. Carry flag is not changed."),
-            definition: Box::new(|cpu|  {
-                // Format: 7XNN
-                let x = (cpu.opcode & 0x0F00) >> 8;
-                let n = cpu.opcode & 0x00FF;
+    // The instruction's name, as in `SetConstant`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::Noop => "Noop",
+            Instruction::ClearScreen => "ClearScreen",
+            Instruction::Return => "Return",
+            Instruction::Jump { .. } => "Jump",
+            Instruction::Call { .. } => "Call",
+            Instruction::SkipIfEqual { .. } => "SkipIfEqual",
+            Instruction::SkipIfNotEqual { .. } => "SkipIfNotEqual",
+            Instruction::SkipIfRegistersEqual { .. } => "SkipIfRegistersEqual",
+            Instruction::SetConstant { .. } => "SetConstant",
+            Instruction::AddConstant { .. } => "AddConstant",
+            Instruction::Assign { .. } => "Assign",
+            Instruction::Or { .. } => "Or",
+            Instruction::And { .. } => "And",
+            Instruction::Xor { .. } => "Xor",
+            Instruction::Add { .. } => "Add",
+            Instruction::Subtract { .. } => "Subtract",
+            Instruction::ShiftRight { .. } => "ShiftRight",
+            Instruction::SubtractReversed { .. } => "SubtractReversed",
+            Instruction::ShiftLeft { .. } => "ShiftLeft",
+            Instruction::SkipIfRegistersNotEqual { .. } => "SkipIfRegistersNotEqual",
+            Instruction::SetIndex { .. } => "SetIndex",
+            Instruction::JumpOffset { .. } => "JumpOffset",
+            Instruction::Random { .. } => "Random",
+            Instruction::Draw { .. } => "Draw",
+            Instruction::SkipIfKeyPressed { .. } => "SkipIfKeyPressed",
+            Instruction::SkipIfKeyNotPressed { .. } => "SkipIfKeyNotPressed",
+            Instruction::GetDelayTimer { .. } => "GetDelayTimer",
+            Instruction::AwaitKey { .. } => "AwaitKey",
+            Instruction::SetDelayTimer { .. } => "SetDelayTimer",
+            Instruction::SetSoundTimer { .. } => "SetSoundTimer",
+            Instruction::AddIndex { .. } => "AddIndex",
+            Instruction::FontCharacter { .. } => "FontCharacter",
+            Instruction::BinaryCodedDecimal { .. } => "BinaryCodedDecimal",
+            Instruction::StoreRegisters { .. } => "StoreRegisters",
+            Instruction::LoadRegisters { .. } => "LoadRegisters",
+            Instruction::ScrollDown { .. } => "ScrollDown",
+            Instruction::ScrollRight => "ScrollRight",
+            Instruction::ScrollLeft => "ScrollLeft",
+            Instruction::Exit => "Exit",
+            Instruction::LowResolution => "LowResolution",
+            Instruction::HighResolution => "HighResolution",
+            Instruction::LargeFontCharacter { .. } => "LargeFontCharacter",
+            Instruction::StoreFlags { .. } => "StoreFlags",
+            Instruction::LoadFlags { .. } => "LoadFlags",
+            Instruction::ScrollUp { .. } => "ScrollUp",
+            Instruction::StoreRange { .. } => "StoreRange",
+            Instruction::LoadRange { .. } => "LoadRange",
+            Instruction::LongLoad => "LongLoad",
+            Instruction::SelectPlanes { .. } => "SelectPlanes",
+            Instruction::LoadAudio => "LoadAudio",
+            Instruction::SetPitch { .. } => "SetPitch",
+        }
+    }
 
-                cpu.v[x as usize] += n as u8;
-            }),
-        }),
-        0x8000..=0x8FFF => {
-            // Match against rightmost hex digit
-            match opcode & 0x000F {
-                0x0 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Assignment"),
-                    description: String::from("Set VX to the value of VY."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XY0
-                        let x = (cpu.opcode & 0x0F00) >> 8;
-                        let y = (cpu.opcode & 0x00F0) >> 4;
+    // The Cowgod mnemonic the disassembler writes the instruction with, as
+    // in `LD`
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Noop => "NOP",
+            Instruction::ClearScreen => "CLS",
+            Instruction::Return => "RET",
+            Instruction::Jump { .. } | Instruction::JumpOffset { .. } => "JP",
+            Instruction::Call { .. } => "CALL",
+            Instruction::SkipIfEqual { .. } | Instruction::SkipIfRegistersEqual { .. } => "SE",
+            Instruction::SkipIfNotEqual { .. } | Instruction::SkipIfRegistersNotEqual { .. } => {
+                "SNE"
+            }
+            Instruction::SetConstant { .. }
+            | Instruction::Assign { .. }
+            | Instruction::SetIndex { .. }
+            | Instruction::GetDelayTimer { .. }
+            | Instruction::AwaitKey { .. }
+            | Instruction::SetDelayTimer { .. }
+            | Instruction::SetSoundTimer { .. }
+            | Instruction::FontCharacter { .. }
+            | Instruction::BinaryCodedDecimal { .. }
+            | Instruction::StoreRegisters { .. }
+            | Instruction::LoadRegisters { .. }
+            | Instruction::LargeFontCharacter { .. }
+            | Instruction::StoreFlags { .. }
+            | Instruction::LoadFlags { .. }
+            | Instruction::StoreRange { .. }
+            | Instruction::LoadRange { .. }
+            | Instruction::LongLoad => "LD",
+            Instruction::AddConstant { .. }
+            | Instruction::Add { .. }
+            | Instruction::AddIndex { .. } => "ADD",
+            Instruction::Or { .. } => "OR",
+            Instruction::And { .. } => "AND",
+            Instruction::Xor { .. } => "XOR",
+            Instruction::Subtract { .. } => "SUB",
+            Instruction::ShiftRight { .. } => "SHR",
+            Instruction::SubtractReversed { .. } => "SUBN",
+            Instruction::ShiftLeft { .. } => "SHL",
+            Instruction::Random { .. } => "RND",
+            Instruction::Draw { .. } => "DRW",
+            Instruction::SkipIfKeyPressed { .. } => "SKP",
+            Instruction::SkipIfKeyNotPressed { .. } => "SKNP",
+            Instruction::ScrollDown { .. } => "SCD",
+            Instruction::ScrollRight => "SCR",
+            Instruction::ScrollLeft => "SCL",
+            Instruction::Exit => "EXIT",
+            Instruction::LowResolution => "LOW",
+            Instruction::HighResolution => "HIGH",
+            Instruction::ScrollUp { .. } => "SCU",
+            Instruction::SelectPlanes { .. } => "PLANE",
+            Instruction::LoadAudio => "AUDIO",
+            Instruction::SetPitch { .. } => "PITCH",
+        }
+    }
+
+    // The operands decoded from the opcode, by field name
+    pub fn operands(&self) -> Vec<(&'static str, u16)> {
+        use Instruction::*;
+
+        match *self {
+            Jump { nnn } | Call { nnn } | SetIndex { nnn } | JumpOffset { nnn } => {
+                vec![("nnn", nnn)]
+            }
+            SkipIfEqual { x, nn }
+            | SkipIfNotEqual { x, nn }
+            | SetConstant { x, nn }
+            | AddConstant { x, nn }
+            | Random { x, nn } => vec![("x", x as u16), ("nn", nn as u16)],
+            SkipIfRegistersEqual { x, y }
+            | Assign { x, y }
+            | Or { x, y }
+            | And { x, y }
+            | Xor { x, y }
+            | Add { x, y }
+            | Subtract { x, y }
+            | ShiftRight { x, y }
+            | SubtractReversed { x, y }
+            | ShiftLeft { x, y }
+            | SkipIfRegistersNotEqual { x, y }
+            | StoreRange { x, y }
+            | LoadRange { x, y } => vec![("x", x as u16), ("y", y as u16)],
+            Draw { x, y, n } => vec![("x", x as u16), ("y", y as u16), ("n", n as u16)],
+            SkipIfKeyPressed { x }
+            | SkipIfKeyNotPressed { x }
+            | GetDelayTimer { x }
+            | AwaitKey { x }
+            | SetDelayTimer { x }
+            | SetSoundTimer { x }
+            | AddIndex { x }
+            | FontCharacter { x }
+            | BinaryCodedDecimal { x }
+            | StoreRegisters { x }
+            | LoadRegisters { x }
+            | LargeFontCharacter { x }
+            | StoreFlags { x }
+            | LoadFlags { x }
+            | SetPitch { x } => vec![("x", x as u16)],
+            ScrollDown { n } | ScrollUp { n } | SelectPlanes { n } => vec![("n", n as u16)],
+            Noop | ClearScreen | Return | ScrollRight | ScrollLeft | Exit | LowResolution
+            | HighResolution | LongLoad | LoadAudio => vec![],
+        }
+    }
+
+    pub fn category(&self) -> &'static str {
+        match self {
+            Instruction::Noop => "NOOP",
+            Instruction::ClearScreen
+            | Instruction::Draw { .. }
+            | Instruction::ScrollDown { .. }
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::LowResolution
+            | Instruction::HighResolution
+            | Instruction::ScrollUp { .. }
+            | Instruction::SelectPlanes { .. } => "Display",
+            Instruction::Exit
+            | Instruction::Return
+            | Instruction::Jump { .. }
+            | Instruction::Call { .. }
+            | Instruction::JumpOffset { .. } => "Flow",
+            Instruction::SkipIfEqual { .. }
+            | Instruction::SkipIfNotEqual { .. }
+            | Instruction::SkipIfRegistersEqual { .. }
+            | Instruction::SkipIfRegistersNotEqual { .. } => "Conditional",
+            Instruction::SetConstant { .. } | Instruction::AddConstant { .. } => "Constant",
+            Instruction::Assign { .. } => "Assignment",
+            Instruction::Or { .. }
+            | Instruction::And { .. }
+            | Instruction::Xor { .. }
+            | Instruction::ShiftRight { .. }
+            | Instruction::ShiftLeft { .. } => "Bitwise operation",
+            Instruction::Add { .. }
+            | Instruction::Subtract { .. }
+            | Instruction::SubtractReversed { .. } => "Math",
+            Instruction::SetIndex { .. }
+            | Instruction::AddIndex { .. }
+            | Instruction::FontCharacter { .. }
+            | Instruction::StoreRegisters { .. }
+            | Instruction::LoadRegisters { .. }
+            | Instruction::LargeFontCharacter { .. }
+            | Instruction::StoreRange { .. }
+            | Instruction::LoadRange { .. }
+            | Instruction::LongLoad => "Memory",
+            Instruction::StoreFlags { .. } | Instruction::LoadFlags { .. } => "Flags",
+            Instruction::Random { .. } => "Random",
+            Instruction::SkipIfKeyPressed { .. }
+            | Instruction::SkipIfKeyNotPressed { .. }
+            | Instruction::AwaitKey { .. } => "Key operation",
+            Instruction::GetDelayTimer { .. } | Instruction::SetDelayTimer { .. } => "Timer",
+            Instruction::SetSoundTimer { .. }
+            | Instruction::LoadAudio
+            | Instruction::SetPitch { .. } => "Sound",
+            Instruction::BinaryCodedDecimal { .. } => "Binary-coded decimal",
+        }
+    }
 
-                        cpu.v[x as usize] = cpu.v[y as usize]; 
-                    }),
-                }),
-                0x1 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Bitwise operation"),
-                    description: String::from("Set VX to VX OR VY."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XY1
-                        let x = (cpu.opcode & 0x0F00) >> 8;
-                        let y = (cpu.opcode & 0x00F0) >> 4;
+    pub fn description(&self) -> &'static str {
+        match self {
+            Instruction::Noop => "Perform no operation.",
+            Instruction::ClearScreen => "Clear the screen.",
+            Instruction::Return => "Return from a subroutine.",
+            Instruction::Jump { .. } => "Jump to address.",
+            Instruction::Call { .. } => "Call subroutine.",
+            Instruction::SkipIfEqual { .. } => "Skip next instruction if VX equals NN.",
+            Instruction::SkipIfNotEqual { .. } => "Skip next instruction if VX not equal to NN.",
+            Instruction::SkipIfRegistersEqual { .. } => "Skip next instruction if VX equals VY.",
+            Instruction::SetConstant { .. } => "Set VX to NN.",
+            Instruction::AddConstant { .. } => "Add NN to VX. Carry flag is not changed.",
+            Instruction::Assign { .. } => "Set VX to the value of VY.",
+            Instruction::Or { .. } => "Set VX to VX OR VY.",
+            Instruction::And { .. } => "Sets VX to VX AND VY.",
+            Instruction::Xor { .. } => "Set VX to VX XOR VY.",
+            Instruction::Add { .. } => "Add VY to VX. VF is set to 1 when there's a carry, and 0 otherwise.",
+            Instruction::Subtract { .. } => "Subtract VY from VX. VF is set to 0 when there's a borrow, and 1 otherwise.",
+            Instruction::ShiftRight { .. } => "Store the least significant bit of VX in VF and shift VX right by 1.",
+            Instruction::SubtractReversed { .. } => "Set VX to VY minus VX. VF is set to 0 when there's a borrow, and 1 otherwise.",
+            Instruction::ShiftLeft { .. } => "Store the most significant bit of VX in VF and shift VX left by 1.",
+            Instruction::SkipIfRegistersNotEqual { .. } => "Skip the next instruction if VX does not equal VY.",
+            Instruction::SetIndex { .. } => "Set I to the address NNN.",
+            Instruction::JumpOffset { .. } => "Jump to the address NNN plus V0. CHIP-48 and SUPER-CHIP read it as BXNN and jump to XNN plus VX instead, under the jump_offset_uses_vx quirk.",
+            Instruction::Random { .. } => "Set VX to the result of a bitwise AND on a random number and NN.",
+            Instruction::Draw { .. } => "Draw a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels. Each row of 8 pixels is read as bit-coded starting from memory location I; I's value doesn't change after the execution of this instruction. VF is set to 1 if any screen pixels are flipped from set to unset when the sprite is drawn, and 0 otherwise. On SUPER-CHIP, a height of 0 draws a 16x16 sprite.",
+            Instruction::SkipIfKeyPressed { .. } => "Skip the next instruction if the key stored in VX is pressed.",
+            Instruction::SkipIfKeyNotPressed { .. } => "Skip the next instruction if the key stored in VX is not pressed.",
+            Instruction::GetDelayTimer { .. } => "Set VX to the value of the delay timer.",
+            Instruction::AwaitKey { .. } => "Await a key press, then store in VX (blocking operation).",
+            Instruction::SetDelayTimer { .. } => "Set the delay timer to VX.",
+            Instruction::SetSoundTimer { .. } => "Set the sound timer to VX.",
+            Instruction::AddIndex { .. } => "Add VX to I. VF is not affected, except on the Amiga interpreter, which set it to 1 when I passed the end of memory and to 0 otherwise, under the index_overflow_sets_vf quirk.",
+            Instruction::FontCharacter { .. } => "Set I to the location of the sprite for the character in VX. Characters 0-F (in hex) are represented by a 4x5 font.",
+            Instruction::BinaryCodedDecimal { .. } => "Stores the binary-coded decimal representation of VX, with the most significant 3 digits at the address in I, the middle digit at I plus 1, and the least significant digit at I plus 2.",
+            Instruction::StoreRegisters { .. } => "Store V0 to VX (including VX) in memory starting at address I. The COSMAC VIP left I pointing past the last value written, under the load_store_increments_i quirk, and CHIP-48 at the last value, under load_store_increments_i_by_x; otherwise I is left unmodified.",
+            Instruction::LoadRegisters { .. } => "Fill V0 to VX (including VX) with values from memory starting at address I. The COSMAC VIP left I pointing past the last value read, under the load_store_increments_i quirk, and CHIP-48 at the last value, under load_store_increments_i_by_x; otherwise I is left unmodified.",
+            Instruction::ScrollDown { .. } => "Scroll the display down by N pixels.",
+            Instruction::ScrollRight => "Scroll the display right by 4 pixels.",
+            Instruction::ScrollLeft => "Scroll the display left by 4 pixels.",
+            Instruction::Exit => "Exit the interpreter.",
+            Instruction::LowResolution => "Switch to the 64x32 low resolution display.",
+            Instruction::HighResolution => "Switch to the 128x64 high resolution display.",
+            Instruction::LargeFontCharacter { .. } => "Set I to the location of the sprite for the character in VX. Characters 0-F (in hex) are represented by an 8x10 font.",
+            Instruction::StoreFlags { .. } => "Store V0 to VX (including VX) in the RPL user flags.",
+            Instruction::LoadFlags { .. } => "Fill V0 to VX (including VX) with values from the RPL user flags.",
+            Instruction::ScrollUp { .. } => "Scroll the display up by N pixels.",
+            Instruction::StoreRange { .. } => "Store VX to VY (inclusive, in either order) in memory starting at address I. I is left unmodified.",
+            Instruction::LoadRange { .. } => "Fill VX to VY (inclusive, in either order) with values from memory starting at address I. I is left unmodified.",
+            Instruction::LongLoad => "Set I to the 16-bit address stored in the next two bytes, and skip over them.",
+            Instruction::SelectPlanes { .. } => "Select the bitplanes, given as the bitmask N, that drawing, clearing and scrolling affect.",
+            Instruction::LoadAudio => "Load the 16-byte audio pattern buffer from memory starting at address I.",
+            Instruction::SetPitch { .. } => "Set the audio pattern playback pitch to VX.",
+        }
+    }
+}
 
-                        cpu.v[x as usize] = cpu.v[x as usize] | cpu.v[y as usize]; 
-                    }),
-                }),
-                0x2 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Bitwise operation"),
-                    description: String::from("Sets VX to VX AND VY."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XY2
-                        let x = (cpu.opcode & 0x0F00) >> 8;
-                        let y = (cpu.opcode & 0x00F0) >> 4;
+pub fn lookup(opcode: u16) -> Result<Instruction, EmulatorError> {
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let nn = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
 
-                        cpu.v[x as usize] = cpu.v[x as usize] & cpu.v[y as usize]; 
-                    }),
-                }),
-                0x3 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Bitwise operation"),
-                    description: String::from("Set VX to VX XOR VY."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XY3
-                        let x = (cpu.opcode & 0x0F00) >> 8;
-                        let y = (cpu.opcode & 0x00F0) >> 4;
+    match opcode {
+        0x0000 => Ok(Instruction::Noop),
+        0x00C0..=0x00CF => Ok(Instruction::ScrollDown { n }),
+        0x00D0..=0x00DF => Ok(Instruction::ScrollUp { n }),
+        0x00E0 => Ok(Instruction::ClearScreen),
+        0x00EE => Ok(Instruction::Return),
+        0x00FB => Ok(Instruction::ScrollRight),
+        0x00FC => Ok(Instruction::ScrollLeft),
+        0x00FD => Ok(Instruction::Exit),
+        0x00FE => Ok(Instruction::LowResolution),
+        0x00FF => Ok(Instruction::HighResolution),
+        0x1000..=0x1FFF => Ok(Instruction::Jump { nnn }),
+        0x2000..=0x2FFF => Ok(Instruction::Call { nnn }),
+        0x3000..=0x3FFF => Ok(Instruction::SkipIfEqual { x, nn }),
+        0x4000..=0x4FFF => Ok(Instruction::SkipIfNotEqual { x, nn }),
+        0x5000..=0x5FFF => {
+            // Match against rightmost hex digit
+            match n {
+                0x0 => Ok(Instruction::SkipIfRegistersEqual { x, y }),
+                0x2 => Ok(Instruction::StoreRange { x, y }),
+                0x3 => Ok(Instruction::LoadRange { x, y }),
+                _ => Err(unknown(opcode)),
+            }
+        }
+        0x6000..=0x6FFF => Ok(Instruction::SetConstant { x, nn }),
+        0x7000..=0x7FFF => Ok(Instruction::AddConstant { x, nn }),
+        0x8000..=0x8FFF => {
+            // Match against rightmost hex digit
+            match n {
+                0x0 => Ok(Instruction::Assign { x, y }),
+                0x1 => Ok(Instruction::Or { x, y }),
+                0x2 => Ok(Instruction::And { x, y }),
+                0x3 => Ok(Instruction::Xor { x, y }),
+                0x4 => Ok(Instruction::Add { x, y }),
+                0x5 => Ok(Instruction::Subtract { x, y }),
+                0x6 => Ok(Instruction::ShiftRight { x, y }),
+                0x7 => Ok(Instruction::SubtractReversed { x, y }),
+                0xE => Ok(Instruction::ShiftLeft { x, y }),
+                _ => Err(unknown(opcode)),
+            }
+        }
+        0x9000..=0x9FFF => Ok(Instruction::SkipIfRegistersNotEqual { x, y }),
+        0xA000..=0xAFFF => Ok(Instruction::SetIndex { nnn }),
+        0xB000..=0xBFFF => Ok(Instruction::JumpOffset { nnn }),
+        0xC000..=0xCFFF => Ok(Instruction::Random { x, nn }),
+        0xD000..=0xDFFF => Ok(Instruction::Draw { x, y, n }),
+        0xE000..=0xEFFF => {
+            // Match against rightmost byte
+            match nn {
+                0x9E => Ok(Instruction::SkipIfKeyPressed { x }),
+                0xA1 => Ok(Instruction::SkipIfKeyNotPressed { x }),
+                _ => Err(unknown(opcode)),
+            }
+        }
+        0xF000..=0xFFFF => {
+            // Match against rightmost byte
+            match nn {
+                0x00 if x == 0 => Ok(Instruction::LongLoad),
+                0x01 => Ok(Instruction::SelectPlanes { n: x }),
+                0x02 if x == 0 => Ok(Instruction::LoadAudio),
+                0x07 => Ok(Instruction::GetDelayTimer { x }),
+                0x0A => Ok(Instruction::AwaitKey { x }),
+                0x15 => Ok(Instruction::SetDelayTimer { x }),
+                0x18 => Ok(Instruction::SetSoundTimer { x }),
+                0x1E => Ok(Instruction::AddIndex { x }),
+                0x29 => Ok(Instruction::FontCharacter { x }),
+                0x30 => Ok(Instruction::LargeFontCharacter { x }),
+                0x33 => Ok(Instruction::BinaryCodedDecimal { x }),
+                0x3A => Ok(Instruction::SetPitch { x }),
+                0x55 => Ok(Instruction::StoreRegisters { x }),
+                0x65 => Ok(Instruction::LoadRegisters { x }),
+                0x75 => Ok(Instruction::StoreFlags { x }),
+                0x85 => Ok(Instruction::LoadFlags { x }),
+                _ => Err(unknown(opcode)),
+            }
+        }
+        _ => Err(unknown(opcode)),
+    }
+}
 
-                        cpu.v[x as usize] = cpu.v[x as usize] ^ cpu.v[y as usize]; 
-                    }),
-                }),
-                0x4 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Math"),
-                    description: String::from("Add VY to VX. VF is set to 1 when there's a carry, and 0 otherwise."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XY4
-                        let x = (cpu.opcode & 0x0F00) >> 8;
-                        let y = (cpu.opcode & 0x00F0) >> 4;
+fn unknown(opcode: u16) -> EmulatorError {
+    EmulatorError::UnknownOpcode { opcode, pc: None }
+}
 
-                        // Add u8 as u16 values to check for carry
-                        let sum: u16 = cpu.v[x as usize] as u16 + cpu.v[y as usize] as u16;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                        cpu.v[x as usize] = sum as u8;
+    #[test]
+    fn decodes_operands() {
+        assert_eq!(
+            lookup(0xD12F).unwrap(),
+            Instruction::Draw { x: 1, y: 2, n: 0xF }
+        );
+        assert_eq!(
+            lookup(0x7A42).unwrap(),
+            Instruction::AddConstant { x: 0xA, nn: 0x42 }
+        );
+        assert_eq!(
+            lookup(0xB123).unwrap(),
+            Instruction::JumpOffset { nnn: 0x123 }
+        );
+        assert_eq!(lookup(0xF30A).unwrap(), Instruction::AwaitKey { x: 3 });
+    }
 
-                        // Set carry flag
-                        let carry: bool = (sum & 0xFF00) > 0;
-                        cpu.v[0xF as usize] = if carry { 1 } else { 0 };
-                    }),
-                }),
-                0x5 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Math"),
-                    description: String::from("Subtract VY from VX. VF is set to 0 when there's a borrow, and 1 otherwise."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XY5
-                        let x = (cpu.opcode & 0x0F00) >> 8;
-                        let y = (cpu.opcode & 0x00F0) >> 4;
+    #[test]
+    fn rejects_unknown_opcodes() {
+        for &opcode in &[0x5001, 0x8008, 0xE000, 0xF0FF, 0x0123] {
+            match lookup(opcode) {
+                Err(EmulatorError::UnknownOpcode { opcode: found, pc }) => {
+                    assert_eq!((found, pc), (opcode, None))
+                }
+                result => panic!("{:04X} decoded to {:?}", opcode, result),
+            }
+        }
+    }
 
-                        // Subtract u8 as i16 values to check for borrow
-                        let difference: i16 = cpu.v[x as usize] as i16 - cpu.v[y as usize] as i16;
+    #[test]
+    fn opcode_is_the_inverse_of_lookup() {
+        for opcode in 0..=0xFFFF {
+            if let Ok(instruction) = lookup(opcode) {
+                assert_eq!(lookup(instruction.opcode()).unwrap(), instruction);
+            }
+        }
 
-                        cpu.v[x as usize] = difference as u8;
+        // 9XY0 is decoded whatever its last digit is
+        assert_eq!(lookup(0x9125).unwrap().opcode(), 0x9120);
+    }
 
-                        // Set borrow flag
-                        cpu.v[0xF as usize] = if difference < 0 { 0 } else { 1 };
-                    }),
-                }),
-                0x6 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Bitwise operation"),
-                    description: String::from("Store the least significant bit of VX in VF and shift VX right by 1."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XY6
-                        let x = (cpu.opcode & 0x0F00) >> 8;
+    #[test]
+    fn names_instructions_and_operands() {
+        let draw = Instruction::Draw { x: 1, y: 2, n: 3 };
+        assert_eq!(draw.name(), "Draw");
+        assert_eq!(draw.operands(), vec![("x", 1), ("y", 2), ("n", 3)]);
 
-                        cpu.v[0xF as usize] = cpu.v[x as usize] & 0x000F & 0b0001;
-                        cpu.v[x as usize] = cpu.v[x as usize] >> 1;
-                    }),
-                }),
-                0x7 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Math"),
-                    description: String::from("Set VX to VY minus VX. VF is set to 0 when there's a bottow, and 1 otherwise."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XY7
-                        let x = (cpu.opcode & 0x0F00) >> 8;
-                        let y = (cpu.opcode & 0x0F00) >> 4;
-                        let difference: i16 = cpu.v[y as usize] as i16 - cpu.v[x as usize] as i16;
+        assert_eq!(draw.mnemonic(), "DRW");
 
-                        cpu.v[x as usize] = difference as u8;
-                        cpu.v[0xF as usize] = if difference < 0 { 0 } else { 1 };
-                    }),
-                }),
-                0xE => Ok(Instruction {
-                    opcode,
-                    category: String::from("Bitwise operation"),
-                    description: String::from("Store the most significant bit of VX in VF an shifts VX left by 1."),
-                    definition: Box::new(|cpu|  {
-                        // Format: 8XYE
-                        let x = (cpu.opcode & 0x0F00) >> 8;
+        assert_eq!(Instruction::LongLoad.name(), "LongLoad");
+        assert_eq!(Instruction::LongLoad.mnemonic(), "LD");
+        assert!(Instruction::LongLoad.operands().is_empty());
+    }
 
-                        cpu.v[0xF as usize] = cpu.v[x as usize] & 0x000F & 0b0001;
-                        cpu.v[x as usize] = cpu.v[x as usize] << 1;
-                    }),
-                }),
-                _ => Err(format!("Opcode {} not found", format!("{:0>4X}", opcode))),
+    #[test]
+    fn names_match_the_debug_output() {
+        for opcode in 0..=0xFFFF {
+            if let Ok(instruction) = lookup(opcode) {
+                let debug = format!("{:?}", instruction);
+                assert_eq!(debug.split(' ').next(), Some(instruction.name()));
             }
         }
-        0x9000..=0x9FFF => Ok(Instruction {
-            opcode,
-            category: String::from("Conditional"),
-            description: String::from("Skip the next instruction if VX does not equal VY."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0xA000..=0xAFFF => Ok(Instruction {
-            opcode,
-            category: String::from("Memory"),
-            description: String::from("Set I to the address NNN."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0xB000..=0xBFFF => Ok(Instruction {
-            opcode,
-            category: String::from("Flow"),
-            description: String::from("Jump to the address NNN plus V0."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0xC000..=0xCFFF => Ok(Instruction {
-            opcode,
-            category: String::from("Random"),
-            description: String::from(
-                "Set VX to the result of a bitwise AND on a random number and NN.",
-            ),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0xD000..=0xDFFF => Ok(Instruction {
-            opcode,
-            category: String::from("Display"),
-            description: String::from("Draw a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels. Each row of 8 pixels is read as bit-coded starting from memory location I; I's value doesn't change after the execution of this instruction. VF is set to 1 if any screen pixels are flipped from set to unset when the sprite is drawn, and 0 otherwise."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0xE09E | 0xE19E | 0xE29E | 0xE39E | 0xE49E | 0xE59E | 0xE69E | 0xE79E | 0xE89E | 0xE99E => Ok(Instruction {
-            opcode,
-            category: String::from("Key operation"),
-            description: String::from("Skip the next instruction if the key stored in VX is pressed."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0xE0A1 | 0xE1A1 | 0xE2A1 | 0xE3A1 | 0xE4A1 | 0xE5A1 | 0xE6A1 | 0xE7A1 | 0xE8A1 | 0xE9A1 => Ok(Instruction {
-            opcode,
-            category: String::from("Key operation"),
-            description: String::from("Skip the next instruction if the key stored in VX is not pressed."),
-            definition: Box::new(|_cpu| eprint!("No definition.")),
-        }),
-        0xF000..=0xFFFF => {
-            match opcode & 0x0FFF {
-                0x007 | 0x107 | 0x207 | 0x307 | 0x407 | 0x507 | 0x607 | 0x707 | 0x807 | 0x907 | 0xA07 | 0xB07 | 0xC07 | 0xD07 | 0xE07 | 0xF07 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Timer"),
-                    description: String::from("Set VX to the value of the delay timer."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                0x00A | 0x10A | 0x20A | 0x30A | 0x40A | 0x50A | 0x60A | 0x70A | 0x80A | 0x90A | 0xA0A | 0xB0A | 0xC0A | 0xD0A | 0xE0A | 0xF0A => Ok(Instruction {
-                    opcode,
-                    category: String::from("Key operation"),
-                    description: String::from("Await a key press, then store in VX (blocking operation)."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                0x015 | 0x115 | 0x215 | 0x315 | 0x415 | 0x515 | 0x615 | 0x715 | 0x815 | 0x915 | 0xA15 | 0xB15 | 0xC15 | 0xD15 | 0xE15 | 0xF15 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Timer"),
-                    description: String::from("Set the delay timer to VX."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                0x018 | 0x118 | 0x218 | 0x318 | 0x418 | 0x518 | 0x618 | 0x718 | 0x818 | 0x918 | 0xA18 | 0xB18 | 0xC18 | 0xD18 | 0xE18 | 0xF18 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Sound"),
-                    description: String::from("Set the sound timer to VX."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                0x01E | 0x11E | 0x21E | 0x31E | 0x41E | 0x51E | 0x61E | 0x71E | 0x81E | 0x91E | 0xA1E | 0xB1E | 0xC1E | 0xD1E | 0xE1E | 0xF1E => Ok(Instruction {
-                    opcode,
-                    category: String::from("Memory"),
-                    description: String::from("Add VX to I. VF is set to 1 when there is a range overflow (I + VX > 0xFFF), 0 otherwise."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                0x029 | 0x129 | 0x229 | 0x329 | 0x429 | 0x529 | 0x629 | 0x729 | 0x829 | 0x929 | 0xA29 | 0xB29 | 0xC29 | 0xD29 | 0xE29 | 0xF29 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Memory"),
-                            description: String::from("Set I to the location of the sprite for the character in VX. Characters 0-F (in hex) are represented by a 4x5 font."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                0x033 | 0x133 | 0x233 | 0x333 | 0x433 | 0x533 | 0x633 | 0x733 | 0x833 | 0x933 | 0xA33 | 0xB33 | 0xC33 | 0xD33 | 0xE33 | 0xF33 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Binary-coded decimal"),
-                    description: String::from("Stores the binary-coded decimal representation of VX, with the most significant 3 digits at the address in I, the middle digit at I plus 1, and the least significant digit at I plus 2."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                0x055 | 0x155 | 0x255 | 0x553 | 0x455 | 0x555 | 0x655 | 0x755 | 0x855 | 0x955 | 0xA55 | 0xB55 | 0xC55 | 0xD55 | 0xE55 | 0xF55 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Memory"),
-                    description: String::from("Store V0 to VX (including VX) in memory starting at address I. The offset from I is increased by 1 for each value written, but I is left unmodified."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                0x065 | 0x165 | 0x265 | 0x365 | 0x465 | 0x565 | 0x665 | 0x765 | 0x865 | 0x965 | 0xA65 | 0xB65 | 0xC65 | 0xD65 | 0xE65 | 0xF65 => Ok(Instruction {
-                    opcode,
-                    category: String::from("Memory"),
-                    description: String::from("Fill V0 into VX (including VX) with values from memory starting address I. The offset from I is increased by 1 for each value written, but I is left unmodified."),
-                    definition: Box::new(|_cpu| eprint!("No definition.")),
-                }),
-                _ => Err(format!("Opcode {} not found", format!("{:0>4X}", opcode))),
+    }
+
+    #[test]
+    fn every_instruction_has_a_known_category() {
+        for opcode in 0..=0xFFFF {
+            if let Ok(instruction) = lookup(opcode) {
+                assert!(CATEGORIES.contains(&instruction.category()));
             }
         }
-        _ => Err(format!("Opcode {} not found", format!("{:0>4X}", opcode))),
     }
 }
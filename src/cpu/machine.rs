@@ -0,0 +1,44 @@
+// The family of interpreter being emulated. Each machine is a superset of the
+// one before it, so they are ordered: an instruction introduced by a machine
+// is available on every later one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Machine {
+    // The original CHIP-8 instruction set
+    #[default]
+    Chip8,
+    // SUPER-CHIP 1.1, adding a 128x64 display mode, scrolling, 16x16
+    // sprites, a large font, an exit instruction and RPL user flags
+    SuperChip,
+    // XO-CHIP, adding 64KB of memory, a second bitplane for four colours,
+    // register range loads and stores, upward scrolling and sampled audio
+    XoChip,
+}
+
+impl Machine {
+    // Number of RPL user flags FX75 and FX85 can reach
+    pub fn flag_count(&self) -> usize {
+        match self {
+            Machine::Chip8 => 0,
+            Machine::SuperChip => 8,
+            Machine::XoChip => 16,
+        }
+    }
+
+    // Amount of memory programs can address
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Machine::Chip8 | Machine::SuperChip => 0x1000,
+            Machine::XoChip => 0x10000,
+        }
+    }
+
+    // Look up a machine by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Machine> {
+        match name.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" => Some(Machine::Chip8),
+            "schip" | "super-chip" => Some(Machine::SuperChip),
+            "xochip" | "xo-chip" => Some(Machine::XoChip),
+            _ => None,
+        }
+    }
+}
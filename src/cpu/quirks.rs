@@ -0,0 +1,136 @@
+use crate::cpu::machine::Machine;
+
+// Behaviours that differ between historical interpreters. Programs written for
+// one interpreter often misbehave on another, so each divergence can be
+// switched individually or through one of the named presets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6 and 8XYE shift VX in place. The COSMAC VIP shifted VY and stored
+    // the result in VX.
+    pub shift_in_place: bool,
+
+    // FX55 and FX65 leave I pointing past the last register stored or loaded.
+    // Later interpreters leave I unchanged.
+    pub load_store_increments_i: bool,
+
+    // With `load_store_increments_i`, I is left pointing at the last register
+    // rather than past it, as CHIP-48 increased it by X instead of X + 1.
+    pub load_store_increments_i_by_x: bool,
+
+    // BNNN is read as BXNN and jumps to XNN plus VX rather than NNN plus V0.
+    pub jump_offset_uses_vx: bool,
+
+    // 8XY1, 8XY2 and 8XY3 reset VF to 0, a side effect of how the COSMAC VIP
+    // ran them.
+    pub logic_resets_vf: bool,
+
+    // FX1E sets VF to 1 when I passes the end of memory, and to 0 otherwise,
+    // keeping I within memory. Only the Amiga interpreter did this, and one
+    // known game relies on it; every preset leaves it off, as it clobbers VF
+    // for programs using it as an ordinary register.
+    pub index_overflow_sets_vf: bool,
+
+    // DXYN cuts sprites off at the edges of the screen instead of wrapping
+    // them around to the opposite edge. The starting coordinate always wraps.
+    pub clip_sprites: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::cosmac_vip()
+    }
+}
+
+impl Quirks {
+    // The original interpreter for the RCA COSMAC VIP (1977)
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_in_place: false,
+            load_store_increments_i: true,
+            load_store_increments_i_by_x: false,
+            jump_offset_uses_vx: false,
+            logic_resets_vf: true,
+            index_overflow_sets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    // CHIP-48 for the HP48 calculators (1990)
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_in_place: true,
+            load_store_increments_i: true,
+            load_store_increments_i_by_x: true,
+            jump_offset_uses_vx: true,
+            logic_resets_vf: false,
+            index_overflow_sets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    // SUPER-CHIP 1.1 for the HP48 calculators (1991)
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            shift_in_place: true,
+            load_store_increments_i: false,
+            load_store_increments_i_by_x: false,
+            jump_offset_uses_vx: true,
+            logic_resets_vf: false,
+            index_overflow_sets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    // XO-CHIP as implemented by Octo (2015)
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_in_place: false,
+            load_store_increments_i: true,
+            load_store_increments_i_by_x: false,
+            jump_offset_uses_vx: false,
+            logic_resets_vf: false,
+            index_overflow_sets_vf: false,
+            clip_sprites: false,
+        }
+    }
+
+    // The behaviour programs written for a machine usually expect
+    pub fn for_machine(machine: Machine) -> Quirks {
+        match machine {
+            Machine::Chip8 => Quirks::cosmac_vip(),
+            Machine::SuperChip => Quirks::super_chip(),
+            Machine::XoChip => Quirks::xo_chip(),
+        }
+    }
+
+    // Look up a preset by the name used on the command line.
+    pub fn preset(name: &str) -> Option<Quirks> {
+        match name.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" => Some(Quirks::cosmac_vip()),
+            "chip48" | "chip-48" => Some(Quirks::chip48()),
+            "schip" | "super-chip" => Some(Quirks::super_chip()),
+            "xochip" | "xo-chip" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_presets_by_name() {
+        assert_eq!(Quirks::preset("CHIP-48"), Some(Quirks::chip48()));
+        assert_eq!(Quirks::preset("schip"), Some(Quirks::super_chip()));
+        assert_eq!(Quirks::preset("amiga"), None);
+        assert_eq!(Quirks::for_machine(Machine::XoChip), Quirks::xo_chip());
+    }
+
+    #[test]
+    fn presets_leave_vf_alone_on_index_overflow() {
+        for name in &["vip", "chip48", "schip", "xochip"] {
+            assert!(!Quirks::preset(name).unwrap().index_overflow_sets_vf);
+        }
+    }
+}
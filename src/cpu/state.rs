@@ -0,0 +1,418 @@
+use crate::cpu::machine::Machine;
+use crate::cpu::quirks::Quirks;
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use std::fs;
+
+// Save states start with this, followed by the format version and the hash of
+// the ROM the state was taken from. Everything is stored big-endian, like
+// CHIP-8 itself.
+pub const MAGIC: &[u8; 4] = b"CH8S";
+pub const VERSION: u16 = 1;
+
+impl CPU {
+    // Capture everything needed to resume execution exactly where it is. The
+    // trace sink and memory accesses are not part of the machine and are left
+    // out.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+
+        writer.bytes(MAGIC);
+        writer.u16(VERSION);
+        writer.u64(self.rom_hash);
+
+        writer.u8(machine_id(self.machine));
+        writer.u8(quirk_bits(&self.quirks));
+        writer.u16(self.load_address);
+
+        writer.bytes(&self.v);
+        writer.u16(self.i);
+        writer.u16(self.pc);
+        writer.u16(self.opcode);
+        writer.u16(self.sp);
+        for value in self.stack.iter() {
+            writer.u16(*value);
+        }
+
+        writer.u16(self.delay_timer);
+        writer.u16(self.sound_timer);
+        writer.bytes(&self.audio_pattern);
+        writer.u8(self.pitch);
+
+        for value in self.key.iter() {
+            writer.u16(*value);
+        }
+        writer.u16(bits(&self.key_released));
+
+        writer.bytes(&self.rpl);
+        writer.u8(self.halted as u8);
+        writer.u32(self.rng_state);
+
+        writer.u8(self.hires as u8);
+        writer.u8(self.planes);
+        writer.bytes(&self.gfx);
+        writer.bytes(&self.hires_gfx);
+
+        // Only the memory the machine can address is kept, which keeps
+        // CHIP-8 and SUPER-CHIP states small.
+        writer.bytes(&self.memory[..self.machine.memory_size()]);
+
+        writer.0
+    }
+
+    // Restore a state captured with `save_state`. The state must have been
+    // taken while the same ROM was loaded. Nothing is changed if the state is
+    // rejected.
+    pub fn restore_state(&mut self, state: &[u8]) -> Result<(), EmulatorError> {
+        let mut reader = Reader { state, position: 0 };
+
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a save state"));
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+
+        let rom_hash = reader.u64()?;
+        if rom_hash != self.rom_hash {
+            return Err(EmulatorError::RomMismatch {
+                expected: self.rom_hash,
+                found: rom_hash,
+            });
+        }
+
+        // Read into a copy so that a truncated state leaves this CPU alone
+        let mut restored = CPU {
+            rom_hash,
+            machine: machine_from_id(reader.u8()?)?,
+            quirks: quirks_from_bits(reader.u8()?),
+            load_address: reader.u16()?,
+            ..Default::default()
+        };
+
+        restored.v.copy_from_slice(reader.bytes(16)?);
+        restored.i = reader.u16()?;
+        restored.pc = reader.u16()?;
+        restored.opcode = reader.u16()?;
+        restored.sp = reader.u16()?;
+        for value in restored.stack.iter_mut() {
+            *value = reader.u16()?;
+        }
+
+        restored.delay_timer = reader.u16()?;
+        restored.sound_timer = reader.u16()?;
+        restored.audio_pattern.copy_from_slice(reader.bytes(16)?);
+        restored.pitch = reader.u8()?;
+
+        for value in restored.key.iter_mut() {
+            *value = reader.u16()?;
+        }
+        let released = reader.u16()?;
+        for (key, value) in restored.key_released.iter_mut().enumerate() {
+            *value = released & (1 << key) != 0;
+        }
+
+        restored.rpl.copy_from_slice(reader.bytes(16)?);
+        restored.halted = reader.u8()? != 0;
+        restored.rng_state = reader.u32()?;
+
+        restored.hires = reader.u8()? != 0;
+        restored.planes = reader.u8()?;
+        restored.gfx.copy_from_slice(reader.bytes(64 * 32)?);
+        restored.hires_gfx.copy_from_slice(reader.bytes(128 * 64)?);
+
+        let memory_size = restored.machine.memory_size();
+        restored.memory[..memory_size].copy_from_slice(reader.bytes(memory_size)?);
+
+        if reader.position != state.len() {
+            return Err(invalid("unexpected data after the end of the state"));
+        }
+
+        // Values the CPU indexes with are checked so that a damaged state
+        // cannot crash it later
+        if restored.sp as usize > restored.stack.len() {
+            return Err(invalid(&format!(
+                "stack pointer {} is out of range",
+                restored.sp
+            )));
+        }
+
+        if restored.load_address as usize >= memory_size {
+            return Err(invalid(&format!(
+                "load address {:#06X} is out of range",
+                restored.load_address
+            )));
+        }
+
+        if restored.planes > 0b11 {
+            return Err(invalid(&format!("unknown bitplanes {}", restored.planes)));
+        }
+
+        // Keep the trace sink and memory watching, which are not part of the
+        // saved state
+        std::mem::swap(&mut restored.tracer, &mut self.tracer);
+        std::mem::swap(&mut restored.memory_accesses, &mut self.memory_accesses);
+        *self = restored;
+
+        Ok(())
+    }
+
+    pub fn save_state_file(&self, filename: &str) -> Result<(), EmulatorError> {
+        fs::write(filename, self.save_state())?;
+
+        Ok(())
+    }
+
+    pub fn restore_state_file(&mut self, filename: &str) -> Result<(), EmulatorError> {
+        self.restore_state(&fs::read(filename)?)
+    }
+}
+
+fn invalid(message: &str) -> EmulatorError {
+    EmulatorError::InvalidSaveState(message.to_string())
+}
+
+fn machine_id(machine: Machine) -> u8 {
+    match machine {
+        Machine::Chip8 => 0,
+        Machine::SuperChip => 1,
+        Machine::XoChip => 2,
+    }
+}
+
+fn machine_from_id(id: u8) -> Result<Machine, EmulatorError> {
+    match id {
+        0 => Ok(Machine::Chip8),
+        1 => Ok(Machine::SuperChip),
+        2 => Ok(Machine::XoChip),
+        _ => Err(invalid(&format!("unknown machine {}", id))),
+    }
+}
+
+fn quirk_bits(quirks: &Quirks) -> u8 {
+    bits(&[
+        quirks.shift_in_place,
+        quirks.load_store_increments_i,
+        quirks.jump_offset_uses_vx,
+        quirks.logic_resets_vf,
+        quirks.clip_sprites,
+        quirks.load_store_increments_i_by_x,
+        quirks.index_overflow_sets_vf,
+    ]) as u8
+}
+
+fn quirks_from_bits(bits: u8) -> Quirks {
+    let bit = |index: u8| bits & (1 << index) != 0;
+
+    Quirks {
+        shift_in_place: bit(0),
+        load_store_increments_i: bit(1),
+        jump_offset_uses_vx: bit(2),
+        logic_resets_vf: bit(3),
+        clip_sprites: bit(4),
+        load_store_increments_i_by_x: bit(5),
+        index_overflow_sets_vf: bit(6),
+    }
+}
+
+// Pack up to 16 flags into a bitfield, the first flag in the lowest bit.
+fn bits(flags: &[bool]) -> u16 {
+    flags
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (index, &flag)| bits | (flag as u16) << index)
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+}
+
+struct Reader<'a> {
+    state: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], EmulatorError> {
+        let bytes = self
+            .state
+            .get(self.position..self.position + length)
+            .ok_or_else(|| invalid("the state is truncated"))?;
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, EmulatorError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, EmulatorError> {
+        let bytes = self.bytes(2)?;
+
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, EmulatorError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, EmulatorError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Offsets of fields in a state, after the 14 byte header
+    const SP: usize = 14 + 4 + 16 + 6;
+    const LOAD_ADDRESS: usize = 14 + 2;
+
+    fn running_cpu() -> CPU {
+        let mut cpu = CPU {
+            machine: Machine::SuperChip,
+            quirks: Quirks::super_chip(),
+            ..CPU::default()
+        };
+        cpu.initialize();
+        cpu.load_program_bytes(&[0x60, 0x05, 0x22, 0x06, 0x00, 0x00, 0xF0, 0x15, 0x00, 0xFF])
+            .unwrap();
+
+        for _ in 0..4 {
+            cpu.fetch_decode_execute().unwrap();
+        }
+        cpu.key_released[3] = true;
+        cpu.rpl[2] = 9;
+        cpu
+    }
+
+    #[test]
+    fn restores_exactly_what_was_saved() {
+        let cpu = running_cpu();
+        let state = cpu.save_state();
+
+        let mut restored = CPU {
+            rom_hash: cpu.rom_hash,
+            ..CPU::default()
+        };
+        restored.restore_state(&state).unwrap();
+
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(
+            (restored.pc, restored.sp, restored.delay_timer),
+            (0x20A, 1, 5)
+        );
+        assert_eq!(restored.machine, Machine::SuperChip);
+        assert_eq!(restored.quirks, Quirks::super_chip());
+        assert!(restored.hires && restored.key_released[3]);
+        assert_eq!(restored.rpl[2], 9);
+    }
+
+    #[test]
+    fn refuses_states_from_other_programs() {
+        let state = running_cpu().save_state();
+        let mut other = CPU::default();
+        other.load_program_bytes(&[0x12, 0x00]).unwrap();
+
+        match other.restore_state(&state) {
+            Err(EmulatorError::RomMismatch { expected, .. }) => {
+                assert_eq!(expected, other.rom_hash)
+            }
+            result => panic!("expected a ROM mismatch, got {:?}", result),
+        }
+        assert_eq!(other.pc, 0x200);
+    }
+
+    fn rejected(state: &[u8]) -> String {
+        let mut cpu = running_cpu();
+        let before = cpu.save_state();
+
+        let message = match cpu.restore_state(state) {
+            Err(EmulatorError::InvalidSaveState(message)) => message,
+            result => panic!("expected an invalid state, got {:?}", result),
+        };
+        assert_eq!(cpu.save_state(), before);
+
+        message
+    }
+
+    #[test]
+    fn rejects_damaged_states() {
+        let state = running_cpu().save_state();
+
+        assert_eq!(
+            rejected(&state[..state.len() - 1]),
+            "the state is truncated"
+        );
+        assert_eq!(rejected(&state[..3]), "the state is truncated");
+        assert_eq!(rejected(b"PNG\x00"), "not a save state");
+
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(
+            rejected(&longer),
+            "unexpected data after the end of the state"
+        );
+
+        let mut version = state.clone();
+        version[5] = 9;
+        assert_eq!(rejected(&version), "unsupported version 9");
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let state = running_cpu().save_state();
+
+        let mut sp = state.clone();
+        sp[SP..SP + 2].copy_from_slice(&17u16.to_be_bytes());
+        assert_eq!(rejected(&sp), "stack pointer 17 is out of range");
+
+        let mut load_address = state.clone();
+        load_address[LOAD_ADDRESS..LOAD_ADDRESS + 2].copy_from_slice(&0x1000u16.to_be_bytes());
+        assert_eq!(
+            rejected(&load_address),
+            "load address 0x1000 is out of range"
+        );
+
+        // Planes come after the gfx, hires gfx and memory at the end
+        let mut planes = state.clone();
+        let position = state.len() - 0x1000 - 128 * 64 - 64 * 32 - 1;
+        planes[position] = 4;
+        assert_eq!(rejected(&planes), "unknown bitplanes 4");
+
+        let mut machine = state;
+        machine[14] = 7;
+        assert_eq!(rejected(&machine), "unknown machine 7");
+    }
+}
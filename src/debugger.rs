@@ -0,0 +1,959 @@
+use crate::cpu::instruction::{self, CATEGORIES};
+use crate::cpu::CPU;
+use crate::gif::GifRecorder;
+use crate::rewind::Rewind;
+use crate::scheduler::Scheduler;
+use crate::screenshot::{Palette, DEFAULT_SCALE};
+use crate::trace::{Access, Register};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+// Instructions `continue` runs before giving control back, as most programs
+// end in an endless loop or a wait for a key rather than finishing
+pub const DEFAULT_CONTINUE_LIMIT: u64 = 100_000;
+
+const HELP: &str = "Commands:
+  step, s [N]             Execute N instructions (default 1)
+  continue, c [N]         Run until a breakpoint, the end of the program or
+                          the cycle limit, or for at most N instructions
+                          (default 100000)
+  back [N]                Undo the last N instructions (default 1)
+  break, b <ADDR>         Stop before executing the instruction at ADDR
+  break, b <CATEGORY>     Stop before any instruction in CATEGORY, e.g. Display
+  delete, d <ADDR|CATEGORY|all>
+                          Remove breakpoints
+  breakpoints, bl         List breakpoints
+  watch, w read|write|access <ADDR>[-<END>]
+                          Stop after memory in the range is read or written
+  watch, w <REG>          Stop after V0-VF, I, SP, DT or ST changes
+  watch, w <REG> <OP> <VALUE>
+                          Stop once a register comparison becomes true, with
+                          OP one of == != < <= > >=, e.g. w V3 == 10
+  unwatch <N|all>         Remove watchpoints by their number
+  watchpoints, wl         List watchpoints
+  registers, r            Show the registers
+  memory, x <ADDR> [LEN]  Show LEN bytes of memory from ADDR (default 64)
+  set <REG> <VALUE>       Change V0-VF, I, PC, SP, DT or ST
+  poke <ADDR> <BYTE>...   Write bytes to memory from ADDR
+  backtrace, bt           Show the call stack
+  record <FILE>           Record the display to FILE as an animated GIF, one
+                          frame per emulated frame from now on
+  record stop             Finish the recording
+  help, h                 Print this message
+  quit, q                 Exit the debugger
+Addresses and values are hexadecimal; counts are decimal.";
+
+// Why execution stopped in the middle of `continue` or `step`
+enum Stop {
+    Breakpoint(u16),
+    Category(&'static str),
+    // A watchpoint triggered by the instruction at `pc`
+    Watch {
+        index: usize,
+        pc: u16,
+        detail: String,
+    },
+    Finished,
+    // `continue` ran out of instructions
+    Limit(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<", Comparison::Less),
+        ("<=", Comparison::LessOrEqual),
+        (">", Comparison::Greater),
+        (">=", Comparison::GreaterOrEqual),
+    ];
+
+    fn holds(&self, left: u16, right: u16) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (symbol, _) = Comparison::ALL
+            .iter()
+            .find(|(_, comparison)| comparison == self)
+            .expect("every comparison has a symbol");
+
+        write!(f, "{}", symbol)
+    }
+}
+
+// Something to check after every instruction.
+enum Watch {
+    // Memory from `start` to `end` inclusive was accessed. `None` matches
+    // both reads and writes.
+    Memory {
+        access: Option<Access>,
+        start: u16,
+        end: u16,
+    },
+    Change(Register),
+    // Triggers when the comparison goes from false to true, so that execution
+    // can continue past it while it stays true
+    Condition(Register, Comparison, u16),
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Memory { access, start, end } => {
+                let kind = match access {
+                    Some(Access::Read) => "read",
+                    Some(Access::Write) => "write",
+                    None => "access",
+                };
+
+                write!(f, "{} {:#06X}-{:#06X}", kind, start, end)
+            }
+            Watch::Change(register) => write!(f, "{} changes", register),
+            Watch::Condition(register, comparison, value) => {
+                write!(f, "{} {} {:#04X}", register, comparison, value)
+            }
+        }
+    }
+}
+
+// An interactive debugger reading commands from a line-based input. Execution
+// is driven through a scheduler so that timers tick exactly as they would in a
+// normal run, and recent frames are kept so that instructions can be undone.
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    category_breakpoints: BTreeSet<&'static str>,
+    watches: Vec<Watch>,
+    pub rewind: Rewind,
+    // Instructions `continue` runs when not told how many
+    pub continue_limit: u64,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            category_breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+            rewind: Rewind::default(),
+            continue_limit: DEFAULT_CONTINUE_LIMIT,
+        }
+    }
+}
+
+impl Debugger {
+    // Read and run commands until `quit` or the end of the input.
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+        input: R,
+        mut output: W,
+    ) -> io::Result<()> {
+        self.show_next(cpu, &mut output)?;
+
+        let mut lines = input.lines();
+
+        loop {
+            write!(output, "(chip8) ")?;
+            output.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return writeln!(output),
+            };
+
+            if !self.command(&line, cpu, scheduler, &mut output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Run a single command. Returns false when the user asked to quit.
+    pub fn command<W: Write>(
+        &mut self,
+        line: &str,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+        output: &mut W,
+    ) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let arguments: Vec<&str> = words.collect();
+
+        let result =
+            match command {
+                "step" | "s" => count(arguments.first())
+                    .and_then(|count| self.step(count, cpu, scheduler, output)),
+                "continue" | "c" => match arguments.first() {
+                    Some(_) => count(arguments.first()),
+                    None => Ok(self.continue_limit),
+                }
+                .and_then(|limit| self.resume(limit, cpu, scheduler, output)),
+                "back" => count(arguments.first())
+                    .and_then(|count| self.back(count, cpu, scheduler, output)),
+                "break" | "b" => self.add_breakpoint(&arguments.join(" "), output),
+                "delete" | "d" => self.delete_breakpoint(&arguments.join(" ")),
+                "breakpoints" | "bl" => self.list_breakpoints(output),
+                "watch" | "w" => self.add_watch(&arguments, output),
+                "unwatch" => self.delete_watch(&arguments),
+                "watchpoints" | "wl" => self.list_watches(output),
+                "registers" | "r" => show_registers(cpu, output).map_err(io_error),
+                "memory" | "x" => show_memory(cpu, &arguments, output),
+                "set" => set_register(cpu, &arguments),
+                "poke" => poke(cpu, &arguments),
+                "backtrace" | "bt" => show_backtrace(cpu, output).map_err(io_error),
+                "record" => record(cpu, scheduler, &arguments, output),
+                "help" | "h" => writeln!(output, "{}", HELP).map_err(io_error),
+                "quit" | "q" => return Ok(false),
+                _ => Err(format!("Unknown command: {} (try help)", command)),
+            };
+
+        if let Err(message) = result {
+            writeln!(output, "{}", message)?;
+        }
+
+        Ok(true)
+    }
+
+    fn step<W: Write>(
+        &mut self,
+        count: u64,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+        output: &mut W,
+    ) -> Result<(), String> {
+        for executed in 0..count {
+            // Breakpoints stop a step partway like they stop `continue`, and
+            // likewise never before the first instruction
+            if executed > 0 {
+                if let Some(stop) = self.check_breakpoints(cpu) {
+                    return self.report(stop, cpu, output);
+                }
+            }
+
+            if let Some(stop) = self.execute(cpu, scheduler)? {
+                return self.report(stop, cpu, output);
+            }
+        }
+
+        self.show_next(cpu, output).map_err(io_error)
+    }
+
+    fn resume<W: Write>(
+        &mut self,
+        limit: u64,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+        output: &mut W,
+    ) -> Result<(), String> {
+        for executed in 0..limit {
+            // The first instruction is run regardless, or continuing from a
+            // breakpoint would stop again straight away
+            if executed > 0 {
+                if let Some(stop) = self.check_breakpoints(cpu) {
+                    return self.report(stop, cpu, output);
+                }
+            }
+
+            if let Some(stop) = self.execute(cpu, scheduler)? {
+                return self.report(stop, cpu, output);
+            }
+        }
+
+        self.report(Stop::Limit(limit), cpu, output)
+    }
+
+    fn back<W: Write>(
+        &mut self,
+        count: u64,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+        output: &mut W,
+    ) -> Result<(), String> {
+        for _ in 0..count {
+            match self.rewind.step_back_instruction(cpu, scheduler) {
+                Ok(true) => (),
+                Ok(false) => {
+                    writeln!(output, "No earlier history").map_err(io_error)?;
+                    break;
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+
+        self.show_next(cpu, output).map_err(io_error)
+    }
+
+    // Run one instruction, remembering the state at the start of every frame
+    // and checking the watchpoints afterwards.
+    fn execute(
+        &mut self,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+    ) -> Result<Option<Stop>, String> {
+        self.rewind.record(cpu, scheduler);
+
+        let pc = cpu.pc;
+        let before: Vec<u16> = self
+            .watches
+            .iter()
+            .map(|watch| match watch {
+                Watch::Change(register) | Watch::Condition(register, ..) => {
+                    register_value(cpu, *register)
+                }
+                Watch::Memory { .. } => 0,
+            })
+            .collect();
+
+        let watching_memory = self
+            .watches
+            .iter()
+            .any(|watch| matches!(watch, Watch::Memory { .. }));
+        if watching_memory {
+            cpu.memory_accesses = Some(Vec::new());
+        }
+
+        let result = scheduler.step(cpu);
+        let accesses = cpu.memory_accesses.take().unwrap_or_default();
+
+        match result {
+            Ok(true) => return Ok(Some(Stop::Finished)),
+            Ok(false) => (),
+            Err(e) => return Err(e.to_string()),
+        }
+
+        for (index, (watch, old)) in self.watches.iter().zip(before).enumerate() {
+            let detail = match watch {
+                Watch::Memory { access, start, end } => accesses
+                    .iter()
+                    .filter(|made| access.is_none_or(|access| made.access == access))
+                    .find(|made| {
+                        made.start <= *end
+                            && (made.start as usize + made.length as usize) > *start as usize
+                    })
+                    .map(|made| {
+                        let kind = match made.access {
+                            Access::Read => "Read",
+                            Access::Write => "Wrote",
+                        };
+
+                        format!(
+                            "{} {:#06X}-{:#06X}",
+                            kind,
+                            made.start,
+                            made.start as usize + made.length as usize - 1
+                        )
+                    }),
+                Watch::Change(register) => {
+                    let new = register_value(cpu, *register);
+
+                    (new != old).then(|| format!("{}: {:#04X} -> {:#04X}", register, old, new))
+                }
+                Watch::Condition(register, comparison, value) => {
+                    let new = register_value(cpu, *register);
+
+                    (comparison.holds(new, *value) && !comparison.holds(old, *value))
+                        .then(|| format!("{} is {:#04X}", register, new))
+                }
+            };
+
+            if let Some(detail) = detail {
+                return Ok(Some(Stop::Watch { index, pc, detail }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn check_breakpoints(&self, cpu: &CPU) -> Option<Stop> {
+        if self.breakpoints.contains(&cpu.pc) {
+            return Some(Stop::Breakpoint(cpu.pc));
+        }
+
+        if self.category_breakpoints.is_empty() {
+            return None;
+        }
+
+        let category = cpu
+            .read_word(cpu.pc)
+            .and_then(instruction::lookup)
+            .ok()?
+            .category();
+
+        self.category_breakpoints
+            .get(category)
+            .map(|category| Stop::Category(category))
+    }
+
+    fn report<W: Write>(&self, stop: Stop, cpu: &CPU, output: &mut W) -> Result<(), String> {
+        match stop {
+            Stop::Breakpoint(address) => writeln!(output, "Breakpoint at {:#06X}", address),
+            Stop::Category(category) => writeln!(output, "Breakpoint on {}", category),
+            Stop::Watch { index, pc, detail } => writeln!(
+                output,
+                "Watchpoint {} ({}) hit by {:#06X}: {}",
+                index + 1,
+                self.watches[index],
+                pc,
+                detail
+            ),
+            Stop::Finished => writeln!(output, "Program finished"),
+            Stop::Limit(limit) => writeln!(
+                output,
+                "Still running at {:#06X} after {} instructions",
+                cpu.pc, limit
+            ),
+        }
+        .map_err(io_error)?;
+
+        self.show_next(cpu, output).map_err(io_error)
+    }
+
+    fn add_breakpoint<W: Write>(&mut self, target: &str, output: &mut W) -> Result<(), String> {
+        if let Some(category) = find_category(target) {
+            self.category_breakpoints.insert(category);
+            return writeln!(output, "Breakpoint on {}", category).map_err(io_error);
+        }
+
+        let address = parse_hex(target)?;
+        self.breakpoints.insert(address);
+
+        writeln!(output, "Breakpoint at {:#06X}", address).map_err(io_error)
+    }
+
+    fn delete_breakpoint(&mut self, target: &str) -> Result<(), String> {
+        if target == "all" {
+            self.breakpoints.clear();
+            self.category_breakpoints.clear();
+            return Ok(());
+        }
+
+        let removed = match find_category(target) {
+            Some(category) => self.category_breakpoints.remove(category),
+            None => self.breakpoints.remove(&parse_hex(target)?),
+        };
+
+        if removed {
+            Ok(())
+        } else {
+            Err(format!("No breakpoint on {}", target))
+        }
+    }
+
+    fn list_breakpoints<W: Write>(&self, output: &mut W) -> Result<(), String> {
+        if self.breakpoints.is_empty() && self.category_breakpoints.is_empty() {
+            return writeln!(output, "No breakpoints").map_err(io_error);
+        }
+
+        for address in &self.breakpoints {
+            writeln!(output, "  {:#06X}", address).map_err(io_error)?;
+        }
+
+        for category in &self.category_breakpoints {
+            writeln!(output, "  {}", category).map_err(io_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_watch<W: Write>(&mut self, arguments: &[&str], output: &mut W) -> Result<(), String> {
+        let watch = match arguments {
+            [kind, range] if ["read", "write", "access"].contains(kind) => {
+                let (start, end) = match range.find('-') {
+                    Some(position) => (
+                        parse_hex(&range[..position])?,
+                        parse_hex(&range[position + 1..])?,
+                    ),
+                    None => (parse_hex(range)?, parse_hex(range)?),
+                };
+
+                if end < start {
+                    return Err(format!("Empty range: {}", range));
+                }
+
+                let access = match *kind {
+                    "read" => Some(Access::Read),
+                    "write" => Some(Access::Write),
+                    _ => None,
+                };
+
+                Watch::Memory { access, start, end }
+            }
+            [register] => Watch::Change(parse_register(register)?),
+            [register, comparison, value] => {
+                let comparison = Comparison::ALL
+                    .iter()
+                    .find(|(symbol, _)| symbol == comparison)
+                    .map(|(_, comparison)| *comparison)
+                    .ok_or_else(|| format!("Unknown comparison: {}", comparison))?;
+
+                Watch::Condition(parse_register(register)?, comparison, parse_hex(value)?)
+            }
+            _ => return Err(String::from("Expected a memory range or a register")),
+        };
+
+        writeln!(output, "Watchpoint {}: {}", self.watches.len() + 1, watch).map_err(io_error)?;
+        self.watches.push(watch);
+
+        Ok(())
+    }
+
+    fn delete_watch(&mut self, arguments: &[&str]) -> Result<(), String> {
+        match arguments {
+            ["all"] => self.watches.clear(),
+            [number] => match number.parse::<usize>() {
+                Ok(number) if number >= 1 && number <= self.watches.len() => {
+                    self.watches.remove(number - 1);
+                }
+                _ => return Err(format!("No watchpoint {}", number)),
+            },
+            _ => return Err(String::from("Expected a watchpoint number or all")),
+        }
+
+        Ok(())
+    }
+
+    fn list_watches<W: Write>(&self, output: &mut W) -> Result<(), String> {
+        if self.watches.is_empty() {
+            return writeln!(output, "No watchpoints").map_err(io_error);
+        }
+
+        for (index, watch) in self.watches.iter().enumerate() {
+            writeln!(output, "  {}: {}", index + 1, watch).map_err(io_error)?;
+        }
+
+        Ok(())
+    }
+
+    // Print the instruction about to be executed.
+    fn show_next<W: Write>(&self, cpu: &CPU, output: &mut W) -> io::Result<()> {
+        match cpu.read_word(cpu.pc) {
+            Ok(opcode) => match instruction::lookup(opcode) {
+                Ok(instruction) => writeln!(
+                    output,
+                    "{:#06X}  {:04X}  {:<20} {:?}",
+                    cpu.pc,
+                    opcode,
+                    instruction.category(),
+                    instruction
+                ),
+                Err(_) => writeln!(output, "{:#06X}  {:04X}  (unknown)", cpu.pc, opcode),
+            },
+            Err(_) => writeln!(output, "{:#06X}  (out of memory)", cpu.pc),
+        }
+    }
+}
+
+fn show_registers<W: Write>(cpu: &CPU, output: &mut W) -> io::Result<()> {
+    for (row, values) in cpu.v.chunks(8).enumerate() {
+        for (x, value) in values.iter().enumerate() {
+            write!(output, "V{:X}={:02X}  ", row * 8 + x, value)?;
+        }
+
+        writeln!(output)?;
+    }
+
+    writeln!(
+        output,
+        "I={:04X}  PC={:04X}  SP={:X}  DT={:02X}  ST={:02X}",
+        cpu.i, cpu.pc, cpu.sp, cpu.delay_timer, cpu.sound_timer
+    )
+}
+
+fn show_memory<W: Write>(cpu: &CPU, arguments: &[&str], output: &mut W) -> Result<(), String> {
+    let start = parse_hex(arguments.first().ok_or("Expected an address")?)? as usize;
+    let length = match arguments.get(1) {
+        Some(_) => count(arguments.get(1))? as usize,
+        None => 64,
+    };
+    let end = start.saturating_add(length).min(cpu.machine.memory_size());
+
+    for line_start in (start..end).step_by(16) {
+        let bytes = &cpu.memory[line_start..(line_start + 16).min(end)];
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+
+        writeln!(output, "{:#06X}  {}", line_start, hex.join(" ")).map_err(io_error)?;
+    }
+
+    Ok(())
+}
+
+fn set_register(cpu: &mut CPU, arguments: &[&str]) -> Result<(), String> {
+    let (name, value) = match arguments {
+        [name, value] => (name, parse_hex(value)?),
+        _ => return Err(String::from("Expected a register and a value")),
+    };
+
+    // The program counter is not something watchpoints can refer to
+    if name.eq_ignore_ascii_case("PC") {
+        cpu.pc = value;
+        return Ok(());
+    }
+
+    match parse_register(name)? {
+        Register::V(x) if value <= 0xFF => cpu.v[x as usize] = value as u8,
+        Register::I => cpu.i = value,
+        Register::Sp if (value as usize) <= cpu.stack.len() => cpu.sp = value,
+        Register::DelayTimer => cpu.delay_timer = value,
+        Register::SoundTimer => cpu.sound_timer = value,
+        register => return Err(format!("Cannot set {} to {:X}", register, value)),
+    }
+
+    Ok(())
+}
+
+fn parse_register(name: &str) -> Result<Register, String> {
+    let name = name.to_ascii_uppercase();
+
+    match name.as_str() {
+        "I" => Ok(Register::I),
+        "SP" => Ok(Register::Sp),
+        "DT" => Ok(Register::DelayTimer),
+        "ST" => Ok(Register::SoundTimer),
+        _ => match name.strip_prefix('V').map(|x| u8::from_str_radix(x, 16)) {
+            Some(Ok(x)) if x < 16 => Ok(Register::V(x)),
+            _ => Err(format!("Unknown register: {}", name)),
+        },
+    }
+}
+
+fn register_value(cpu: &CPU, register: Register) -> u16 {
+    match register {
+        Register::V(x) => cpu.v[x as usize] as u16,
+        Register::I => cpu.i,
+        Register::Sp => cpu.sp,
+        Register::DelayTimer => cpu.delay_timer,
+        Register::SoundTimer => cpu.sound_timer,
+    }
+}
+
+// Start or stop recording frames. Starting a new recording finishes the one
+// before it.
+fn record<W: Write>(
+    cpu: &CPU,
+    scheduler: &mut Scheduler,
+    arguments: &[&str],
+    output: &mut W,
+) -> Result<(), String> {
+    let filename = match arguments {
+        [filename] => filename,
+        _ => return Err(String::from("Expected a file name or stop")),
+    };
+
+    let recording = scheduler.frame_hook.is_some();
+    scheduler
+        .finish_frame_hook()
+        .map_err(|e| format!("Could not save recording: {}", e))?;
+
+    if *filename == "stop" {
+        if !recording {
+            return Err(String::from("Not recording"));
+        }
+        return writeln!(output, "Recording saved").map_err(io_error);
+    }
+
+    let palette = Palette::for_machine(cpu.machine);
+    let recorder = GifRecorder::create(filename, cpu.machine, DEFAULT_SCALE, palette)
+        .map_err(|e| format!("Could not start recording: {}", e))?;
+    scheduler.frame_hook = Some(Box::new(recorder));
+
+    writeln!(output, "Recording to {}", filename).map_err(io_error)
+}
+
+fn poke(cpu: &mut CPU, arguments: &[&str]) -> Result<(), String> {
+    let start = parse_hex(arguments.first().ok_or("Expected an address")?)? as usize;
+
+    for (offset, byte) in arguments[1..].iter().enumerate() {
+        let address = start + offset;
+
+        if address >= cpu.machine.memory_size() {
+            return Err(format!("Address {:#06X} is out of bounds", address));
+        }
+
+        cpu.memory[address] = u8::from_str_radix(byte, 16)
+            .map_err(|_| format!("Expected a hex byte, got: {}", byte))?;
+    }
+
+    Ok(())
+}
+
+// Each subroutine call pushed its return address; the call was made from the
+// instruction before it.
+fn show_backtrace<W: Write>(cpu: &CPU, output: &mut W) -> io::Result<()> {
+    writeln!(output, "#0  {:#06X}", cpu.pc)?;
+
+    for (depth, frame) in (0..cpu.sp as usize).rev().enumerate() {
+        let return_address = cpu.stack[frame];
+
+        writeln!(
+            output,
+            "#{}  {:#06X}  called from {:#06X}",
+            depth + 1,
+            return_address,
+            return_address.wrapping_sub(2)
+        )?;
+    }
+
+    Ok(())
+}
+
+fn find_category(name: &str) -> Option<&'static str> {
+    CATEGORIES
+        .iter()
+        .find(|category| category.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+fn count(argument: Option<&&str>) -> Result<u64, String> {
+    match argument {
+        Some(text) => text
+            .parse()
+            .map_err(|_| format!("Expected a count, got: {}", text)),
+        None => Ok(1),
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Expected a hex number, got: {}", text))
+}
+
+fn io_error(e: io::Error) -> String {
+    format!("I/O error: {}", e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD V0, 5; ADD V0, 1; CLS; JP 0x202
+    const PROGRAM: [u8; 8] = [0x60, 0x05, 0x70, 0x01, 0x00, 0xE0, 0x12, 0x02];
+
+    struct Session {
+        debugger: Debugger,
+        cpu: CPU,
+        scheduler: Scheduler,
+    }
+
+    impl Session {
+        fn new() -> Session {
+            Session::with(&PROGRAM)
+        }
+
+        fn with(program: &[u8]) -> Session {
+            let mut cpu = CPU::default();
+            cpu.load_program_bytes(program).unwrap();
+
+            Session {
+                debugger: Debugger::default(),
+                cpu,
+                scheduler: Scheduler::new(600),
+            }
+        }
+
+        // Run a command and return what it printed
+        fn run(&mut self, line: &str) -> String {
+            let mut output = Vec::new();
+            self.debugger
+                .command(line, &mut self.cpu, &mut self.scheduler, &mut output)
+                .unwrap();
+
+            String::from_utf8(output).unwrap()
+        }
+    }
+
+    #[test]
+    fn steps_a_number_of_instructions() {
+        let mut session = Session::new();
+
+        assert!(session.run("step 2").starts_with("0x0204  00E0"));
+        assert_eq!((session.cpu.v[0], session.scheduler.cycles), (6, 2));
+        assert_eq!(session.run("s x"), "Expected a count, got: x\n");
+    }
+
+    #[test]
+    fn stops_steps_at_breakpoints() {
+        let mut session = Session::new();
+        session.run("break 204");
+
+        assert!(session
+            .run("step 10")
+            .starts_with("Breakpoint at 0x0204\n0x0204"));
+        assert_eq!(session.cpu.pc, 0x204);
+
+        // Stepping from a breakpoint runs the instruction under it
+        session.run("delete 204");
+        session.run("break display");
+        assert!(session
+            .run("step 10")
+            .starts_with("Breakpoint on Display\n0x0204"));
+        assert_eq!(session.scheduler.cycles, 5);
+    }
+
+    #[test]
+    fn continues_to_breakpoints() {
+        let mut session = Session::new();
+        session.run("b 206");
+
+        assert!(session.run("c").starts_with("Breakpoint at 0x0206"));
+        assert_eq!(session.run("bl"), "  0x0206\n");
+        assert_eq!(session.run("d all"), "");
+        assert_eq!(session.run("bl"), "No breakpoints\n");
+    }
+
+    #[test]
+    fn gives_control_back_from_endless_loops() {
+        let mut session = Session::new();
+
+        assert!(session
+            .run("c 50")
+            .starts_with("Still running at 0x0204 after 50 instructions\n0x0204"));
+        assert_eq!(session.scheduler.cycles, 50);
+
+        session.debugger.continue_limit = 1000;
+        assert!(session
+            .run("continue")
+            .starts_with("Still running at 0x0206 after 1000 instructions"));
+        assert_eq!(session.scheduler.cycles, 1050);
+        assert_eq!(session.run("c -1"), "Expected a count, got: -1\n");
+    }
+
+    #[test]
+    fn shows_memory_with_a_decimal_length() {
+        let mut session = Session::new();
+
+        assert_eq!(
+            session.run("x 200 10"),
+            "0x0200  60 05 70 01 00 E0 12 02 00 00\n"
+        );
+        assert_eq!(session.run("x FFE 16"), "0x0FFE  00 00\n");
+        assert_eq!(session.run("x 200 1A"), "Expected a count, got: 1A\n");
+    }
+
+    #[test]
+    fn goes_back_over_instructions() {
+        let mut session = Session::new();
+        session.run("step 3");
+
+        assert!(session.run("back 2").starts_with("0x0202  7001"));
+        assert_eq!((session.cpu.v[0], session.scheduler.cycles), (5, 1));
+        assert!(session
+            .run("back 5")
+            .starts_with("No earlier history\n0x0200"));
+    }
+
+    #[test]
+    fn edits_registers_and_memory() {
+        let mut session = Session::new();
+        session.run("set V3 2A");
+        session.run("set pc 0x204");
+        session.run("poke 300 AB CD");
+
+        assert_eq!((session.cpu.v[3], session.cpu.pc), (0x2A, 0x204));
+        assert_eq!(session.cpu.memory[0x300..0x302], [0xAB, 0xCD]);
+        assert_eq!(session.run("set V3 100"), "Cannot set V3 to 100\n");
+        assert_eq!(
+            session.run("poke FFF 1 2"),
+            "Address 0x1000 is out of bounds\n"
+        );
+    }
+
+    // LD I, 0x300; LD V0, 7; LD [I], V0; LD V1, [I]; JP 0x206
+    const MEMORY_PROGRAM: [u8; 10] = [0xA3, 0x00, 0x60, 0x07, 0xF0, 0x55, 0xF1, 0x65, 0x12, 0x06];
+
+    #[test]
+    fn watches_memory_accesses() {
+        let mut session = Session::with(&MEMORY_PROGRAM);
+
+        assert_eq!(
+            session.run("watch write 300"),
+            "Watchpoint 1: write 0x0300-0x0300\n"
+        );
+        assert!(session.run("c").starts_with(
+            "Watchpoint 1 (write 0x0300-0x0300) hit by 0x0204: Wrote 0x0300-0x0300\n"
+        ));
+        assert_eq!(session.cpu.memory[0x300], 7);
+
+        session.run("unwatch 1");
+        session.run("w read 301-3FF");
+        // The COSMAC VIP's FX55 left I past the byte it stored
+        assert!(session
+            .run("c")
+            .starts_with("Watchpoint 1 (read 0x0301-0x03FF) hit by 0x0206: Read 0x0301-0x0302\n"));
+        assert_eq!(session.run("w access 302-301"), "Empty range: 302-301\n");
+    }
+
+    #[test]
+    fn ignores_draws_of_no_rows() {
+        // LD I, 0x305; DRW V0, V0, 0; JP 0x204
+        let mut session = Session::with(&[0xA3, 0x05, 0xD0, 0x00, 0x12, 0x04]);
+        session.run("w access 300-310");
+
+        assert!(session
+            .run("c 10")
+            .starts_with("Still running at 0x0204 after 10 instructions"));
+    }
+
+    #[test]
+    fn watches_register_changes_and_conditions() {
+        let mut session = Session::new();
+        session.run("w V0");
+        session.run("w V0 >= 8");
+
+        assert!(session
+            .run("c")
+            .starts_with("Watchpoint 1 (V0 changes) hit by 0x0200: V0: 0x00 -> 0x05\n"));
+
+        session.run("unwatch 1");
+        assert!(session
+            .run("c")
+            .starts_with("Watchpoint 1 (V0 >= 0x08) hit by 0x0202: V0 is 0x08\n"));
+
+        // Conditions only trigger again once they stop holding
+        session.run("set V0 0");
+        assert!(session.run("c").starts_with("Watchpoint 1 (V0 >= 0x08)"));
+        assert_eq!(session.cpu.v[0], 8);
+    }
+
+    #[test]
+    fn lists_and_removes_watchpoints() {
+        let mut session = Session::new();
+        session.run("w I");
+        session.run("w DT != 0");
+
+        assert_eq!(session.run("wl"), "  1: I changes\n  2: DT != 0x00\n");
+        assert_eq!(session.run("unwatch 3"), "No watchpoint 3\n");
+        assert_eq!(session.run("w V0 ~ 1"), "Unknown comparison: ~\n");
+        assert_eq!(session.run("w VG"), "Unknown register: VG\n");
+        assert_eq!(session.run("unwatch all"), "");
+        assert_eq!(session.run("wl"), "No watchpoints\n");
+    }
+}
@@ -0,0 +1,546 @@
+use crate::cpu::instruction::{self, Instruction};
+use crate::cpu::machine::Machine;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Number of data bytes written per line
+const BYTES_PER_LINE: usize = 8;
+
+// Assembly language conventions to print instructions in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Syntax {
+    // The mnemonics from Cowgod's Chip-8 Technical Reference, e.g.
+    // `LD V3, 0x1F` and `DRW V0, V1, 5`
+    #[default]
+    Cowgod,
+    // The Octo language, e.g. `v3 := 0x1F` and `sprite v0 v1 5`
+    Octo,
+}
+
+impl Syntax {
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name.to_ascii_lowercase().as_str() {
+            "cowgod" => Some(Syntax::Cowgod),
+            "octo" => Some(Syntax::Octo),
+            _ => None,
+        }
+    }
+}
+
+// A decoded instruction found by following the program's control flow.
+struct Code {
+    instruction: Instruction,
+    // The address loaded by XO-CHIP's four byte F000 NNNN
+    long_address: u16,
+    length: usize,
+}
+
+// Turns ROM images back into assembly source. Only bytes reachable from the
+// entry point by following jumps, calls and skips are decoded as instructions;
+// everything else is written out as data.
+#[derive(Clone, Debug)]
+pub struct Disassembler {
+    pub syntax: Syntax,
+    // Name the targets of jumps, calls and index loads instead of writing
+    // their addresses
+    pub labels: bool,
+    // Start every line with the address and raw bytes it came from. Without
+    // this the output is plain source for the assembler.
+    pub listing: bool,
+    pub machine: Machine,
+    pub load_address: u16,
+}
+
+impl Default for Disassembler {
+    fn default() -> Disassembler {
+        Disassembler {
+            syntax: Syntax::default(),
+            labels: false,
+            listing: true,
+            machine: Machine::default(),
+            load_address: 0x200,
+        }
+    }
+}
+
+impl Disassembler {
+    pub fn disassemble(&self, rom: &[u8]) -> String {
+        let code = self.trace(rom);
+        let labels = if self.labels {
+            self.find_labels(rom, &code)
+        } else {
+            BTreeMap::new()
+        };
+
+        let name = |address: u16, digits: usize| match labels.get(&address) {
+            Some(label) => label.clone(),
+            None => format!("0x{:0width$X}", address, width = digits),
+        };
+
+        let mut output = String::new();
+        let mut offset = 0;
+
+        while offset < rom.len() {
+            let address = self.load_address as usize + offset;
+
+            if let Some(label) = labels.get(&(address as u16)) {
+                match self.syntax {
+                    Syntax::Cowgod => output.push_str(&format!("{}:\n", label)),
+                    Syntax::Octo => output.push_str(&format!(": {}\n", label)),
+                }
+            }
+
+            let (length, text) = match code.get(&(address as u16)) {
+                Some(code) => {
+                    let text = mnemonic(code.instruction, code.long_address, self.syntax, &name);
+                    let opcode = code.instruction.opcode().to_be_bytes();
+
+                    // Opcodes decoded leniently, like 9XY1, would assemble
+                    // back to a different opcode, so source keeps their bytes
+                    if self.listing || rom[offset..offset + 2] == opcode {
+                        (code.length, text)
+                    } else {
+                        let comment = match self.syntax {
+                            Syntax::Cowgod => ';',
+                            Syntax::Octo => '#',
+                        };
+                        let data = self.data(&rom[offset..offset + 2]);
+
+                        (2, format!("{} {} {}", data, comment, text))
+                    }
+                }
+                None => {
+                    // Data runs up to the next instruction or label
+                    let mut length = 1;
+
+                    while length < BYTES_PER_LINE
+                        && offset + length < rom.len()
+                        && !code.contains_key(&((address + length) as u16))
+                        && !labels.contains_key(&((address + length) as u16))
+                    {
+                        length += 1;
+                    }
+
+                    (length, self.data(&rom[offset..offset + length]))
+                }
+            };
+
+            if self.listing {
+                let bytes: Vec<String> = rom[offset..offset + length]
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect();
+
+                output.push_str(&format!(
+                    "{:#06X}  {:<16}  {}\n",
+                    address,
+                    bytes.join(""),
+                    text
+                ));
+            } else {
+                output.push_str(&format!("    {}\n", text));
+            }
+
+            offset += length;
+        }
+
+        output
+    }
+
+    // Follow control flow from the entry point, decoding every instruction
+    // that can be reached.
+    fn trace(&self, rom: &[u8]) -> BTreeMap<u16, Code> {
+        let mut code = BTreeMap::new();
+        let mut pending = vec![self.load_address];
+
+        while let Some(address) = pending.pop() {
+            if code.contains_key(&address) {
+                continue;
+            }
+
+            let decoded = match self.decode(rom, address) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+
+            let next = address.wrapping_add(decoded.length as u16);
+
+            match decoded.instruction {
+                Instruction::Jump { nnn } => pending.push(nnn),
+                Instruction::Call { nnn } => {
+                    pending.push(nnn);
+                    pending.push(next);
+                }
+                // The target of BNNN depends on a register, and return
+                // addresses were already followed from their calls
+                Instruction::Return | Instruction::Exit | Instruction::JumpOffset { .. } => (),
+                Instruction::SkipIfEqual { .. }
+                | Instruction::SkipIfNotEqual { .. }
+                | Instruction::SkipIfRegistersEqual { .. }
+                | Instruction::SkipIfRegistersNotEqual { .. }
+                | Instruction::SkipIfKeyPressed { .. }
+                | Instruction::SkipIfKeyNotPressed { .. } => {
+                    pending.push(next);
+
+                    // Skipping over F000 NNNN skips all four bytes
+                    let skipped = match self.decode(rom, next) {
+                        Some(following) => following.length as u16,
+                        None => 2,
+                    };
+                    pending.push(next.wrapping_add(skipped));
+                }
+                _ => pending.push(next),
+            }
+
+            code.insert(address, decoded);
+        }
+
+        // Drop instructions overlapping the one before them, which happens
+        // when a jump lands in the middle of an instruction. The earlier
+        // instruction is kept.
+        let mut covered_until = 0;
+        code.retain(|&address, decoded| {
+            let keep = address as usize >= covered_until;
+            if keep {
+                covered_until = address as usize + decoded.length;
+            }
+            keep
+        });
+
+        code
+    }
+
+    fn decode(&self, rom: &[u8], address: u16) -> Option<Code> {
+        let offset = (address as usize).checked_sub(self.load_address as usize)?;
+        let word = |offset: usize| -> Option<u16> {
+            Some((*rom.get(offset)? as u16) << 8 | *rom.get(offset + 1)? as u16)
+        };
+
+        let opcode = word(offset)?;
+        let instruction = instruction::lookup(opcode).ok()?;
+
+        if instruction.machine() > self.machine {
+            return None;
+        }
+
+        let (long_address, length) = match instruction {
+            Instruction::LongLoad => (word(offset + 2)?, 4),
+            _ => (0, 2),
+        };
+
+        Some(Code {
+            instruction,
+            long_address,
+            length,
+        })
+    }
+
+    // Name the addresses referred to by instructions, where they fall on the
+    // start of an instruction or within data in the ROM.
+    fn find_labels(&self, rom: &[u8], code: &BTreeMap<u16, Code>) -> BTreeMap<u16, String> {
+        let start = self.load_address as usize;
+        let end = start + rom.len();
+
+        // Covered by an instruction but not at its start
+        let inside_code: BTreeSet<usize> = code
+            .iter()
+            .flat_map(|(&address, decoded)| address as usize + 1..address as usize + decoded.length)
+            .collect();
+
+        // Subroutines are named as such even where they are also jumped to,
+        // and jump targets even where they are also loaded into I
+        let mut kinds: BTreeMap<u16, usize> = BTreeMap::new();
+
+        for decoded in code.values() {
+            let (target, kind) = match decoded.instruction {
+                Instruction::Call { nnn } => (nnn, 0),
+                Instruction::Jump { nnn } | Instruction::JumpOffset { nnn } => (nnn, 1),
+                Instruction::SetIndex { nnn } => (nnn, 2),
+                Instruction::LongLoad => (decoded.long_address, 2),
+                _ => continue,
+            };
+
+            let address = target as usize;
+            if address < start || address >= end || inside_code.contains(&address) {
+                continue;
+            }
+
+            let existing = kinds.entry(target).or_insert(kind);
+            *existing = (*existing).min(kind);
+        }
+
+        kinds
+            .into_iter()
+            .map(|(address, kind)| {
+                let prefix = ["sub", "loc", "data"][kind];
+                (address, format!("{}_{:03X}", prefix, address))
+            })
+            .collect()
+    }
+
+    fn data(&self, bytes: &[u8]) -> String {
+        let values: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+
+        match self.syntax {
+            Syntax::Cowgod => format!("db {}", values.join(", ")),
+            Syntax::Octo => values.join(" "),
+        }
+    }
+}
+
+// Write out a single instruction. `name` formats an address with the given
+// number of hex digits, or replaces it with a label.
+pub fn mnemonic(
+    instruction: Instruction,
+    long_address: u16,
+    syntax: Syntax,
+    name: &dyn Fn(u16, usize) -> String,
+) -> String {
+    match syntax {
+        Syntax::Cowgod => cowgod(instruction, long_address, name),
+        Syntax::Octo => octo(instruction, long_address, name),
+    }
+}
+
+fn cowgod(
+    instruction: Instruction,
+    long_address: u16,
+    name: &dyn Fn(u16, usize) -> String,
+) -> String {
+    use Instruction::*;
+
+    match instruction {
+        Noop => String::from("NOP"),
+        ClearScreen => String::from("CLS"),
+        Return => String::from("RET"),
+        Jump { nnn } => format!("JP {}", name(nnn, 3)),
+        Call { nnn } => format!("CALL {}", name(nnn, 3)),
+        SkipIfEqual { x, nn } => format!("SE V{:X}, 0x{:02X}", x, nn),
+        SkipIfNotEqual { x, nn } => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        SkipIfRegistersEqual { x, y } => format!("SE V{:X}, V{:X}", x, y),
+        SetConstant { x, nn } => format!("LD V{:X}, 0x{:02X}", x, nn),
+        AddConstant { x, nn } => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        Assign { x, y } => format!("LD V{:X}, V{:X}", x, y),
+        Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+        And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+        Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+        Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+        Subtract { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+        ShiftRight { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+        SubtractReversed { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+        ShiftLeft { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+        SkipIfRegistersNotEqual { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+        SetIndex { nnn } => format!("LD I, {}", name(nnn, 3)),
+        JumpOffset { nnn } => format!("JP V0, {}", name(nnn, 3)),
+        Random { x, nn } => format!("RND V{:X}, 0x{:02X}", x, nn),
+        Draw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        SkipIfKeyPressed { x } => format!("SKP V{:X}", x),
+        SkipIfKeyNotPressed { x } => format!("SKNP V{:X}", x),
+        GetDelayTimer { x } => format!("LD V{:X}, DT", x),
+        AwaitKey { x } => format!("LD V{:X}, K", x),
+        SetDelayTimer { x } => format!("LD DT, V{:X}", x),
+        SetSoundTimer { x } => format!("LD ST, V{:X}", x),
+        AddIndex { x } => format!("ADD I, V{:X}", x),
+        FontCharacter { x } => format!("LD F, V{:X}", x),
+        BinaryCodedDecimal { x } => format!("LD B, V{:X}", x),
+        StoreRegisters { x } => format!("LD [I], V{:X}", x),
+        LoadRegisters { x } => format!("LD V{:X}, [I]", x),
+        ScrollDown { n } => format!("SCD {}", n),
+        ScrollRight => String::from("SCR"),
+        ScrollLeft => String::from("SCL"),
+        Exit => String::from("EXIT"),
+        LowResolution => String::from("LOW"),
+        HighResolution => String::from("HIGH"),
+        LargeFontCharacter { x } => format!("LD HF, V{:X}", x),
+        StoreFlags { x } => format!("LD R, V{:X}", x),
+        LoadFlags { x } => format!("LD V{:X}, R", x),
+        ScrollUp { n } => format!("SCU {}", n),
+        StoreRange { x, y } => format!("LD [I], V{:X}-V{:X}", x, y),
+        LoadRange { x, y } => format!("LD V{:X}-V{:X}, [I]", x, y),
+        LongLoad => format!("LD I, LONG {}", name(long_address, 4)),
+        SelectPlanes { n } => format!("PLANE {}", n),
+        LoadAudio => String::from("AUDIO"),
+        SetPitch { x } => format!("PITCH V{:X}", x),
+    }
+}
+
+// Octo writes skips as conditions under which the next instruction runs, so
+// each skip reads as its opposite.
+fn octo(
+    instruction: Instruction,
+    long_address: u16,
+    name: &dyn Fn(u16, usize) -> String,
+) -> String {
+    use Instruction::*;
+
+    match instruction {
+        Noop => String::from("0x00 0x00"),
+        ClearScreen => String::from("clear"),
+        Return => String::from("return"),
+        Jump { nnn } => format!("jump {}", name(nnn, 3)),
+        Call { nnn } => {
+            let target = name(nnn, 3);
+
+            if target.starts_with("0x") {
+                format!(":call {}", target)
+            } else {
+                target
+            }
+        }
+        SkipIfEqual { x, nn } => format!("if v{:x} != 0x{:02X} then", x, nn),
+        SkipIfNotEqual { x, nn } => format!("if v{:x} == 0x{:02X} then", x, nn),
+        SkipIfRegistersEqual { x, y } => format!("if v{:x} != v{:x} then", x, y),
+        SetConstant { x, nn } => format!("v{:x} := 0x{:02X}", x, nn),
+        AddConstant { x, nn } => format!("v{:x} += 0x{:02X}", x, nn),
+        Assign { x, y } => format!("v{:x} := v{:x}", x, y),
+        Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+        And { x, y } => format!("v{:x} &= v{:x}", x, y),
+        Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+        Add { x, y } => format!("v{:x} += v{:x}", x, y),
+        Subtract { x, y } => format!("v{:x} -= v{:x}", x, y),
+        ShiftRight { x, y } => format!("v{:x} >>= v{:x}", x, y),
+        SubtractReversed { x, y } => format!("v{:x} =- v{:x}", x, y),
+        ShiftLeft { x, y } => format!("v{:x} <<= v{:x}", x, y),
+        SkipIfRegistersNotEqual { x, y } => format!("if v{:x} == v{:x} then", x, y),
+        SetIndex { nnn } => format!("i := {}", name(nnn, 3)),
+        JumpOffset { nnn } => format!("jump0 {}", name(nnn, 3)),
+        Random { x, nn } => format!("v{:x} := random 0x{:02X}", x, nn),
+        Draw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+        SkipIfKeyPressed { x } => format!("if v{:x} -key then", x),
+        SkipIfKeyNotPressed { x } => format!("if v{:x} key then", x),
+        GetDelayTimer { x } => format!("v{:x} := delay", x),
+        AwaitKey { x } => format!("v{:x} := key", x),
+        SetDelayTimer { x } => format!("delay := v{:x}", x),
+        SetSoundTimer { x } => format!("buzzer := v{:x}", x),
+        AddIndex { x } => format!("i += v{:x}", x),
+        FontCharacter { x } => format!("i := hex v{:x}", x),
+        BinaryCodedDecimal { x } => format!("bcd v{:x}", x),
+        StoreRegisters { x } => format!("save v{:x}", x),
+        LoadRegisters { x } => format!("load v{:x}", x),
+        ScrollDown { n } => format!("scroll-down {}", n),
+        ScrollRight => String::from("scroll-right"),
+        ScrollLeft => String::from("scroll-left"),
+        Exit => String::from("exit"),
+        LowResolution => String::from("lores"),
+        HighResolution => String::from("hires"),
+        LargeFontCharacter { x } => format!("i := bighex v{:x}", x),
+        StoreFlags { x } => format!("saveflags v{:x}", x),
+        LoadFlags { x } => format!("loadflags v{:x}", x),
+        ScrollUp { n } => format!("scroll-up {}", n),
+        StoreRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+        LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+        LongLoad => format!("i := long {}", name(long_address, 4)),
+        SelectPlanes { n } => format!("plane {}", n),
+        LoadAudio => String::from("audio"),
+        SetPitch { x } => format!("pitch := v{:x}", x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CALL 0x206; LD I, 0x20A; JP 0x204; LD V0, 5; RET; then sprite data
+    const ROM: [u8; 12] = [
+        0x22, 0x06, 0xA2, 0x0A, 0x12, 0x04, 0x60, 0x05, 0x00, 0xEE, 0xF0, 0x90,
+    ];
+
+    fn source(syntax: Syntax) -> Disassembler {
+        Disassembler {
+            syntax,
+            labels: true,
+            listing: false,
+            ..Disassembler::default()
+        }
+    }
+
+    #[test]
+    fn lists_addresses_and_bytes() {
+        assert_eq!(
+            Disassembler::default().disassemble(&ROM),
+            "0x0200  2206              CALL 0x206\n\
+             0x0202  A20A              LD I, 0x20A\n\
+             0x0204  1204              JP 0x204\n\
+             0x0206  6005              LD V0, 0x05\n\
+             0x0208  00EE              RET\n\
+             0x020A  F090              db 0xF0, 0x90\n"
+        );
+    }
+
+    #[test]
+    fn names_targets_with_labels() {
+        assert_eq!(
+            source(Syntax::Cowgod).disassemble(&ROM),
+            "    CALL sub_206\n    LD I, data_20A\nloc_204:\n    JP loc_204\n\
+             sub_206:\n    LD V0, 0x05\n    RET\ndata_20A:\n    db 0xF0, 0x90\n"
+        );
+        assert_eq!(
+            source(Syntax::Octo).disassemble(&ROM),
+            "    sub_206\n    i := data_20A\n: loc_204\n    jump loc_204\n\
+             : sub_206\n    v0 := 0x05\n    return\n: data_20A\n    0xF0 0x90\n"
+        );
+    }
+
+    #[test]
+    fn follows_both_sides_of_skips() {
+        // SE V0, 0; JP 0x208; CLS; then data that is never reached
+        let rom = [0x30, 0x00, 0x12, 0x08, 0x00, 0xE0, 0xFF, 0xFF, 0x00, 0xEE];
+
+        assert_eq!(
+            source(Syntax::Cowgod).disassemble(&rom),
+            "    SE V0, 0x00\n    JP loc_208\n    CLS\n    db 0xFF, 0xFF\nloc_208:\n    RET\n"
+        );
+    }
+
+    #[test]
+    fn keeps_bytes_that_would_assemble_differently() {
+        // 9011 decodes as SNE V0, V1 but assembles back to 9010, and HIGH
+        // does not exist before the SUPER-CHIP
+        let rom = [0x90, 0x11, 0x00, 0xFF];
+        let disassembler = source(Syntax::Cowgod);
+
+        assert_eq!(
+            disassembler.disassemble(&rom),
+            "    db 0x90, 0x11 ; SNE V0, V1\n    db 0x00, 0xFF\n"
+        );
+        assert_eq!(
+            Disassembler {
+                machine: Machine::SuperChip,
+                ..disassembler
+            }
+            .disassemble(&rom),
+            "    db 0x90, 0x11 ; SNE V0, V1\n    HIGH\n"
+        );
+    }
+
+    #[test]
+    fn decodes_long_loads_as_four_bytes() {
+        let disassembler = Disassembler {
+            machine: Machine::XoChip,
+            ..source(Syntax::Octo)
+        };
+
+        assert_eq!(
+            disassembler.disassemble(&[0xF0, 0x00, 0x02, 0x04, 0x12, 0x04]),
+            "    i := long loc_204\n: loc_204\n    jump loc_204\n"
+        );
+    }
+
+    #[test]
+    fn starts_instructions_with_their_mnemonic() {
+        let name = |address: u16, _| address.to_string();
+
+        for opcode in 0..=0xFFFF {
+            if let Ok(instruction) = instruction::lookup(opcode) {
+                let text = mnemonic(instruction, 0, Syntax::Cowgod, &name);
+                assert_eq!(text.split(' ').next(), Some(instruction.mnemonic()));
+            }
+        }
+    }
+
+    #[test]
+    fn finds_syntaxes_by_name() {
+        assert_eq!(Syntax::from_name("Octo"), Some(Syntax::Octo));
+        assert_eq!(Syntax::from_name("cowgod"), Some(Syntax::Cowgod));
+        assert_eq!(Syntax::from_name("nasm"), None);
+    }
+}
@@ -0,0 +1,108 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Everything that can go wrong while loading or running a program.
+#[derive(Debug)]
+pub enum EmulatorError {
+    // The opcode does not decode to any known instruction. The program counter
+    // is only known once the opcode has been fetched from memory.
+    UnknownOpcode {
+        opcode: u16,
+        pc: Option<u16>,
+    },
+    // A subroutine call was made with every stack slot in use
+    StackOverflow {
+        pc: u16,
+    },
+    // A return was made with nothing on the stack
+    StackUnderflow {
+        pc: u16,
+    },
+    // An instruction tried to read or write past the end of memory
+    MemoryOutOfBounds {
+        address: usize,
+    },
+    // The program does not fit in the memory available for it
+    RomTooLarge {
+        size: usize,
+        capacity: usize,
+    },
+    // A configuration file could not be parsed
+    InvalidConfig {
+        line: usize,
+        message: String,
+    },
+    // Assembly source could not be assembled
+    InvalidSource {
+        file: String,
+        line: usize,
+        message: String,
+    },
+    // A save state is malformed or from an unsupported version
+    InvalidSaveState(String),
+    // A save state was taken while a different program was loaded
+    RomMismatch {
+        expected: u64,
+        found: u64,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::UnknownOpcode {
+                opcode,
+                pc: Some(pc),
+            } => {
+                write!(f, "Opcode {:0>4X} not found at {:#06X}", opcode, pc)
+            }
+            EmulatorError::UnknownOpcode { opcode, pc: None } => {
+                write!(f, "Opcode {:0>4X} not found", opcode)
+            }
+            EmulatorError::StackOverflow { pc } => write!(f, "Stack overflow at {:#06X}", pc),
+            EmulatorError::StackUnderflow { pc } => write!(f, "Stack underflow at {:#06X}", pc),
+            EmulatorError::MemoryOutOfBounds { address } => {
+                write!(f, "Memory access out of bounds at {:#06X}", address)
+            }
+            EmulatorError::RomTooLarge { size, capacity } => write!(
+                f,
+                "Program is {} bytes but only {} bytes are available",
+                size, capacity
+            ),
+            EmulatorError::InvalidConfig { line, message } => {
+                write!(f, "Invalid configuration on line {}: {}", line, message)
+            }
+            EmulatorError::InvalidSource {
+                file,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", file, line, message),
+            EmulatorError::InvalidSaveState(message) => {
+                write!(f, "Invalid save state: {}", message)
+            }
+            EmulatorError::RomMismatch { expected, found } => write!(
+                f,
+                "Save state is for ROM {:016x}, but ROM {:016x} is loaded",
+                found, expected
+            ),
+            EmulatorError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EmulatorError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EmulatorError {
+    fn from(e: io::Error) -> EmulatorError {
+        EmulatorError::Io(e)
+    }
+}
@@ -0,0 +1,145 @@
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Keeps SUPER-CHIP RPL user flags between runs. On the HP48 the flags
+// survived after a program exited, and some games keep high scores in them.
+// Each program gets its own file, named after the hash of its ROM.
+pub struct FlagStore {
+    directory: PathBuf,
+
+    // The flags as they were loaded, so that a program which never stores
+    // any, or stores the same ones again, leaves its file alone
+    loaded: [u8; 16],
+}
+
+impl FlagStore {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> FlagStore {
+        FlagStore {
+            directory: directory.into(),
+            loaded: [0; 16],
+        }
+    }
+
+    // $XDG_DATA_HOME/chip8/flags, falling back to ~/.local/share/chip8/flags
+    pub fn default_directory() -> Option<PathBuf> {
+        let data_home = match env::var_os("XDG_DATA_HOME") {
+            Some(directory) if !directory.is_empty() => PathBuf::from(directory),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".local/share"),
+        };
+
+        Some(data_home.join("chip8").join("flags"))
+    }
+
+    pub fn path(&self, rom_hash: u64) -> PathBuf {
+        self.directory.join(format!("{:016x}.flags", rom_hash))
+    }
+
+    // Restore the flags saved for the loaded program, if there are any.
+    pub fn load(&mut self, cpu: &mut CPU) -> Result<(), EmulatorError> {
+        let count = cpu.machine.flag_count();
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        let saved = match fs::read(self.path(cpu.rom_hash)) {
+            Ok(saved) => saved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let count = count.min(saved.len());
+        cpu.rpl[..count].copy_from_slice(&saved[..count]);
+        self.loaded[..count].copy_from_slice(&saved[..count]);
+
+        Ok(())
+    }
+
+    // Save the loaded program's flags if FX75 has changed them since they
+    // were loaded. Programs without saved flags start with them all zero.
+    pub fn save(&self, cpu: &CPU) -> Result<(), EmulatorError> {
+        let count = cpu.machine.flag_count();
+
+        if cpu.rpl[..count] == self.loaded[..count] {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.directory)?;
+        fs::write(self.path(cpu.rom_hash), &cpu.rpl[..count])?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::machine::Machine;
+    use std::process;
+
+    fn store(name: &str) -> FlagStore {
+        let directory = env::temp_dir().join(format!("chip8-flags-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        FlagStore::new(directory)
+    }
+
+    fn super_chip() -> CPU {
+        let mut cpu = CPU {
+            machine: Machine::SuperChip,
+            ..CPU::default()
+        };
+        cpu.load_program_bytes(&[0x00, 0xFD]).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn keeps_flags_between_runs() {
+        let mut flags = store("keep");
+        let mut cpu = super_chip();
+        flags.load(&mut cpu).unwrap();
+        cpu.rpl[..3].copy_from_slice(&[7, 8, 9]);
+        flags.save(&cpu).unwrap();
+
+        let mut next = super_chip();
+        let mut flags = FlagStore::new(&flags.directory);
+        flags.load(&mut next).unwrap();
+        assert_eq!(&next.rpl[..4], &[7, 8, 9, 0]);
+
+        // Programs with a different ROM have flags of their own
+        let mut other = CPU {
+            machine: Machine::SuperChip,
+            ..CPU::default()
+        };
+        other.load_program_bytes(&[0x00, 0xFE]).unwrap();
+        flags.load(&mut other).unwrap();
+        assert_eq!(other.rpl, [0; 16]);
+
+        fs::remove_dir_all(&flags.directory).unwrap();
+    }
+
+    #[test]
+    fn only_writes_changed_flags() {
+        let mut flags = store("unchanged");
+        let mut cpu = super_chip();
+        flags.load(&mut cpu).unwrap();
+        flags.save(&cpu).unwrap();
+
+        assert!(!flags.path(cpu.rom_hash).exists());
+    }
+
+    #[test]
+    fn ignores_machines_without_flags() {
+        let mut flags = store("chip8");
+        let mut cpu = CPU::default();
+        cpu.rpl[0] = 1;
+        flags.load(&mut cpu).unwrap();
+        flags.save(&cpu).unwrap();
+
+        assert!(!flags.directory.exists());
+    }
+}
@@ -0,0 +1,665 @@
+use crate::cpu::CPU;
+use crate::scheduler::Scheduler;
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// How often a running program checks for an interrupt from the debugger
+const INTERRUPT_CHECK_INTERVAL: u64 = 1000;
+
+const INTERRUPT: u8 = 0x03;
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Registers are numbered V0 to VF, then I, PC and SP. Values are sent
+// little-endian in register packets.
+const REGISTER_COUNT: usize = 19;
+
+const TARGET_DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+// Why a `continue` or `step` came back
+enum Stop {
+    Signal(u8),
+    // The program ran off the end of memory, exited or hit the cycle limit
+    Exited,
+}
+
+// A GDB Remote Serial Protocol server for a single debugger connection,
+// supporting register and memory access, software breakpoints, single
+// stepping and continuing. Execution is driven through a scheduler so that
+// timers tick as they would in a normal run.
+#[derive(Default)]
+pub struct GdbStub {
+    breakpoints: BTreeSet<u16>,
+    no_ack: bool,
+}
+
+impl GdbStub {
+    // Wait for a debugger to connect on `address`, then serve it until it
+    // detaches, kills the program or disconnects.
+    pub fn listen(
+        &mut self,
+        address: &str,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+    ) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        self.serve(stream, cpu, scheduler)
+    }
+
+    pub fn serve(
+        &mut self,
+        mut stream: TcpStream,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+    ) -> io::Result<()> {
+        while let Some((packet, intact)) = read_packet(&mut stream)? {
+            // Ask for a damaged packet to be sent again
+            if !intact {
+                if !self.no_ack {
+                    stream.write_all(b"-")?;
+                }
+                continue;
+            }
+
+            if !self.no_ack {
+                stream.write_all(b"+")?;
+            }
+
+            let reply = match self.handle(&packet, &mut stream, cpu, scheduler)? {
+                Some(reply) => reply,
+                None => return Ok(()),
+            };
+
+            self.send(&mut stream, &reply)?;
+
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Build the reply to a packet, or `None` when the session is over.
+    fn handle(
+        &mut self,
+        packet: &str,
+        stream: &mut TcpStream,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+    ) -> io::Result<Option<String>> {
+        let command = packet.get(..1).unwrap_or("");
+        let arguments = packet.get(1..).unwrap_or("");
+
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..REGISTER_COUNT)
+                .map(|register| encode_register(cpu, register))
+                .collect(),
+            "G" => self.write_registers(cpu, arguments),
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(register) if register < REGISTER_COUNT => encode_register(cpu, register),
+                _ => error(),
+            },
+            "P" => self.write_register(cpu, arguments),
+            "m" => read_memory(cpu, arguments),
+            "M" => write_memory(cpu, arguments),
+            "Z" | "z" => self.change_breakpoint(command == "Z", arguments),
+            "s" | "c" => {
+                if !arguments.is_empty() {
+                    if let Some(address) = parse_hex(arguments) {
+                        cpu.pc = address as u16;
+                    }
+                }
+
+                let stop = if command == "s" {
+                    self.step(cpu, scheduler, stream)?
+                } else {
+                    self.resume(cpu, scheduler, stream)?
+                };
+
+                match stop {
+                    Stop::Signal(signal) => format!("S{:02x}", signal),
+                    Stop::Exited => String::from("W00"),
+                }
+            }
+            "D" => {
+                self.send(stream, "OK")?;
+                return Ok(None);
+            }
+            "k" => return Ok(None),
+            "H" => String::from("OK"),
+            "q" | "Q" | "v" => self.query(packet),
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return String::from("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+");
+        }
+
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, length)) => {
+                    let document = TARGET_DESCRIPTION.as_bytes();
+                    let start = offset.min(document.len());
+                    let end = match start.checked_add(length) {
+                        Some(end) => end.min(document.len()),
+                        None => return error(),
+                    };
+                    let marker = if end == document.len() { 'l' } else { 'm' };
+
+                    format!(
+                        "{}{}",
+                        marker,
+                        String::from_utf8_lossy(&document[start..end])
+                    )
+                }
+                None => error(),
+            };
+        }
+
+        match packet {
+            "QStartNoAckMode" => String::from("OK"),
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        }
+    }
+
+    fn write_registers(&self, cpu: &mut CPU, hex: &str) -> String {
+        let mut remaining = hex;
+        let mut values = Vec::with_capacity(REGISTER_COUNT);
+
+        // Decode every value before changing any, so that a malformed packet
+        // leaves the registers alone
+        for register in 0..REGISTER_COUNT {
+            let width = register_size(register) * 2;
+
+            if remaining.len() < width {
+                return error();
+            }
+
+            match decode_little_endian(&remaining[..width]) {
+                Some(value) => values.push(value),
+                None => return error(),
+            }
+
+            remaining = &remaining[width..];
+        }
+
+        for (register, value) in values.into_iter().enumerate() {
+            set_register(cpu, register, value);
+        }
+
+        String::from("OK")
+    }
+
+    fn write_register(&self, cpu: &mut CPU, arguments: &str) -> String {
+        let (register, value) = match arguments.find('=') {
+            Some(position) => (&arguments[..position], &arguments[position + 1..]),
+            None => return error(),
+        };
+
+        match (
+            usize::from_str_radix(register, 16),
+            decode_little_endian(value),
+        ) {
+            (Ok(register), Some(value)) if register < REGISTER_COUNT => {
+                set_register(cpu, register, value);
+                String::from("OK")
+            }
+            _ => error(),
+        }
+    }
+
+    // Z0,addr,kind and z0,addr,kind. Only software breakpoints are
+    // supported; an empty reply tells the debugger so for the other kinds.
+    fn change_breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+
+        if fields.next() != Some("0") {
+            return String::new();
+        }
+
+        match fields.next().and_then(parse_hex) {
+            Some(address) => {
+                if insert {
+                    self.breakpoints.insert(address as u16);
+                } else {
+                    self.breakpoints.remove(&(address as u16));
+                }
+
+                String::from("OK")
+            }
+            None => error(),
+        }
+    }
+
+    fn step(
+        &mut self,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+        stream: &mut TcpStream,
+    ) -> io::Result<Stop> {
+        match scheduler.step(cpu) {
+            Ok(true) => Ok(Stop::Exited),
+            Ok(false) => Ok(Stop::Signal(SIGTRAP)),
+            Err(e) => {
+                self.console(stream, &format!("{}\n", e))?;
+                Ok(Stop::Signal(SIGILL))
+            }
+        }
+    }
+
+    fn resume(
+        &mut self,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+        stream: &mut TcpStream,
+    ) -> io::Result<Stop> {
+        let mut executed: u64 = 0;
+
+        loop {
+            // The first instruction is run regardless, or continuing from a
+            // breakpoint would stop again straight away
+            if executed > 0 && self.breakpoints.contains(&cpu.pc) {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+
+            if executed % INTERRUPT_CHECK_INTERVAL == INTERRUPT_CHECK_INTERVAL - 1
+                && interrupted(stream)?
+            {
+                return Ok(Stop::Signal(SIGINT));
+            }
+
+            match self.step(cpu, scheduler, stream)? {
+                Stop::Signal(SIGTRAP) => executed += 1,
+                stop => return Ok(stop),
+            }
+        }
+    }
+
+    // Show a message in the debugger's console.
+    fn console(&self, stream: &mut TcpStream, message: &str) -> io::Result<()> {
+        self.send(stream, &format!("O{}", encode_hex(message.as_bytes())))
+    }
+
+    fn send(&self, stream: &mut TcpStream, data: &str) -> io::Result<()> {
+        stream.write_all(format!("${}#{:02x}", data, checksum(data.as_bytes())).as_bytes())?;
+        stream.flush()
+    }
+}
+
+// Read the next packet, skipping acknowledgements and interrupts sent while
+// the program was already stopped, along with whether its checksum matched.
+// Returns `None` once the debugger hangs up.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<(String, bool)>> {
+    let mut byte = [0];
+
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+
+    let mut data = Vec::new();
+
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+
+        if byte[0] == b'#' {
+            break;
+        }
+
+        data.push(byte[0]);
+    }
+
+    let mut sent = [0; 2];
+    stream.read_exact(&mut sent)?;
+    let intact = std::str::from_utf8(&sent)
+        .ok()
+        .and_then(|sent| u8::from_str_radix(sent, 16).ok())
+        == Some(checksum(&data));
+
+    Ok(Some((String::from_utf8_lossy(&data).into_owned(), intact)))
+}
+
+// The sum of a packet's bytes modulo 256
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Check, without waiting, whether the debugger sent an interrupt.
+fn interrupted(stream: &mut TcpStream) -> io::Result<bool> {
+    let mut byte = [0];
+
+    stream.set_nonblocking(true)?;
+    let result = stream.peek(&mut byte);
+    stream.set_nonblocking(false)?;
+
+    match result {
+        Ok(1) if byte[0] == INTERRUPT => {
+            stream.read_exact(&mut byte)?;
+            Ok(true)
+        }
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn register_size(register: usize) -> usize {
+    if register < 16 {
+        1
+    } else {
+        2
+    }
+}
+
+fn encode_register(cpu: &CPU, register: usize) -> String {
+    let value = match register {
+        0..=15 => cpu.v[register] as u16,
+        16 => cpu.i,
+        17 => cpu.pc,
+        _ => cpu.sp,
+    };
+
+    encode_hex(&value.to_le_bytes()[..register_size(register)])
+}
+
+fn set_register(cpu: &mut CPU, register: usize, value: u64) {
+    match register {
+        0..=15 => cpu.v[register] = value as u8,
+        16 => cpu.i = value as u16,
+        17 => cpu.pc = value as u16,
+        _ => cpu.sp = (value as u16).min(cpu.stack.len() as u16),
+    }
+}
+
+// m addr,length
+fn read_memory(cpu: &CPU, arguments: &str) -> String {
+    match parse_range(arguments).and_then(|range| memory_range(cpu, range)) {
+        Some((start, end)) => encode_hex(&cpu.memory[start..end]),
+        None => error(),
+    }
+}
+
+// M addr,length:data
+fn write_memory(cpu: &mut CPU, arguments: &str) -> String {
+    let (range, data) = match arguments.find(':') {
+        Some(position) => (&arguments[..position], &arguments[position + 1..]),
+        None => return error(),
+    };
+
+    let bytes = match decode_hex(data) {
+        Some(bytes) => bytes,
+        None => return error(),
+    };
+
+    match parse_range(range) {
+        Some((start, length)) if length == bytes.len() => {
+            match memory_range(cpu, (start, length)) {
+                Some((start, end)) => {
+                    cpu.memory[start..end].copy_from_slice(&bytes);
+                    String::from("OK")
+                }
+                None => error(),
+            }
+        }
+        _ => error(),
+    }
+}
+
+// The start and end of `length` bytes of memory from `start`, if they are
+// all inside it.
+fn memory_range(cpu: &CPU, (start, length): (usize, usize)) -> Option<(usize, usize)> {
+    start
+        .checked_add(length)
+        .filter(|end| *end <= cpu.machine.memory_size())
+        .map(|end| (start, end))
+}
+
+fn error() -> String {
+    String::from("E01")
+}
+
+// Parse `start,length` in hex.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let position = text.find(',')?;
+
+    Some((
+        parse_hex(&text[..position])? as usize,
+        parse_hex(&text[position + 1..])? as usize,
+    ))
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_little_endian(text: &str) -> Option<u64> {
+    let bytes = decode_hex(text)?;
+
+    if bytes.len() > 8 {
+        return None;
+    }
+
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u64),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // A minimal Remote Serial Protocol client, as a debugger would connect
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send_raw(&mut self, packet: &str) -> u8 {
+            self.stream.write_all(packet.as_bytes()).unwrap();
+
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            ack[0]
+        }
+
+        // Send a packet and return the reply to it
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            assert_eq!(self.send_raw(&packet), b'+');
+
+            let (reply, intact) = read_packet(&mut self.stream).unwrap().unwrap();
+            assert!(intact);
+            self.stream.write_all(b"+").unwrap();
+
+            reply
+        }
+
+        // Kill the program, which has no reply
+        fn kill(&mut self) {
+            assert_eq!(self.send_raw("$k#6b"), b'+');
+        }
+    }
+
+    // Serve a CPU running `program` to a client running `session` on a
+    // thread of its own, returning the CPU once the client hangs up.
+    fn debug<F>(program: &[u8], session: F) -> CPU
+    where
+        F: FnOnce(&mut Client) + Send + 'static,
+    {
+        let mut cpu = CPU::default();
+        cpu.load_program_bytes(program).unwrap();
+        let mut scheduler = Scheduler::new(600);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client { stream };
+            session(&mut client);
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        GdbStub::default()
+            .serve(stream, &mut cpu, &mut scheduler)
+            .unwrap();
+        client.join().unwrap();
+
+        cpu
+    }
+
+    // LD V0, 5; ADD V0, 1; CLS; JP 0x202
+    const PROGRAM: [u8; 8] = [0x60, 0x05, 0x70, 0x01, 0x00, 0xE0, 0x12, 0x02];
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let cpu = debug(&PROGRAM, |client| {
+            let registers = format!("{}{}", "00".repeat(16), "000000020000");
+            assert_eq!(client.request("g"), registers);
+
+            let registers = format!("11{}{}", "00".repeat(15), "341200020000");
+            assert_eq!(client.request(&format!("G{}", registers)), "OK");
+            assert_eq!(client.request("g"), registers);
+            assert_eq!(client.request("G00"), "E01");
+
+            assert_eq!(client.request("p10"), "3412");
+            assert_eq!(client.request("P3=2a"), "OK");
+            assert_eq!(client.request("p13"), "E01");
+            client.kill();
+        });
+
+        assert_eq!(
+            (cpu.v[0], cpu.v[3], cpu.i, cpu.pc),
+            (0x11, 0x2A, 0x1234, 0x200)
+        );
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let cpu = debug(&PROGRAM, |client| {
+            assert_eq!(client.request("m200,4"), "60057001");
+            assert_eq!(client.request("M300,2:abcd"), "OK");
+            assert_eq!(client.request("m300,2"), "abcd");
+
+            // Ranges past the end of memory, including ones that overflow
+            assert_eq!(client.request("mfff,2"), "E01");
+            assert_eq!(client.request("mffffffffffffffff,2"), "E01");
+            assert_eq!(client.request("Mffffffffffffffff,1:00"), "E01");
+            assert_eq!(client.request("M300,2:ab"), "E01");
+            assert_eq!(
+                client.request("qXfer:features:read:target.xml:ffffffffffffffff,ff"),
+                "l"
+            );
+            assert_eq!(
+                client.request("qXfer:features:read:target.xml:1,ffffffffffffffff"),
+                "E01"
+            );
+            client.kill();
+        });
+
+        assert_eq!(cpu.memory[0x300..0x302], [0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn steps_and_continues_to_breakpoints() {
+        let cpu = debug(&PROGRAM, |client| {
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(client.request("Z0,204,2"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p11"), "0402");
+
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p11"), "0602");
+
+            // Continuing from the breakpoint goes around the loop once
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p0"), "07");
+
+            assert_eq!(client.request("z0,204,2"), "OK");
+            assert_eq!(client.request("Z1,204,2"), "");
+            assert_eq!(client.request("s206"), "S05");
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        assert_eq!((cpu.pc, cpu.v[0]), (0x202, 7));
+    }
+
+    #[test]
+    fn asks_for_damaged_packets_again() {
+        debug(&PROGRAM, |client| {
+            assert_eq!(client.send_raw("$m200,2#00"), b'-');
+            assert_eq!(client.request("m200,2"), "6005");
+            client.kill();
+        });
+    }
+}
@@ -0,0 +1,558 @@
+use crate::cpu::machine::Machine;
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use crate::scheduler::{FrameHook, FRAMES_PER_SECOND};
+use crate::screenshot::{Image, Palette};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// GIF codes are at most 12 bits wide, limiting the LZW table to 4096 entries
+const MAX_CODES: u16 = 4096;
+
+// GIF frame delays are counted in hundredths of a second
+const CENTISECONDS_PER_SECOND: u64 = 100;
+
+// Records the display to an animated GIF, one frame per emulated frame.
+// Frames the same as the one before are merged into it, so a program waiting
+// for input costs nothing, and each frame after the first only covers the
+// part of the display that changed.
+//
+// GIF delays are in hundredths of a second, so 60 frames a second come out
+// as alternating delays of 1 and 2 hundredths. Some viewers slow down delays
+// that short.
+pub struct GifRecorder<W: Write> {
+    output: W,
+    scale: usize,
+    palette: Palette,
+    // SUPER-CHIP and XO-CHIP programs can switch between resolutions, so
+    // their low resolution frames are doubled to fill a high resolution
+    // canvas
+    doubled: bool,
+    started: bool,
+    // The frame waiting for a different one to arrive, and how many frames
+    // it has lasted
+    pending: Option<(Image, u64)>,
+    // The last frame written, which the next one is drawn over
+    written: Option<Image>,
+    // Frames written so far, to keep delays in step with emulated time
+    frames_written: u64,
+}
+
+impl GifRecorder<BufWriter<File>> {
+    pub fn create(
+        filename: &str,
+        machine: Machine,
+        scale: usize,
+        palette: Palette,
+    ) -> Result<GifRecorder<BufWriter<File>>, EmulatorError> {
+        let file = File::create(filename)?;
+
+        Ok(GifRecorder::new(
+            BufWriter::new(file),
+            machine,
+            scale,
+            palette,
+        ))
+    }
+}
+
+impl<W: Write> GifRecorder<W> {
+    pub fn new(output: W, machine: Machine, scale: usize, palette: Palette) -> GifRecorder<W> {
+        GifRecorder {
+            output,
+            scale,
+            palette,
+            doubled: machine >= Machine::SuperChip,
+            started: false,
+            pending: None,
+            written: None,
+            frames_written: 0,
+        }
+    }
+
+    // Add the display as it is now to the recording.
+    pub fn capture(&mut self, cpu: &CPU) -> Result<(), EmulatorError> {
+        let scale = if self.doubled && !cpu.hires {
+            self.scale * 2
+        } else {
+            self.scale
+        };
+        let image = Image::capture(cpu, scale, self.palette.clone());
+
+        match &mut self.pending {
+            Some((pending, frames)) if pending.pixels == image.pixels => *frames += 1,
+            _ => {
+                if let Some((pending, frames)) = self.pending.take() {
+                    self.write_frame(pending, frames)?;
+                }
+                self.pending = Some((image, 1));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write out the last frame and the end of the file.
+    pub fn close(&mut self) -> Result<(), EmulatorError> {
+        if let Some((pending, frames)) = self.pending.take() {
+            self.write_frame(pending, frames)?;
+        }
+
+        if self.started {
+            self.output.write_all(&[0x3B])?;
+        }
+        self.output.flush()?;
+
+        Ok(())
+    }
+
+    fn write_header(&mut self, image: &Image) -> Result<(), EmulatorError> {
+        let bits = self.colour_bits();
+        let mut header = Vec::new();
+
+        header.extend_from_slice(b"GIF89a");
+        header.extend_from_slice(&dimension(image.width)?);
+        header.extend_from_slice(&dimension(image.height)?);
+        // A global colour table of 2^bits entries, with `bits` bits of
+        // colour resolution
+        header.push(0x80 | (bits - 1) << 4 | (bits - 1));
+        header.extend_from_slice(&[0, 0]);
+        for colour in self.palette.colours.iter() {
+            header.extend_from_slice(colour);
+        }
+
+        // Loop forever
+        header.extend_from_slice(&[0x21, 0xFF, 0x0B]);
+        header.extend_from_slice(b"NETSCAPE2.0");
+        header.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+
+        self.output.write_all(&header)?;
+        self.started = true;
+
+        Ok(())
+    }
+
+    fn write_frame(&mut self, image: Image, frames: u64) -> Result<(), EmulatorError> {
+        if !self.started {
+            self.write_header(&image)?;
+        }
+
+        // Round the times the frame starts and ends, rather than its length,
+        // so that rounding errors do not add up
+        let time = |frames: u64| {
+            (frames * CENTISECONDS_PER_SECOND * 2 + FRAMES_PER_SECOND as u64)
+                / (FRAMES_PER_SECOND as u64 * 2)
+        };
+        let mut delay = time(self.frames_written + frames) - time(self.frames_written);
+        self.frames_written += frames;
+
+        let (left, top, right, bottom) = match &self.written {
+            Some(written) => changed_area(written, &image),
+            None => (0, 0, image.width, image.height),
+        };
+
+        // Delays longer than a GIF can hold continue in frames redrawing a
+        // single unchanged pixel
+        let mut area = (left, top, right, bottom);
+        loop {
+            let length = delay.min(u16::MAX as u64);
+            self.write_image(&image, area, length as u16)?;
+            delay -= length;
+
+            if delay == 0 {
+                break;
+            }
+            area = (0, 0, 1, 1);
+        }
+
+        self.written = Some(image);
+
+        Ok(())
+    }
+
+    fn write_image(
+        &mut self,
+        image: &Image,
+        (left, top, right, bottom): (usize, usize, usize, usize),
+        delay: u16,
+    ) -> Result<(), EmulatorError> {
+        let mut block = Vec::new();
+
+        // Graphic control extension: leave the frame in place for the next
+        // one to be drawn over
+        block.extend_from_slice(&[0x21, 0xF9, 0x04, 0x04]);
+        block.extend_from_slice(&delay.to_le_bytes());
+        block.extend_from_slice(&[0x00, 0x00]);
+
+        block.push(0x2C);
+        for value in [left, top, right - left, bottom - top].iter() {
+            block.extend_from_slice(&dimension(*value)?);
+        }
+        block.push(0x00);
+
+        let pixels: Vec<u8> = image
+            .pixels
+            .chunks(image.width)
+            .skip(top)
+            .take(bottom - top)
+            .flat_map(|row| row[left..right].iter().copied())
+            .collect();
+
+        // GIF needs at least 2 bits for the first codes
+        let minimum_code_size = self.colour_bits().max(2);
+        block.push(minimum_code_size);
+        for chunk in lzw(&pixels, minimum_code_size).chunks(255) {
+            block.push(chunk.len() as u8);
+            block.extend_from_slice(chunk);
+        }
+        block.push(0x00);
+
+        self.output.write_all(&block)?;
+
+        Ok(())
+    }
+
+    fn colour_bits(&self) -> u8 {
+        match self.palette.colours.len() {
+            4 => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl<W: Write> FrameHook for GifRecorder<W> {
+    fn frame(&mut self, cpu: &CPU, _frame: u64) -> Result<(), EmulatorError> {
+        self.capture(cpu)
+    }
+
+    fn finish(&mut self) -> Result<(), EmulatorError> {
+        self.close()
+    }
+}
+
+// The smallest rectangle holding every pixel that differs between two
+// images, as left, top, right and bottom edges. It covers a single pixel
+// when nothing differs, as GIF frames cannot be empty.
+fn changed_area(before: &Image, after: &Image) -> (usize, usize, usize, usize) {
+    if before.width != after.width || before.height != after.height {
+        return (0, 0, after.width, after.height);
+    }
+
+    let (mut left, mut top, mut right, mut bottom) = (after.width, after.height, 0, 0);
+
+    for (index, (a, b)) in before.pixels.iter().zip(after.pixels.iter()).enumerate() {
+        if a != b {
+            let (x, y) = (index % after.width, index / after.width);
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + 1);
+            bottom = bottom.max(y + 1);
+        }
+    }
+
+    if left >= right {
+        return (0, 0, 1, 1);
+    }
+
+    (left, top, right, bottom)
+}
+
+// A width, height or position as GIF stores it, in 16 bits.
+fn dimension(value: usize) -> Result<[u8; 2], EmulatorError> {
+    u16::try_from(value).map(u16::to_le_bytes).map_err(|_| {
+        EmulatorError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} pixels is too large for a GIF", value),
+        ))
+    })
+}
+
+// Compress palette indices with GIF's variant of LZW, starting with codes one
+// bit wider than `minimum_code_size` and growing them up to 12 bits.
+fn lzw(indices: &[u8], minimum_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << minimum_code_size;
+    let end = clear + 1;
+
+    let mut writer = BitWriter::default();
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut width = minimum_code_size + 1;
+
+    writer.write(clear, width);
+
+    let mut prefix: Option<u16> = None;
+
+    for &index in indices {
+        let code = match prefix {
+            Some(code) => code,
+            None => {
+                prefix = Some(index as u16);
+                continue;
+            }
+        };
+
+        if let Some(&extended) = table.get(&(code, index)) {
+            prefix = Some(extended);
+            continue;
+        }
+
+        writer.write(code, width);
+        table.insert((code, index), next);
+        next += 1;
+
+        if next > 1 << width && width < 12 {
+            width += 1;
+        }
+
+        // Start again once the table is full
+        if next == MAX_CODES {
+            writer.write(clear, width);
+            table.clear();
+            next = end + 1;
+            width = minimum_code_size + 1;
+        }
+
+        prefix = Some(index as u16);
+    }
+
+    if let Some(code) = prefix {
+        writer.write(code, width);
+
+        // Decoders add an entry for every code they read, the last one
+        // included, so the end code may need to be wider
+        if next >= 1 << width && width < 12 {
+            width += 1;
+        }
+    }
+    writer.write(end, width);
+
+    writer.finish()
+}
+
+// Packs codes into bytes least significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, width: u8) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += width;
+
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Undo `lzw`, reading codes least significant bit first and growing
+    // them as a GIF decoder does. Returns the indices and how many clear
+    // codes were read.
+    fn unlzw(bytes: &[u8], minimum_code_size: u8) -> (Vec<u8>, usize) {
+        let clear = 1usize << minimum_code_size;
+        let end = clear + 1;
+
+        let mut position = 0;
+        let mut read = |width: u8| {
+            let mut code = 0;
+            for bit in 0..width as usize {
+                let byte = bytes[(position + bit) / 8];
+                code |= ((byte >> ((position + bit) % 8)) as usize & 1) << bit;
+            }
+            position += width as usize;
+            code
+        };
+
+        let reset = || -> Vec<Vec<u8>> { (0..end + 1).map(|index| vec![index as u8]).collect() };
+        let mut table = reset();
+        let mut width = minimum_code_size + 1;
+        let mut previous: Option<usize> = None;
+        let mut output = Vec::new();
+        let mut clears = 0;
+
+        loop {
+            let code = read(width);
+
+            if code == clear {
+                table = reset();
+                width = minimum_code_size + 1;
+                previous = None;
+                clears += 1;
+                continue;
+            }
+            if code == end {
+                break;
+            }
+
+            let entry = match previous {
+                Some(previous) if code == table.len() => {
+                    let mut entry = table[previous].clone();
+                    entry.push(entry[0]);
+                    entry
+                }
+                _ => table[code].clone(),
+            };
+
+            if let Some(previous) = previous {
+                let mut added = table[previous].clone();
+                added.push(entry[0]);
+                table.push(added);
+            }
+
+            output.extend_from_slice(&entry);
+            previous = Some(code);
+
+            if table.len() == 1 << width && width < 12 {
+                width += 1;
+            }
+        }
+
+        (output, clears)
+    }
+
+    // Pseudo-random palette indices below 2^bits
+    fn noise(count: usize, bits: u8) -> Vec<u8> {
+        let mut state: u32 = 1;
+
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8 & ((1 << bits) - 1)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compresses_known_bytes() {
+        // Clear, 0, 0 0 as code 6, end, all 3 bits wide
+        assert_eq!(lzw(&[0, 0, 0], 2), [0x84, 0x0B]);
+        assert_eq!(lzw(&[], 2), [0x2C]);
+    }
+
+    #[test]
+    fn decompresses_to_the_input() {
+        for &(count, bits) in [(1, 2), (10, 2), (1000, 1), (5000, 2)].iter() {
+            let indices = noise(count, bits);
+            let (decoded, _) = unlzw(&lzw(&indices, 2), 2);
+
+            assert_eq!(decoded, indices, "{} indices of {} bits", count, bits);
+        }
+    }
+
+    #[test]
+    fn starts_again_when_the_table_fills() {
+        let indices = noise(200_000, 2);
+        let (decoded, clears) = unlzw(&lzw(&indices, 2), 2);
+
+        assert_eq!(decoded, indices);
+        assert!(clears > 2);
+    }
+
+    // The delay and area of every image in a GIF, along with its pixels
+    fn frames(gif: &[u8]) -> Vec<(u16, [u16; 4], Vec<u8>)> {
+        assert_eq!(gif[..6], *b"GIF89a");
+        assert_eq!(gif.last(), Some(&0x3B));
+
+        let table = 3 << ((gif[10] & 0x07) + 1);
+        let mut position = 13 + table;
+        let mut delay = 0;
+        let mut frames = Vec::new();
+
+        // Data sub-blocks, up to the empty one ending them
+        let sub_blocks = |position: &mut usize| {
+            let mut data = Vec::new();
+            while gif[*position] != 0 {
+                let length = gif[*position] as usize;
+                data.extend_from_slice(&gif[*position + 1..*position + 1 + length]);
+                *position += 1 + length;
+            }
+            *position += 1;
+            data
+        };
+
+        loop {
+            match gif[position] {
+                0x21 => {
+                    let label = gif[position + 1];
+                    position += 2;
+                    let data = sub_blocks(&mut position);
+                    if label == 0xF9 {
+                        delay = u16::from_le_bytes([data[1], data[2]]);
+                    }
+                }
+                0x2C => {
+                    let field = |index: usize| {
+                        u16::from_le_bytes([gif[position + index], gif[position + index + 1]])
+                    };
+                    let area = [field(1), field(3), field(5), field(7)];
+                    let minimum_code_size = gif[position + 10];
+                    position += 11;
+
+                    let (pixels, _) = unlzw(&sub_blocks(&mut position), minimum_code_size);
+                    frames.push((delay, area, pixels));
+                }
+                0x3B => return frames,
+                byte => panic!("unexpected block {:#04X}", byte),
+            }
+        }
+    }
+
+    #[test]
+    fn merges_identical_frames() {
+        let mut recorder = GifRecorder::new(Vec::new(), Machine::Chip8, 1, Palette::default());
+        let mut cpu = CPU::default();
+
+        for _ in 0..3 {
+            recorder.capture(&cpu).unwrap();
+        }
+        cpu.gfx[64 * 2 + 5] = 1;
+        for _ in 0..2 {
+            recorder.capture(&cpu).unwrap();
+        }
+        recorder.close().unwrap();
+
+        // Five frames last 5/60 of a second, rounded as 5 and 3 hundredths
+        let frames = frames(&recorder.output);
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].0, frames[0].1), (5, [0, 0, 64, 32]));
+        assert!(frames[0].2.iter().all(|&pixel| pixel == 0));
+        assert_eq!(frames[1], (3, [5, 2, 1, 1], vec![1]));
+    }
+
+    #[test]
+    fn splits_delays_too_long_for_one_frame() {
+        let mut recorder = GifRecorder::new(Vec::new(), Machine::Chip8, 1, Palette::default());
+        let image = Image::capture(&CPU::default(), 1, Palette::default());
+
+        // 11 minutes, or 66000 hundredths of a second
+        recorder
+            .write_frame(image, 11 * 60 * FRAMES_PER_SECOND as u64)
+            .unwrap();
+        recorder.close().unwrap();
+
+        let delays: Vec<u16> = frames(&recorder.output)
+            .iter()
+            .map(|(delay, _, _)| *delay)
+            .collect();
+        assert_eq!(delays, [u16::MAX, (66000 - u16::MAX as u32) as u16]);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use std::collections::HashMap;
+use std::fs;
+
+// Maps host keys to the 16 keys of the hex keypad.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    bindings: HashMap<char, u8>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::qwerty()
+    }
+}
+
+impl Keymap {
+    pub fn empty() -> Keymap {
+        Keymap {
+            bindings: HashMap::new(),
+        }
+    }
+
+    // The conventional layout of the hex keypad on the left of a QWERTY
+    // keyboard:
+    //
+    //   1 2 3 C        1 2 3 4
+    //   4 5 6 D   <-   Q W E R
+    //   7 8 9 E        A S D F
+    //   A 0 B F        Z X C V
+    pub fn qwerty() -> Keymap {
+        let mut keymap = Keymap::empty();
+        let layout = [
+            ('1', 0x1),
+            ('2', 0x2),
+            ('3', 0x3),
+            ('4', 0xC),
+            ('q', 0x4),
+            ('w', 0x5),
+            ('e', 0x6),
+            ('r', 0xD),
+            ('a', 0x7),
+            ('s', 0x8),
+            ('d', 0x9),
+            ('f', 0xE),
+            ('z', 0xA),
+            ('x', 0x0),
+            ('c', 0xB),
+            ('v', 0xF),
+        ];
+
+        for (host, key) in layout.iter() {
+            keymap.bind(*host, *key);
+        }
+
+        keymap
+    }
+
+    // Read a keymap from a file. See `parse` for the format.
+    pub fn load(filename: &str) -> Result<Keymap, EmulatorError> {
+        Keymap::parse(&fs::read_to_string(filename)?)
+    }
+
+    // Parse a keymap from text with one binding per line, written as the host
+    // key, an equals sign and the hex keypad key:
+    //
+    //   # Left-hand keys for a two-player game
+    //   q = 1
+    //   a = 4
+    //   space = 0
+    //
+    // The host key is a single character, `space`, or a character code
+    // written in hex such as `0x2C`. Blank lines and lines starting with `#`
+    // are ignored.
+    pub fn parse(text: &str) -> Result<Keymap, EmulatorError> {
+        let mut keymap = Keymap::empty();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: &str| EmulatorError::InvalidConfig {
+                line: index + 1,
+                message: message.to_string(),
+            };
+
+            let (host, key) = match line.find('=') {
+                Some(position) => (line[..position].trim(), line[position + 1..].trim()),
+                None => return Err(invalid("expected `host key = keypad key`")),
+            };
+
+            let host = parse_host_key(host).ok_or_else(|| invalid("unknown host key"))?;
+            let key = match u8::from_str_radix(key, 16) {
+                Ok(key) if key <= 0xF => key,
+                _ => return Err(invalid("keypad key must be a hex digit from 0 to F")),
+            };
+
+            keymap.bind(host, key);
+        }
+
+        Ok(keymap)
+    }
+
+    pub fn bind(&mut self, host: char, key: u8) {
+        self.bindings.insert(host.to_ascii_lowercase(), key & 0x0F);
+    }
+
+    pub fn lookup(&self, host: char) -> Option<u8> {
+        self.bindings.get(&host.to_ascii_lowercase()).copied()
+    }
+}
+
+fn parse_host_key(text: &str) -> Option<char> {
+    let mut characters = text.chars();
+
+    match (characters.next(), characters.next()) {
+        (Some(character), None) => Some(character),
+        _ if text.eq_ignore_ascii_case("space") => Some(' '),
+        _ if text.starts_with("0x") || text.starts_with("0X") => {
+            u32::from_str_radix(&text[2..], 16)
+                .ok()
+                .and_then(std::char::from_u32)
+        }
+        _ => None,
+    }
+}
+
+// Current state of the hex keypad as seen by a frontend. Frontends report key
+// presses and releases here as they happen, and the keypad hands the held keys
+// and the release edges to the CPU once per frame.
+#[derive(Clone, Debug, Default)]
+pub struct Keypad {
+    pressed: [bool; 16],
+
+    // State as of the last time it was applied to a CPU, used to find edges
+    previous: [bool; 16],
+}
+
+impl Keypad {
+    pub fn press(&mut self, key: u8) {
+        self.pressed[(key & 0x0F) as usize] = true;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.pressed[(key & 0x0F) as usize] = false;
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.pressed[(key & 0x0F) as usize]
+    }
+
+    // Keys that went down since the last call to `apply`
+    pub fn just_pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..16u8).filter(move |&key| self.pressed[key as usize] && !self.previous[key as usize])
+    }
+
+    // Keys that came up since the last call to `apply`
+    pub fn just_released(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..16u8).filter(move |&key| !self.pressed[key as usize] && self.previous[key as usize])
+    }
+
+    // Copy the keypad into the CPU. Release edges are recorded separately
+    // from the held keys because FX0A, like the original COSMAC VIP, only
+    // completes once the key is let go.
+    pub fn apply(&mut self, cpu: &mut CPU) {
+        cpu.key_released = [false; 16];
+
+        for key in self.just_released() {
+            cpu.key_released[key as usize] = true;
+        }
+
+        for (key, pressed) in self.pressed.iter().enumerate() {
+            cpu.key[key] = *pressed as u16;
+        }
+
+        self.previous = self.pressed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_the_qwerty_layout() {
+        let keymap = Keymap::default();
+
+        assert_eq!(keymap.lookup('4'), Some(0xC));
+        assert_eq!(keymap.lookup('X'), Some(0x0));
+        assert_eq!(keymap.lookup('v'), Some(0xF));
+        assert_eq!(keymap.lookup('p'), None);
+    }
+
+    #[test]
+    fn parses_keymaps() {
+        let keymap = Keymap::parse("# two players\nq = 1\n\nspace=0\n0x2C = f\n").unwrap();
+
+        assert_eq!(keymap.lookup('Q'), Some(0x1));
+        assert_eq!(keymap.lookup(' '), Some(0x0));
+        assert_eq!(keymap.lookup(','), Some(0xF));
+        assert_eq!(keymap.lookup('w'), None);
+    }
+
+    #[test]
+    fn reports_the_line_of_bad_bindings() {
+        for (text, bad_line) in &[("q = 1\nw 2\n", 2), ("\n\nenter = 1", 3), ("q = 10", 1)] {
+            match Keymap::parse(text) {
+                Err(EmulatorError::InvalidConfig { line, .. }) => assert_eq!(line, *bad_line),
+                result => panic!("{:?} parsed as {:?}", text, result),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_releases_once() {
+        let mut cpu = CPU::default();
+        let mut keypad = Keypad::default();
+
+        keypad.press(5);
+        keypad.apply(&mut cpu);
+        assert_eq!((cpu.key[5], cpu.key_released[5]), (1, false));
+
+        keypad.release(5);
+        keypad.apply(&mut cpu);
+        assert_eq!((cpu.key[5], cpu.key_released[5]), (0, true));
+
+        keypad.apply(&mut cpu);
+        assert!(!cpu.key_released[5]);
+    }
+}
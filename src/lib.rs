@@ -0,0 +1,17 @@
+pub mod assembler;
+pub mod batch;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod error;
+pub mod flags;
+pub mod gdb;
+pub mod gif;
+pub mod hash;
+pub mod input;
+pub mod octo;
+pub mod rewind;
+pub mod scheduler;
+pub mod screenshot;
+pub mod terminal;
+pub mod trace;
@@ -1,25 +1,451 @@
-pub mod cpu;
+mod cli;
+
+use chip8::assembler::Assembler;
+use chip8::batch::{self, KeyScript};
+use chip8::cpu;
+use chip8::cpu::machine::Machine;
+use chip8::cpu::quirks::Quirks;
+use chip8::debugger::Debugger;
+use chip8::disassembler::{Disassembler, Syntax};
+use chip8::error::EmulatorError;
+use chip8::flags::FlagStore;
+use chip8::gdb::GdbStub;
+use chip8::gif::GifRecorder;
+use chip8::input::Keymap;
+use chip8::octo::Compiler;
+use chip8::rewind::{Rewind, DEFAULT_KEYFRAME_INTERVAL};
+use chip8::scheduler::{Scheduler, FRAMES_PER_SECOND};
+use chip8::screenshot::{Image, Palette};
+use chip8::terminal::{Action, Style, Terminal};
+use chip8::trace::{JsonSink, LogSink};
+use cli::{AssembleOptions, Command, DisassembleOptions, Frontend, Options, TestOptions};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+// Process exit codes
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_USAGE: i32 = 2;
+const EXIT_LOAD_FAILED: i32 = 3;
+const EXIT_EMULATION_FAILED: i32 = 4;
+const EXIT_FRONTEND_FAILED: i32 = 5;
+const EXIT_OUTPUT_FAILED: i32 = 6;
+const EXIT_TEST_FAILED: i32 = 7;
 
 fn main() {
-    let mut cpu: cpu::CPU = cpu::CPU {
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error.message, error.usage);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    let code = match command {
+        Command::Run(options) if options.help => print_usage(cli::USAGE),
+        Command::Run(options) => run(&options),
+        Command::Disassemble(options) if options.help => print_usage(cli::DISASSEMBLE_USAGE),
+        Command::Disassemble(options) => disassemble(&options),
+        Command::Assemble(options) if options.help => print_usage(cli::ASSEMBLE_USAGE),
+        Command::Assemble(options) => assemble(&options),
+        Command::Test(options) if options.help => print_usage(cli::TEST_USAGE),
+        Command::Test(options) => test(&options),
+    };
+
+    process::exit(code);
+}
+
+fn print_usage(usage: &str) -> i32 {
+    println!("{}", usage);
+    EXIT_SUCCESS
+}
+
+fn disassemble(options: &DisassembleOptions) -> i32 {
+    let rom = match fs::read(&options.rom) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Program load failed: {}", e);
+            return EXIT_LOAD_FAILED;
+        }
+    };
+
+    let disassembler = Disassembler {
+        syntax: options.syntax,
+        labels: options.labels,
+        listing: !options.source,
+        machine: options.machine,
+        ..Default::default()
+    };
+
+    write_output(
+        options.output.as_deref(),
+        disassembler.disassemble(&rom).as_bytes(),
+    )
+}
+
+fn assemble(options: &AssembleOptions) -> i32 {
+    let result = match options.syntax() {
+        Syntax::Cowgod => Assembler {
+            machine: options.machine,
+            ..Default::default()
+        }
+        .assemble_file(&options.source),
+        Syntax::Octo => Compiler {
+            machine: options.machine,
+            ..Default::default()
+        }
+        .compile_file(&options.source),
+    };
+
+    match result {
+        Ok(rom) => write_output(Some(&options.output_file()), &rom),
+        Err(e) => {
+            eprintln!("Assembly failed: {}", e);
+            EXIT_LOAD_FAILED
+        }
+    }
+}
+
+// Write a command's result to a file, or to stdout without one.
+fn write_output(filename: Option<&str>, bytes: &[u8]) -> i32 {
+    let result = match filename {
+        Some(filename) => fs::write(filename, bytes),
+        None => io::stdout().write_all(bytes),
+    };
+
+    match result {
+        Ok(()) => EXIT_SUCCESS,
+        Err(e) => {
+            eprintln!("Could not write output: {}", e);
+            EXIT_OUTPUT_FAILED
+        }
+    }
+}
+
+fn test(options: &TestOptions) -> i32 {
+    let mut cpu = match load(&options.rom, options.machine, options.quirks) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("Program load failed: {}", e);
+            return EXIT_LOAD_FAILED;
+        }
+    };
+
+    let mut script = match &options.keys {
+        Some(filename) => match KeyScript::load(filename) {
+            Ok(script) => script,
+            Err(e) => {
+                eprintln!("Key script load failed: {}", e);
+                return EXIT_USAGE;
+            }
+        },
+        None => KeyScript::default(),
+    };
+    script.presses.extend_from_slice(&options.presses);
+
+    let mut expectations = options.expectations.clone();
+    if let Some(filename) = &options.expect_file {
+        match fs::read_to_string(filename)
+            .map_err(EmulatorError::from)
+            .and_then(|text| batch::parse_expectations(&text))
+        {
+            Ok(expected) => expectations.extend(expected),
+            Err(e) => {
+                eprintln!("Expectations load failed: {}", e);
+                return EXIT_USAGE;
+            }
+        }
+    }
+
+    let mut scheduler = Scheduler::new(options.instructions_per_second);
+    scheduler.max_cycles = options.max_cycles;
+
+    let report = match batch::run(&mut cpu, &mut scheduler, options.frames, &script) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error in fetch/decode/execute: {}", e);
+            return EXIT_EMULATION_FAILED;
+        }
+    };
+
+    print!("{}", report);
+    if options.screen {
+        print!("{}", batch::screen(&cpu));
+    }
+
+    if let Some(filename) = &options.screenshot {
+        let palette = screenshot_palette(&cpu, &options.palette);
+        let code = save_screenshot(&cpu, filename, options.scale, palette);
+        if code != EXIT_SUCCESS {
+            return code;
+        }
+    }
+
+    let failures = report.check(&expectations);
+    for failure in &failures {
+        eprintln!("Mismatch: {}", failure);
+    }
+
+    if failures.is_empty() {
+        EXIT_SUCCESS
+    } else {
+        EXIT_TEST_FAILED
+    }
+}
+
+fn save_screenshot(cpu: &cpu::CPU, filename: &str, scale: usize, palette: Palette) -> i32 {
+    match Image::capture(cpu, scale, palette).save(filename) {
+        Ok(()) => EXIT_SUCCESS,
+        Err(e) => {
+            eprintln!("Could not save screenshot: {}", e);
+            EXIT_OUTPUT_FAILED
+        }
+    }
+}
+
+// The palette asked for, or the one suiting the machine
+fn screenshot_palette(cpu: &cpu::CPU, palette: &Option<Palette>) -> Palette {
+    palette
+        .clone()
+        .unwrap_or_else(|| Palette::for_machine(cpu.machine))
+}
+
+// Create a CPU for the machine and load a program into it, compiling it
+// first if it is Octo source.
+fn load(rom: &str, machine: Machine, quirks: Option<Quirks>) -> Result<cpu::CPU, EmulatorError> {
+    let mut cpu = cpu::CPU {
+        machine,
+        quirks: quirks.unwrap_or_else(|| Quirks::for_machine(machine)),
         ..Default::default()
     };
 
     cpu.initialize();
 
-    match cpu.load_program("pong.ch8") {
-        Ok(_) => println!("Loaded program successfully."),
-        Err(e) => eprintln!("Program load failed: {}", e),
+    if cli::is_octo(rom) {
+        let compiler = Compiler {
+            machine,
+            load_address: cpu.load_address,
+        };
+        cpu.load_program_bytes(&compiler.compile_file(rom)?)?;
+    } else {
+        cpu.load_program(rom)?;
     }
 
+    Ok(cpu)
+}
+
+fn run(options: &Options) -> i32 {
+    let mut cpu = match load(&options.rom, options.machine, options.quirks) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("Program load failed: {}", e);
+            return EXIT_LOAD_FAILED;
+        }
+    };
+
+    if let Some(filename) = &options.trace_json {
+        match File::create(filename) {
+            Ok(file) => cpu.tracer = Box::new(JsonSink::new(BufWriter::new(file))),
+            Err(e) => {
+                eprintln!("Could not create trace file: {}", e);
+                return EXIT_USAGE;
+            }
+        }
+    } else if options.trace {
+        cpu.tracer = Box::new(LogSink::new(io::stderr()));
+    }
+
+    let mut flag_store = match &options.flags_dir {
+        Some(directory) => Some(FlagStore::new(directory)),
+        None => FlagStore::default_directory().map(FlagStore::new),
+    };
+
+    if let Some(flag_store) = &mut flag_store {
+        if let Err(e) = flag_store.load(&mut cpu) {
+            eprintln!("Could not load RPL flags: {}", e);
+        }
+    }
+
+    // Flags are loaded first so that the ones in the state take precedence
+    if options.resume {
+        if let Err(e) = cpu.restore_state_file(&options.state_file()) {
+            eprintln!("State restore failed: {}", e);
+            return EXIT_LOAD_FAILED;
+        }
+    }
+
+    let keymap = match &options.keymap {
+        Some(filename) => match Keymap::load(filename) {
+            Ok(keymap) => keymap,
+            Err(e) => {
+                eprintln!("Keymap load failed: {}", e);
+                return EXIT_USAGE;
+            }
+        },
+        None => Keymap::default(),
+    };
+
+    let mut scheduler = Scheduler::new(options.instructions_per_second);
+    scheduler.max_cycles = options.max_cycles;
+
+    if let Some(filename) = &options.record {
+        let palette = screenshot_palette(&cpu, &options.palette);
+        match GifRecorder::create(filename, cpu.machine, options.scale, palette) {
+            Ok(recorder) => scheduler.frame_hook = Some(Box::new(recorder)),
+            Err(e) => {
+                eprintln!("Could not start recording: {}", e);
+                return EXIT_OUTPUT_FAILED;
+            }
+        }
+    }
+
+    let result = match options.frontend {
+        _ if options.gdb_port.is_some() => {
+            let address = format!("127.0.0.1:{}", options.gdb_port.unwrap_or_default());
+            eprintln!("Waiting for a debugger on {}", address);
+
+            GdbStub::default()
+                .listen(&address, &mut cpu, &mut scheduler)
+                .map_err(Failure::Frontend)
+        }
+        _ if options.debug => run_debugger(&mut cpu, &mut scheduler).map_err(Failure::Frontend),
+        Frontend::Terminal | Frontend::Braille => {
+            run_terminal(&mut cpu, &mut scheduler, keymap, options)
+        }
+        Frontend::Headless => run_headless(&mut cpu, &mut scheduler).map_err(Failure::Emulation),
+    };
+
+    let recorded = scheduler.finish_frame_hook();
+
+    // A program that failed may have left its flags half written, so the
+    // ones saved before are kept
+    if let (Some(flag_store), Ok(())) = (&flag_store, &result) {
+        if let Err(e) = flag_store.save(&cpu) {
+            eprintln!("Could not save RPL flags: {}", e);
+        }
+    }
+
+    if let Err(e) = recorded {
+        eprintln!("Could not save recording: {}", e);
+        if result.is_ok() {
+            return EXIT_OUTPUT_FAILED;
+        }
+    }
+
+    match result {
+        // Headless runs can only show the display by saving it at the end
+        Ok(()) if options.frontend == Frontend::Headless && options.screenshot.is_some() => {
+            let palette = screenshot_palette(&cpu, &options.palette);
+            save_screenshot(&cpu, &options.screenshot_file(), options.scale, palette)
+        }
+        Ok(()) => EXIT_SUCCESS,
+        Err(Failure::Emulation(e)) => {
+            eprintln!("Error in fetch/decode/execute: {}", e);
+            EXIT_EMULATION_FAILED
+        }
+        Err(Failure::Frontend(e)) => {
+            eprintln!("Frontend error: {}", e);
+            EXIT_FRONTEND_FAILED
+        }
+    }
+}
+
+enum Failure {
+    Emulation(EmulatorError),
+    Frontend(io::Error),
+}
+
+fn run_headless(cpu: &mut cpu::CPU, scheduler: &mut Scheduler) -> Result<(), EmulatorError> {
+    while !scheduler.run_frame(cpu)? {}
+
+    Ok(())
+}
+
+fn run_debugger(cpu: &mut cpu::CPU, scheduler: &mut Scheduler) -> io::Result<()> {
+    let stdin = io::stdin();
+
+    Debugger::default().run(cpu, scheduler, stdin.lock(), io::stdout())
+}
+
+fn run_terminal(
+    cpu: &mut cpu::CPU,
+    scheduler: &mut Scheduler,
+    keymap: Keymap,
+    options: &Options,
+) -> Result<(), Failure> {
+    let style = match options.frontend {
+        Frontend::Braille => Style::Braille,
+        _ => Style::HalfBlock,
+    };
+    let state_file = options.state_file();
+    let screenshot_file = options.screenshot_file();
+    let mut rewind = Rewind::new(
+        options.rewind_seconds as usize * FRAMES_PER_SECOND as usize,
+        DEFAULT_KEYFRAME_INTERVAL,
+    );
+
+    // The terminal is restored when it is dropped on the way out, before the
+    // caller reports any error.
+    let mut terminal = Terminal::new(style, keymap).map_err(Failure::Frontend)?;
+
     loop {
-        match cpu.fetch_decode_execute() {
-            Ok(reached_end) => {
-                if reached_end {
-                    break;
+        let action = terminal.poll_input(cpu);
+
+        // Problems with save states and screenshots are shown without stopping the program
+        let status = match action {
+            Some(Action::Quit) => return Ok(()),
+            Some(Action::SaveState) => Some(match cpu.save_state_file(&state_file) {
+                Ok(()) => format!("Saved state to {}", state_file),
+                Err(e) => format!("State save failed: {}", e),
+            }),
+            Some(Action::RestoreState) => Some(match cpu.restore_state_file(&state_file) {
+                Ok(()) => format!("Restored state from {}", state_file),
+                Err(e) => format!("State restore failed: {}", e),
+            }),
+            Some(Action::Screenshot) => {
+                let palette = screenshot_palette(cpu, &options.palette);
+                let image = Image::capture(cpu, options.scale, palette);
+                Some(match image.save(&screenshot_file) {
+                    Ok(()) => format!("Saved screenshot to {}", screenshot_file),
+                    Err(e) => format!("Screenshot failed: {}", e),
+                })
+            }
+            Some(Action::Rewind) => {
+                let rewound = rewind
+                    .step_back_frame(cpu, scheduler)
+                    .map_err(Failure::Emulation)?;
+
+                if rewound {
+                    None
+                } else {
+                    Some(String::from("Nothing left to rewind"))
                 }
             }
-            Err(e) => eprintln!("Error in fetch/decode/execute: {}", e),
+            None => None,
+        };
+
+        // Frames spent rewinding take as long as any other
+        if action == Some(Action::Rewind) {
+            scheduler.wait_for_next_frame();
+        } else {
+            rewind.record(cpu, scheduler);
+
+            if scheduler
+                .run_frame_realtime(cpu)
+                .map_err(Failure::Emulation)?
+            {
+                return Ok(());
+            }
+        }
+
+        let (gfx, width, height) = cpu.display();
+        terminal
+            .draw(gfx, width, height)
+            .map_err(Failure::Frontend)?;
+
+        if let Some(message) = status {
+            terminal.status(&message).map_err(Failure::Frontend)?;
         }
     }
 }
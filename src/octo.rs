@@ -0,0 +1,1223 @@
+use crate::cpu::instruction::Instruction;
+use crate::cpu::machine::Machine;
+use crate::disassembler::{self, Syntax};
+use crate::error::EmulatorError;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+
+// Deepest nesting of macro expansions before giving up on a macro that
+// expands to itself
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// Compiles programs written in Octo, the language most community CHIP-8
+// programs are written in:
+//
+//   :alias x v0
+//   :const SPEED 2
+//
+//   : main
+//       x := 0
+//       loop
+//           i := ball
+//           sprite x x 4
+//           x += SPEED
+//           if x < 60 then
+//       again
+//       loop again
+//
+//   : ball
+//       0x60 0xF0 0xF0 0x60
+//
+// Execution starts at the `main` label, which is jumped to from the load
+// address unless it is the first thing defined. A name on its own calls the
+// subroutine it labels, and a number on its own is a byte of data. Besides
+// instructions, the compiler understands `:alias`, `:const`, `:calc`,
+// `:macro`, `:byte`, `:pointer`, `:org`, `:next` and `:unpack`, and the
+// structured `if ... then`, `if ... begin ... else ... end` and
+// `loop ... while ... again`. Comparisons other than `==` and `!=` use VF.
+#[derive(Clone, Debug)]
+pub struct Compiler {
+    // Instructions beyond this machine are rejected
+    pub machine: Machine,
+    // Address the program is loaded at
+    pub load_address: u16,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler {
+            machine: Machine::default(),
+            load_address: 0x200,
+        }
+    }
+}
+
+impl Compiler {
+    pub fn compile_file(&self, filename: &str) -> Result<Vec<u8>, EmulatorError> {
+        let source = fs::read_to_string(filename)?;
+
+        self.compile_named(&source, filename)
+    }
+
+    pub fn compile(&self, source: &str) -> Result<Vec<u8>, EmulatorError> {
+        self.compile_named(source, "<source>")
+    }
+
+    fn compile_named(&self, source: &str, filename: &str) -> Result<Vec<u8>, EmulatorError> {
+        let mut compilation = Compilation::new(self, source);
+
+        compilation
+            .run()
+            .map_err(|(line, message)| EmulatorError::InvalidSource {
+                file: filename.to_string(),
+                line,
+                message,
+            })?;
+
+        let capacity = self
+            .machine
+            .memory_size()
+            .saturating_sub(self.load_address as usize);
+        if compilation.rom.len() > capacity {
+            return Err(EmulatorError::RomTooLarge {
+                size: compilation.rom.len(),
+                capacity,
+            });
+        }
+
+        Ok(compilation.rom)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    // How many macro expansions produced the token
+    depth: usize,
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+// How a label's address is written once it is known
+#[derive(Clone, Copy)]
+enum Patch {
+    // The low 12 bits of an instruction
+    Address,
+    // A 16-bit big-endian address
+    Word,
+    // A nibble followed by the high 4 bits of the address, for `:unpack`
+    High(u8),
+    // The low byte of the address
+    Low,
+}
+
+// Structures still waiting for their end, with the positions of the jumps to
+// point at it
+enum Block {
+    If { jump: usize },
+    Else { jump: usize },
+    Loop { start: u16, breaks: Vec<usize> },
+}
+
+type Failure = (usize, String);
+
+struct Compilation<'a> {
+    compiler: &'a Compiler,
+    // Tokens still to compile, the next one last
+    tokens: Vec<Token>,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    // Label uses waiting for the label to be defined
+    patches: Vec<(String, usize, Patch, usize)>,
+    blocks: Vec<(Block, usize)>,
+    // Whether the program starts with a jump to `main`
+    jump_to_main: bool,
+    // Whether an address has been taken, so code can no longer move
+    addresses_taken: bool,
+}
+
+impl<'a> Compilation<'a> {
+    fn new(compiler: &'a Compiler, source: &str) -> Compilation<'a> {
+        let mut tokens = Vec::new();
+
+        for (index, line) in source.lines().enumerate() {
+            for text in line.split_whitespace() {
+                if text.starts_with('#') {
+                    break;
+                }
+
+                tokens.push(Token {
+                    text: text.to_string(),
+                    line: index + 1,
+                    depth: 0,
+                });
+            }
+        }
+        tokens.reverse();
+
+        Compilation {
+            compiler,
+            tokens,
+            line: 1,
+            rom: Vec::new(),
+            here: compiler.load_address as usize,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            patches: Vec::new(),
+            blocks: Vec::new(),
+            jump_to_main: true,
+            addresses_taken: false,
+        }
+    }
+
+    fn run(&mut self) -> Result<(), Failure> {
+        // Room for the jump to main, dropped again if main comes first
+        self.emit_word(0);
+
+        while let Some(token) = self.tokens.pop() {
+            self.line = token.line;
+            self.statement(token)
+                .map_err(|message| (self.line, message))?;
+        }
+
+        if let Some((block, line)) = self.blocks.last() {
+            let message = match block {
+                Block::If { .. } | Block::Else { .. } => "if without a matching end",
+                Block::Loop { .. } => "loop without a matching again",
+            };
+            return Err((*line, String::from(message)));
+        }
+
+        if self.jump_to_main {
+            let main = *self
+                .labels
+                .get("main")
+                .ok_or((self.line, String::from("there is no main label")))?;
+            let jump = Instruction::Jump { nnn: main }.opcode();
+            self.write(self.compiler.load_address as usize, &jump.to_be_bytes());
+        }
+
+        if let Some((name, _, _, line)) = self.patches.first() {
+            return Err((*line, format!("undefined name: {}", name)));
+        }
+
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), String> {
+        use Instruction::*;
+
+        let text = token.text.as_str();
+
+        if let Some(definition) = self.macros.get(text) {
+            let count = definition.parameters.len();
+            return self.expand(&token, count);
+        }
+
+        if self.is_register(text) {
+            return self.assignment(text);
+        }
+
+        match text {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name)
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define(name.clone(), self.here + 1)?;
+                self.resolve(&name)
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.number()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calculation()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":byte" => {
+                let value = match self.peek() {
+                    Some("{") => self.calculation()?,
+                    _ => self.number()?,
+                };
+                let byte = fit(value, 0xFF, "byte")? as u8;
+                self.emit(&[byte]);
+                Ok(())
+            }
+            ":pointer" => {
+                let position = self.here;
+                self.emit_word(0);
+                let address = self.address(Patch::Word, position)?;
+                self.write(position, &address.to_be_bytes());
+                Ok(())
+            }
+            ":org" => {
+                let address = fit(self.number()?, 0xFFFF, "address")? as usize;
+                if address < self.compiler.load_address as usize {
+                    return Err(format!("{:#X} is before the start of the program", address));
+                }
+                self.here = address;
+                self.addresses_taken = true;
+                Ok(())
+            }
+            ":unpack" => {
+                let high = match self.peek() {
+                    Some("long") => {
+                        self.next()?;
+                        0
+                    }
+                    _ => fit(self.number()?, 0xF, "nibble")? as u8,
+                };
+
+                let label = self.next()?.text;
+
+                self.instruction(SetConstant { x: 0, nn: 0 })?;
+                let position = self.here - 1;
+                let address = self.address_named(&label, Patch::High(high), position)?;
+                self.write(position, &[high << 4 | (address >> 8) as u8]);
+
+                self.instruction(SetConstant { x: 1, nn: 0 })?;
+                let position = self.here - 1;
+                let address = self.address_named(&label, Patch::Low, position)?;
+                self.write(position, &[address as u8]);
+                Ok(())
+            }
+            ":macro" => self.define_macro(),
+            ":breakpoint" => self.next().map(|_| ()),
+            ":monitor" => {
+                self.next()?;
+                self.next().map(|_| ())
+            }
+            "if" => self.conditional(),
+            "else" => match self.blocks.pop() {
+                Some((Block::If { jump }, line)) => {
+                    let position = self.jump_placeholder()?;
+                    self.patch_jump(jump, self.here)?;
+                    self.blocks.push((Block::Else { jump: position }, line));
+                    Ok(())
+                }
+                _ => Err(String::from("else without a matching if")),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If { jump }, _)) | Some((Block::Else { jump }, _)) => {
+                    self.patch_jump(jump, self.here)
+                }
+                _ => Err(String::from("end without a matching if")),
+            },
+            "loop" => {
+                let start = self.here as u16;
+                self.blocks.push((
+                    Block::Loop {
+                        start,
+                        breaks: Vec::new(),
+                    },
+                    self.line,
+                ));
+                Ok(())
+            }
+            "while" => {
+                let (setup, skip) = self.condition()?;
+                for instruction in setup {
+                    self.instruction(instruction)?;
+                }
+                self.instruction(invert(skip))?;
+                let jump = self.jump_placeholder()?;
+
+                let block = self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find_map(|(block, _)| match block {
+                        Block::Loop { breaks, .. } => Some(breaks),
+                        _ => None,
+                    });
+                match block {
+                    Some(breaks) => {
+                        breaks.push(jump);
+                        Ok(())
+                    }
+                    None => Err(String::from("while outside of a loop")),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, breaks }, _)) => {
+                    self.instruction(Jump { nnn: start })?;
+                    for jump in breaks {
+                        self.patch_jump(jump, self.here)?;
+                    }
+                    Ok(())
+                }
+                _ => Err(String::from("again without a matching loop")),
+            },
+            "clear" => self.instruction(ClearScreen),
+            "return" | ";" => self.instruction(Return),
+            "exit" => self.instruction(Exit),
+            "lores" => self.instruction(LowResolution),
+            "hires" => self.instruction(HighResolution),
+            "scroll-left" => self.instruction(ScrollLeft),
+            "scroll-right" => self.instruction(ScrollRight),
+            "audio" => self.instruction(LoadAudio),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.instruction(ScrollDown { n })
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.instruction(ScrollUp { n })
+            }
+            "plane" => {
+                let n = self.nibble()?;
+                self.instruction(SelectPlanes { n })
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.instruction(BinaryCodedDecimal { x })
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.instruction(StoreFlags { x })
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.instruction(LoadFlags { x })
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    match text {
+                        "save" => self.instruction(StoreRange { x, y }),
+                        _ => self.instruction(LoadRange { x, y }),
+                    }
+                } else {
+                    match text {
+                        "save" => self.instruction(StoreRegisters { x }),
+                        _ => self.instruction(LoadRegisters { x }),
+                    }
+                }
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.instruction(Draw { x, y, n })
+            }
+            "jump" => self.address_instruction(Jump { nnn: 0 }),
+            "jump0" => self.address_instruction(JumpOffset { nnn: 0 }),
+            "native" => {
+                // 0NNN machine code calls are not emulated, so there is no
+                // instruction for them
+                let position = self.here;
+                self.emit_word(0);
+                let address = self.address(Patch::Address, position)?;
+                self.apply(position, Patch::Address, address)
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                match text {
+                    "delay" => self.instruction(SetDelayTimer { x }),
+                    "buzzer" => self.instruction(SetSoundTimer { x }),
+                    _ => self.instruction(SetPitch { x }),
+                }
+            }
+            "i" => self.index(),
+            _ if parse_number(text).is_some() || self.constants.contains_key(text) => {
+                let value = self.value(text)?;
+                let byte = fit(value, 0xFF, "byte")? as u8;
+                self.emit(&[byte]);
+                Ok(())
+            }
+            _ if text.starts_with(':') || text.starts_with('{') || text.starts_with('}') => {
+                Err(format!("unexpected {}", text))
+            }
+            // Anything else names a subroutine, which may be defined later
+            _ => {
+                self.tokens.push(token.clone());
+                self.address_instruction(Call { nnn: 0 })
+            }
+        }
+    }
+
+    // `vx op value`
+    fn assignment(&mut self, target: &str) -> Result<(), String> {
+        use Instruction::*;
+
+        let x = self.register_named(target)?;
+        let operator = self.next()?.text;
+        let operand = self.next()?.text;
+
+        if self.is_register(&operand) {
+            let y = self.register_named(&operand)?;
+
+            let instruction = match operator.as_str() {
+                ":=" => Assign { x, y },
+                "+=" => Add { x, y },
+                "-=" => Subtract { x, y },
+                "=-" => SubtractReversed { x, y },
+                "|=" => Or { x, y },
+                "&=" => And { x, y },
+                "^=" => Xor { x, y },
+                ">>=" => ShiftRight { x, y },
+                "<<=" => ShiftLeft { x, y },
+                _ => return Err(format!("unknown operator: {}", operator)),
+            };
+
+            return self.instruction(instruction);
+        }
+
+        let instruction = match (operator.as_str(), operand.as_str()) {
+            (":=", "key") => AwaitKey { x },
+            (":=", "delay") => GetDelayTimer { x },
+            (":=", "random") => Random {
+                x,
+                nn: self.byte()?,
+            },
+            (":=", value) => SetConstant {
+                x,
+                nn: fit(self.value(value)?, 0xFF, "byte")? as u8,
+            },
+            ("+=", value) => AddConstant {
+                x,
+                nn: fit(self.value(value)?, 0xFF, "byte")? as u8,
+            },
+            ("-=", value) => AddConstant {
+                x,
+                nn: fit(-self.value(value)?, 0xFF, "byte")? as u8,
+            },
+            _ => return Err(format!("cannot use {} with {}", operator, operand)),
+        };
+
+        self.instruction(instruction)
+    }
+
+    // `i := value`, `i := long value`, `i := hex vx`, `i := bighex vx` and
+    // `i += vx`
+    fn index(&mut self) -> Result<(), String> {
+        use Instruction::*;
+
+        let operator = self.next()?.text;
+
+        match (operator.as_str(), self.peek()) {
+            ("+=", _) => {
+                let x = self.register()?;
+                self.instruction(AddIndex { x })
+            }
+            (":=", Some("hex")) => {
+                self.next()?;
+                let x = self.register()?;
+                self.instruction(FontCharacter { x })
+            }
+            (":=", Some("bighex")) => {
+                self.next()?;
+                let x = self.register()?;
+                self.instruction(LargeFontCharacter { x })
+            }
+            (":=", Some("long")) => {
+                self.next()?;
+                self.instruction(LongLoad)?;
+                let position = self.here;
+                self.emit_word(0);
+                let address = self.address(Patch::Word, position)?;
+                self.write(position, &address.to_be_bytes());
+                Ok(())
+            }
+            (":=", _) => self.address_instruction(SetIndex { nnn: 0 }),
+            _ => Err(format!("cannot use {} with i", operator)),
+        }
+    }
+
+    // `if condition then` skips the next statement when the condition is
+    // false. `if condition begin` jumps past the block instead.
+    fn conditional(&mut self) -> Result<(), String> {
+        let (setup, skip) = self.condition()?;
+        for instruction in setup {
+            self.instruction(instruction)?;
+        }
+
+        match self.next()?.text.as_str() {
+            "then" => self.instruction(skip),
+            "begin" => {
+                self.instruction(invert(skip))?;
+                let jump = self.jump_placeholder()?;
+                self.blocks.push((Block::If { jump }, self.line));
+                Ok(())
+            }
+            other => Err(format!("expected then or begin, got {}", other)),
+        }
+    }
+
+    // Parse a condition into the instructions that work it out and a skip
+    // that skips the next instruction when it is false.
+    fn condition(&mut self) -> Result<(Vec<Instruction>, Instruction), String> {
+        use Instruction::*;
+
+        let x = self.register()?;
+        let operator = self.next()?.text;
+
+        match operator.as_str() {
+            "key" => return Ok((Vec::new(), SkipIfKeyNotPressed { x })),
+            "-key" => return Ok((Vec::new(), SkipIfKeyPressed { x })),
+            _ => (),
+        }
+
+        let operand = self.next()?.text;
+        let register = if self.is_register(&operand) {
+            Some(self.register_named(&operand)?)
+        } else {
+            None
+        };
+        let constant = match register {
+            Some(_) => 0,
+            None => fit(self.value(&operand)?, 0xFF, "byte")? as u8,
+        };
+
+        match (operator.as_str(), register) {
+            ("==", Some(y)) => return Ok((Vec::new(), SkipIfRegistersNotEqual { x, y })),
+            ("==", None) => return Ok((Vec::new(), SkipIfNotEqual { x, nn: constant })),
+            ("!=", Some(y)) => return Ok((Vec::new(), SkipIfRegistersEqual { x, y })),
+            ("!=", None) => return Ok((Vec::new(), SkipIfEqual { x, nn: constant })),
+            _ => (),
+        }
+
+        // The others subtract into VF, leaving VF 1 when there was no
+        // borrow: for `<` and `>=` when x >= y, and for `>` and `<=` when
+        // y >= x
+        let x_at_least_y = match register {
+            Some(y) => vec![Assign { x: 0xF, y: x }, Subtract { x: 0xF, y }],
+            None => vec![
+                SetConstant {
+                    x: 0xF,
+                    nn: constant,
+                },
+                SubtractReversed { x: 0xF, y: x },
+            ],
+        };
+        let y_at_least_x = match register {
+            Some(y) => vec![Assign { x: 0xF, y }, Subtract { x: 0xF, y: x }],
+            None => vec![
+                SetConstant {
+                    x: 0xF,
+                    nn: constant,
+                },
+                Subtract { x: 0xF, y: x },
+            ],
+        };
+        let vf_zero = SkipIfNotEqual { x: 0xF, nn: 0 };
+        let vf_set = SkipIfEqual { x: 0xF, nn: 0 };
+
+        match operator.as_str() {
+            "<" => Ok((x_at_least_y, vf_zero)),
+            ">=" => Ok((x_at_least_y, vf_set)),
+            ">" => Ok((y_at_least_x, vf_zero)),
+            "<=" => Ok((y_at_least_x, vf_set)),
+            _ => Err(format!("unknown comparison: {}", operator)),
+        }
+    }
+
+    // `:macro name parameters { body }`
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let mut parameters = Vec::new();
+
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            parameters.push(token.text);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => (),
+            }
+            if depth == 0 {
+                break;
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name, Macro { parameters, body });
+        Ok(())
+    }
+
+    // Replace a macro's name and arguments with its body.
+    fn expand(&mut self, name: &Token, count: usize) -> Result<(), String> {
+        if name.depth >= MAX_EXPANSION_DEPTH {
+            return Err(format!("{} expands into itself too deeply", name.text));
+        }
+
+        let mut arguments = Vec::with_capacity(count);
+        for _ in 0..count {
+            arguments.push(self.next()?.text);
+        }
+
+        let definition = &self.macros[&name.text];
+        let expansion: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|token| {
+                let text = match definition.parameters.iter().position(|p| *p == token.text) {
+                    Some(index) => arguments[index].clone(),
+                    None => token.text.clone(),
+                };
+
+                Token {
+                    text,
+                    line: name.line,
+                    depth: name.depth + 1,
+                }
+            })
+            .collect();
+
+        self.tokens.extend(expansion.into_iter().rev());
+        Ok(())
+    }
+
+    // `:calc name { expression }` and `:byte { expression }`. Operators have
+    // no precedence and are worked out from right to left, as in Octo.
+    fn calculation(&mut self) -> Result<f64, String> {
+        self.expect("{")?;
+        let value = self.expression()?;
+        self.expect("}")?;
+
+        Ok(value)
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let left = self.term()?;
+
+        let operator = match self.peek() {
+            Some(operator) if operator != "}" && operator != ")" => operator.to_string(),
+            _ => return Ok(left),
+        };
+        self.next()?;
+        let right = self.expression()?;
+
+        let truth = |condition: bool| condition as i32 as f64;
+
+        Ok(match operator.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (left as i64 & right as i64) as f64,
+            "|" => (left as i64 | right as i64) as f64,
+            "^" => (left as i64 ^ right as i64) as f64,
+            "<<" | ">>" => {
+                let amount =
+                    u32::try_from(right as i64).map_err(|_| String::from("shift out of range"))?;
+                let shifted = if operator == "<<" {
+                    (left as i64).checked_shl(amount)
+                } else {
+                    (left as i64).checked_shr(amount)
+                };
+                shifted.ok_or_else(|| String::from("shift out of range"))? as f64
+            }
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => truth(left < right),
+            ">" => truth(left > right),
+            "<=" => truth(left <= right),
+            ">=" => truth(left >= right),
+            "==" => truth(left == right),
+            "!=" => truth(left != right),
+            _ => return Err(format!("unknown operator: {}", operator)),
+        })
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let token = self.next()?.text;
+
+        let unary = |function: fn(f64) -> f64, compilation: &mut Compilation| {
+            compilation.term().map(function)
+        };
+
+        match token.as_str() {
+            "(" => {
+                let value = self.expression()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            "-" => unary(|value| -value, self),
+            "~" => unary(|value| !(value as i64) as f64, self),
+            "!" => unary(|value| (value == 0.0) as i32 as f64, self),
+            "abs" => unary(f64::abs, self),
+            "sqrt" => unary(f64::sqrt, self),
+            "sin" => unary(f64::sin, self),
+            "cos" => unary(f64::cos, self),
+            "tan" => unary(f64::tan, self),
+            "exp" => unary(f64::exp, self),
+            "log" => unary(f64::ln, self),
+            "sign" => unary(f64::signum, self),
+            "ceil" => unary(f64::ceil, self),
+            "floor" => unary(f64::floor, self),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            "HERE" => {
+                self.addresses_taken = true;
+                Ok(self.here as f64)
+            }
+            name => match self.labels.get(name) {
+                Some(&address) => Ok(address as f64),
+                None => self.value(name),
+            },
+        }
+    }
+
+    fn define_label(&mut self, name: String) -> Result<(), String> {
+        let start = self.compiler.load_address as usize;
+
+        // A program starting at main needs no jump to it, unless something
+        // already depends on where the code after the jump lies
+        if name == "main"
+            && self.jump_to_main
+            && !self.addresses_taken
+            && self.here == start + 2
+            && self.rom.len() == 2
+        {
+            self.jump_to_main = false;
+            self.rom.clear();
+            self.here = start;
+        }
+
+        self.define(name.clone(), self.here)?;
+        self.resolve(&name)
+    }
+
+    fn define(&mut self, name: String, address: usize) -> Result<(), String> {
+        if self.labels.contains_key(&name) {
+            return Err(format!("{} is defined more than once", name));
+        }
+
+        self.labels.insert(name, address as u16);
+        self.addresses_taken = true;
+        Ok(())
+    }
+
+    // Fill in the uses of a label made before it was defined.
+    fn resolve(&mut self, name: &str) -> Result<(), String> {
+        let address = self.labels[name];
+        let (waiting, rest): (Vec<_>, Vec<_>) = self
+            .patches
+            .drain(..)
+            .partition(|(label, ..)| label == name);
+        self.patches = rest;
+
+        for (_, position, patch, line) in waiting {
+            self.line = line;
+            self.apply(position, patch, address)?;
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, position: usize, patch: Patch, address: u16) -> Result<(), String> {
+        match patch {
+            Patch::Address => {
+                if address > 0xFFF {
+                    return Err(format!("{:#X} is out of reach, use i := long", address));
+                }
+                let opcode = self.read(position) & 0xF000 | address;
+                self.write(position, &opcode.to_be_bytes());
+            }
+            Patch::Word => self.write(position, &address.to_be_bytes()),
+            Patch::High(nibble) => self.write(position, &[nibble << 4 | (address >> 8) as u8]),
+            Patch::Low => self.write(position, &[address as u8]),
+        }
+
+        Ok(())
+    }
+
+    // Emit an instruction whose address comes from the next token.
+    fn address_instruction(&mut self, instruction: Instruction) -> Result<(), String> {
+        let position = self.here;
+        self.instruction(instruction)?;
+        let address = self.address(Patch::Address, position)?;
+        self.apply(position, Patch::Address, address)
+    }
+
+    // An address from the next token. Labels not yet defined are patched in
+    // once they are, and read as zero until then.
+    fn address(&mut self, patch: Patch, position: usize) -> Result<u16, String> {
+        let token = self.next()?.text;
+        self.address_named(&token, patch, position)
+    }
+
+    fn address_named(&mut self, text: &str, patch: Patch, position: usize) -> Result<u16, String> {
+        if let Some(&address) = self.labels.get(text) {
+            return Ok(address);
+        }
+
+        if parse_number(text).is_some() || self.constants.contains_key(text) {
+            return fit(self.value(text)?, 0xFFFF, "address");
+        }
+
+        if self.is_register(text) || self.macros.contains_key(text) {
+            return Err(format!("expected an address, got {}", text));
+        }
+
+        self.patches
+            .push((text.to_string(), position, patch, self.line));
+        Ok(0)
+    }
+
+    fn jump_placeholder(&mut self) -> Result<usize, String> {
+        let position = self.here;
+        self.instruction(Instruction::Jump { nnn: 0 })?;
+        Ok(position)
+    }
+
+    fn patch_jump(&mut self, position: usize, address: usize) -> Result<(), String> {
+        self.apply(position, Patch::Address, address as u16)
+    }
+
+    fn instruction(&mut self, instruction: Instruction) -> Result<(), String> {
+        if instruction.machine() > self.compiler.machine {
+            let name = |address: u16, _| format!("{:#X}", address);
+            return Err(format!(
+                "{} needs a newer machine than {:?}",
+                disassembler::mnemonic(instruction, 0, Syntax::Octo, &name),
+                self.compiler.machine
+            ));
+        }
+
+        self.emit_word(instruction.opcode());
+        Ok(())
+    }
+
+    fn emit_word(&mut self, word: u16) {
+        self.emit(&word.to_be_bytes());
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.write(self.here, bytes);
+        self.here += bytes.len();
+    }
+
+    fn write(&mut self, address: usize, bytes: &[u8]) {
+        let offset = address - self.compiler.load_address as usize;
+
+        if self.rom.len() < offset + bytes.len() {
+            self.rom.resize(offset + bytes.len(), 0);
+        }
+        self.rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn read(&self, address: usize) -> u16 {
+        let offset = address - self.compiler.load_address as usize;
+
+        (self.rom[offset] as u16) << 8 | self.rom[offset + 1] as u16
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .pop()
+            .ok_or_else(|| String::from("unexpected end of the program"))?;
+        self.line = token.line;
+
+        Ok(token)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), String> {
+        let token = self.next()?;
+
+        if token.text != text {
+            return Err(format!("expected {}, got {}", text, token.text));
+        }
+
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let token = self.next()?.text;
+
+        if parse_number(&token).is_some() || self.is_register(&token) {
+            return Err(format!("{} is not a valid name", token));
+        }
+
+        Ok(token)
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        register_number(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.next()?.text;
+        self.register_named(&token)
+    }
+
+    fn register_named(&self, text: &str) -> Result<u8, String> {
+        register_number(text)
+            .or_else(|| self.aliases.get(text).copied())
+            .ok_or_else(|| format!("expected a register, got {}", text))
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let token = self.next()?.text;
+        self.value(&token)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(fit(self.number()?, 0xFF, "byte")? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, String> {
+        Ok(fit(self.number()?, 0xF, "nibble")? as u8)
+    }
+
+    fn value(&self, text: &str) -> Result<f64, String> {
+        parse_number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .ok_or_else(|| format!("expected a number, got {}", text))
+    }
+}
+
+// The skip that skips when the given one does not.
+fn invert(skip: Instruction) -> Instruction {
+    use Instruction::*;
+
+    match skip {
+        SkipIfEqual { x, nn } => SkipIfNotEqual { x, nn },
+        SkipIfNotEqual { x, nn } => SkipIfEqual { x, nn },
+        SkipIfRegistersEqual { x, y } => SkipIfRegistersNotEqual { x, y },
+        SkipIfRegistersNotEqual { x, y } => SkipIfRegistersEqual { x, y },
+        SkipIfKeyPressed { x } => SkipIfKeyNotPressed { x },
+        SkipIfKeyNotPressed { x } => SkipIfKeyPressed { x },
+        other => other,
+    }
+}
+
+// Check a value fits in a field, allowing negative numbers down to the
+// two's complement of the field. Fractions from `:calc` are dropped.
+fn fit(value: f64, limit: i64, kind: &str) -> Result<u16, String> {
+    let value = value.floor() as i64;
+
+    if value > limit || value < -(limit + 1) / 2 {
+        return Err(format!("{} does not fit in a {}", value, kind));
+    }
+
+    Ok((value & limit) as u16)
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn register_number(text: &str) -> Option<u8> {
+    match text.strip_prefix(['v', 'V']) {
+        Some(digit) if digit.len() == 1 => u8::from_str_radix(digit, 16).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> Vec<u8> {
+        Compiler::default().compile(source).unwrap()
+    }
+
+    fn failure(source: &str) -> (usize, String) {
+        match Compiler::default().compile(source) {
+            Err(EmulatorError::InvalidSource { line, message, .. }) => (line, message),
+            result => panic!("expected a source error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn starts_at_main() {
+        // Main first needs no jump to it
+        assert_eq!(
+            compile(": main\n  v0 := 5\n  v1 := v0\n  loop again"),
+            [0x60, 0x05, 0x81, 0x00, 0x12, 0x04]
+        );
+
+        assert_eq!(
+            compile(
+                ":const SPEED 2
+                : draw
+                    sprite v0 v1 4
+                    return
+                : main
+                    v0 += SPEED
+                    draw"
+            ),
+            [0x12, 0x06, 0xD0, 0x14, 0x00, 0xEE, 0x70, 0x02, 0x22, 0x02]
+        );
+
+        // A label right before main keeps the jump, so its address holds
+        assert_eq!(
+            compile(": foo\n: main\n  jump foo"),
+            [0x12, 0x02, 0x12, 0x02]
+        );
+        assert_eq!(
+            compile(":org 0x202\n: main\n  jump 0x202"),
+            [0x12, 0x02, 0x12, 0x02]
+        );
+    }
+
+    #[test]
+    fn expands_macros() {
+        assert_eq!(
+            compile(":macro twice x { x += 1 x += 1 }\n: main twice v3 twice v4"),
+            [0x73, 0x01, 0x73, 0x01, 0x74, 0x01, 0x74, 0x01]
+        );
+        assert_eq!(
+            failure(":macro forever { forever }\n: main\nforever"),
+            (3, String::from("forever expands into itself too deeply"))
+        );
+    }
+
+    #[test]
+    fn compiles_if_and_else() {
+        assert_eq!(
+            compile(
+                ": main
+                    if v0 == 3 then v1 := 1
+                    if v2 != v3 begin
+                        clear
+                    else
+                        return
+                    end"
+            ),
+            [0x40, 0x03, 0x61, 0x01, 0x92, 0x30, 0x12, 0x0C, 0x00, 0xE0, 0x12, 0x0E, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn compiles_loops() {
+        // While breaks out to after the again when v0 < 10 is false, which
+        // is when v0 - 10 does not borrow
+        assert_eq!(
+            compile(": main\n  loop\n    v0 += 1\n    while v0 < 10\n  again"),
+            [0x70, 0x01, 0x6F, 0x0A, 0x8F, 0x07, 0x3F, 0x00, 0x12, 0x0C, 0x12, 0x00]
+        );
+    }
+
+    #[test]
+    fn compares_registers_through_vf() {
+        let comparisons = [
+            ("<", [0x8F, 0x10, 0x8F, 0x25, 0x4F, 0x00]),
+            (">=", [0x8F, 0x10, 0x8F, 0x25, 0x3F, 0x00]),
+            (">", [0x8F, 0x20, 0x8F, 0x15, 0x4F, 0x00]),
+            ("<=", [0x8F, 0x20, 0x8F, 0x15, 0x3F, 0x00]),
+        ];
+
+        for (operator, expected) in comparisons.iter() {
+            let source = format!(": main if v1 {} v2 then v3 := 0", operator);
+            let rom = compile(&source);
+
+            assert_eq!(rom[..6], expected[..], "{}", operator);
+            assert_eq!(rom[6..], [0x63, 0x00], "{}", operator);
+        }
+    }
+
+    #[test]
+    fn patches_labels_defined_later() {
+        assert_eq!(
+            compile(": main\n  i := data\n  :unpack 0xA data\n  jump main\n: data 0xFF"),
+            [0xA2, 0x08, 0x60, 0xA2, 0x61, 0x08, 0x12, 0x00, 0xFF]
+        );
+    }
+
+    #[test]
+    fn reports_errors_by_line() {
+        assert_eq!(
+            failure(": main\n  jump nowhere"),
+            (2, String::from("undefined name: nowhere"))
+        );
+        assert_eq!(
+            failure(": main\n\n  if v0 == 1 begin\n  clear"),
+            (3, String::from("if without a matching end"))
+        );
+        assert_eq!(
+            failure(": main\n  v0 := 256"),
+            (2, String::from("256 does not fit in a byte"))
+        );
+        assert_eq!(
+            failure(": main\n  :calc X { 1 << 64 }"),
+            (2, String::from("shift out of range"))
+        );
+        assert_eq!(
+            failure(": main\n  :calc X { 8 >> -1 }"),
+            (2, String::from("shift out of range"))
+        );
+        assert_eq!(
+            failure("clear"),
+            (1, String::from("there is no main label"))
+        );
+    }
+
+    #[test]
+    fn rejects_programs_too_large_to_load() {
+        let compiler = Compiler {
+            load_address: 0x2000,
+            ..Compiler::default()
+        };
+
+        assert!(matches!(
+            compiler.compile(": main clear"),
+            Err(EmulatorError::RomTooLarge {
+                size: 2,
+                capacity: 0
+            })
+        ));
+    }
+}
@@ -0,0 +1,446 @@
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use crate::scheduler::{Scheduler, FRAMES_PER_SECOND};
+use crate::trace::NullSink;
+use std::collections::VecDeque;
+use std::mem;
+
+// By default the last ten seconds can be rewound, with a full state kept for
+// every second of that.
+pub const DEFAULT_CAPACITY: usize = 10 * FRAMES_PER_SECOND as usize;
+pub const DEFAULT_KEYFRAME_INTERVAL: usize = FRAMES_PER_SECOND as usize;
+
+// A state recorded at the start of a frame, along with the scheduler's
+// position so that the same instructions run in the same frames on replay.
+struct Entry {
+    frame: u64,
+    cycles: u64,
+    remainder: u32,
+    snapshot: Snapshot,
+}
+
+enum Snapshot {
+    // A full save state
+    Keyframe(Vec<u8>),
+    // The difference from the most recent keyframe before it, see `diff`
+    Delta(Vec<u8>),
+}
+
+// Keeps the states at the start of recent frames so that execution can be
+// stepped backwards. Only every so many frames is a full state stored; the
+// rest are stored as their differences from the last full one, which for most
+// programs is little more than the registers and a few bytes of memory.
+pub struct Rewind {
+    // Number of frames to keep
+    pub capacity: usize,
+    // Frames between full states
+    pub keyframe_interval: usize,
+
+    entries: VecDeque<Entry>,
+}
+
+impl Default for Rewind {
+    fn default() -> Rewind {
+        Rewind::new(DEFAULT_CAPACITY, DEFAULT_KEYFRAME_INTERVAL)
+    }
+}
+
+impl Rewind {
+    pub fn new(capacity: usize, keyframe_interval: usize) -> Rewind {
+        Rewind {
+            capacity,
+            keyframe_interval: keyframe_interval.max(1),
+            entries: VecDeque::new(),
+        }
+    }
+
+    // Number of frames that can currently be stepped back over
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // Remember the current state. Call this between frames, before running
+    // the next one. Anything recorded at or after the current frame is from
+    // a timeline that was rewound away from, and is dropped.
+    pub fn record(&mut self, cpu: &CPU, scheduler: &Scheduler) {
+        if self.capacity == 0 || scheduler.in_frame {
+            return;
+        }
+
+        self.truncate_from(scheduler.frame);
+
+        // Frames recorded since the last keyframe, including it
+        let since_keyframe = self
+            .entries
+            .iter()
+            .rev()
+            .position(|entry| matches!(entry.snapshot, Snapshot::Keyframe(_)))
+            .map_or(usize::MAX, |position| position + 1);
+
+        let state = cpu.save_state();
+        let snapshot = match self.keyframe(self.entries.len()) {
+            Some(keyframe)
+                if since_keyframe < self.keyframe_interval && keyframe.len() == state.len() =>
+            {
+                Snapshot::Delta(diff(keyframe, &state))
+            }
+            _ => Snapshot::Keyframe(state),
+        };
+
+        self.entries.push_back(Entry {
+            frame: scheduler.frame,
+            cycles: scheduler.cycles,
+            remainder: scheduler.remainder,
+            snapshot,
+        });
+
+        // Drop the oldest keyframe along with the deltas that depend on it
+        if self.entries.len() > self.capacity {
+            self.entries.pop_front();
+
+            while let Some(Entry {
+                snapshot: Snapshot::Delta(_),
+                ..
+            }) = self.entries.front()
+            {
+                self.entries.pop_front();
+            }
+        }
+    }
+
+    // Go back to the start of the current frame, or to the start of the
+    // previous one when already between frames. Returns false when there is
+    // nothing recorded to go back to.
+    pub fn step_back_frame(
+        &mut self,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+    ) -> Result<bool, EmulatorError> {
+        let target = match (scheduler.in_frame, scheduler.frame) {
+            (true, frame) => frame,
+            (false, 0) => return Ok(false),
+            (false, frame) => frame - 1,
+        };
+
+        match self.entries.iter().rposition(|entry| entry.frame == target) {
+            Some(index) => {
+                self.restore(index, cpu, scheduler)?;
+                self.truncate_from(target + 1);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Go back to just before the last instruction executed, by returning to
+    // the start of its frame and running forward again. The trace sink and
+    // frame hook are silenced while instructions are replayed. Returns false
+    // when there is nothing recorded to go back to.
+    pub fn step_back_instruction(
+        &mut self,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+    ) -> Result<bool, EmulatorError> {
+        let target = match scheduler.cycles.checked_sub(1) {
+            Some(target) => target,
+            None => return Ok(false),
+        };
+
+        let index = match self
+            .entries
+            .iter()
+            .rposition(|entry| entry.cycles <= target)
+        {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+
+        self.restore(index, cpu, scheduler)?;
+
+        let tracer = mem::replace(&mut cpu.tracer, Box::new(NullSink));
+        let frame_hook = scheduler.frame_hook.take();
+        let mut result = Ok(());
+
+        while scheduler.cycles < target {
+            if let Err(e) = scheduler.step(cpu) {
+                result = Err(e);
+                break;
+            }
+        }
+
+        cpu.tracer = tracer;
+        scheduler.frame_hook = frame_hook;
+        result?;
+
+        // The frame the replay stopped in can be recorded again once it ends
+        self.truncate_from(scheduler.frame + 1);
+
+        Ok(true)
+    }
+
+    fn restore(
+        &self,
+        index: usize,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler,
+    ) -> Result<(), EmulatorError> {
+        let entry = &self.entries[index];
+
+        match &entry.snapshot {
+            Snapshot::Keyframe(state) => cpu.restore_state(state)?,
+            Snapshot::Delta(delta) => {
+                let mut state = self
+                    .keyframe(index)
+                    .ok_or_else(|| {
+                        EmulatorError::InvalidSaveState(String::from("missing rewind keyframe"))
+                    })?
+                    .to_vec();
+                patch(&mut state, delta);
+                cpu.restore_state(&state)?;
+            }
+        }
+
+        scheduler.frame = entry.frame;
+        scheduler.cycles = entry.cycles;
+        scheduler.remainder = entry.remainder;
+        scheduler.pending = 0;
+        scheduler.in_frame = false;
+
+        Ok(())
+    }
+
+    // Drop everything recorded at or after `frame`.
+    fn truncate_from(&mut self, frame: u64) {
+        while self
+            .entries
+            .back()
+            .is_some_and(|entry| entry.frame >= frame)
+        {
+            self.entries.pop_back();
+        }
+    }
+
+    // The most recent keyframe before the entry at `index`
+    fn keyframe(&self, index: usize) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .take(index)
+            .rev()
+            .find_map(|entry| match &entry.snapshot {
+                Snapshot::Keyframe(state) => Some(state.as_slice()),
+                Snapshot::Delta(_) => None,
+            })
+    }
+}
+
+// Encode `state` against a `base` of the same length as a list of runs, each
+// written as the number of unchanged bytes to skip, the number of changed
+// bytes, and those bytes. Lengths are 32-bit big-endian.
+fn diff(base: &[u8], state: &[u8]) -> Vec<u8> {
+    // Short unchanged stretches are cheaper to copy than to start a new run
+    const MINIMUM_SKIP: usize = 8;
+
+    let mut delta = Vec::new();
+    let mut position = 0;
+
+    while position < state.len() {
+        let skip = state[position..]
+            .iter()
+            .zip(&base[position..])
+            .take_while(|(new, old)| new == old)
+            .count();
+        let start = position + skip;
+
+        if start == state.len() {
+            break;
+        }
+
+        // Extend the run of changes until enough unchanged bytes follow
+        let mut end = start;
+        let mut unchanged = 0;
+
+        while end < state.len() && unchanged < MINIMUM_SKIP {
+            if state[end] == base[end] {
+                unchanged += 1;
+            } else {
+                unchanged = 0;
+            }
+            end += 1;
+        }
+
+        end -= unchanged;
+
+        delta.extend_from_slice(&(skip as u32).to_be_bytes());
+        delta.extend_from_slice(&((end - start) as u32).to_be_bytes());
+        delta.extend_from_slice(&state[start..end]);
+        position = end;
+    }
+
+    delta
+}
+
+// Apply a delta produced by `diff` to a copy of its base.
+fn patch(state: &mut [u8], delta: &[u8]) {
+    let read_length = |at: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&delta[at..at + 4]);
+        u32::from_be_bytes(bytes) as usize
+    };
+
+    let mut position = 0;
+    let mut offset = 0;
+
+    while offset < delta.len() {
+        let skip = read_length(offset);
+        let length = read_length(offset + 4);
+        offset += 8;
+
+        position += skip;
+        state[position..position + length].copy_from_slice(&delta[offset..offset + length]);
+        position += length;
+        offset += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CPU adding one to V0 and jumping back forever, so that every
+    // instruction leaves a different state
+    fn counting_cpu() -> CPU {
+        let mut cpu = CPU::default();
+        cpu.load_program_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        cpu.delay_timer = 200;
+        cpu
+    }
+
+    #[test]
+    fn patches_deltas_back_into_states() {
+        let base: Vec<u8> = (0..100).collect();
+        let mut state = base.clone();
+        state[3] = 0xFF;
+        state[5] = 0xFF;
+        state[60..70].copy_from_slice(&[0; 10]);
+        state[99] = 0;
+
+        let delta = diff(&base, &state);
+        let mut patched = base.clone();
+        patch(&mut patched, &delta);
+
+        assert_eq!(patched, state);
+        assert!(diff(&base, &base).is_empty());
+    }
+
+    #[test]
+    fn steps_back_to_the_exact_start_of_each_frame() {
+        let mut cpu = counting_cpu();
+        let mut scheduler = Scheduler::new(100);
+        let mut rewind = Rewind::new(100, 4);
+        let mut states = Vec::new();
+
+        for _ in 0..20 {
+            rewind.record(&cpu, &scheduler);
+            states.push((cpu.save_state(), scheduler.frame, scheduler.cycles));
+            scheduler.run_frame(&mut cpu).unwrap();
+        }
+
+        while let Some((state, frame, cycles)) = states.pop() {
+            assert!(rewind.step_back_frame(&mut cpu, &mut scheduler).unwrap());
+            assert_eq!(cpu.save_state(), state);
+            assert_eq!((scheduler.frame, scheduler.cycles), (frame, cycles));
+        }
+
+        // The first frame stays recorded, as it is the one now running
+        assert!(!rewind.step_back_frame(&mut cpu, &mut scheduler).unwrap());
+        assert_eq!(rewind.len(), 1);
+    }
+
+    #[test]
+    fn steps_back_one_instruction_at_a_time() {
+        let mut cpu = counting_cpu();
+        let mut scheduler = Scheduler::new(100);
+        let mut rewind = Rewind::default();
+        let mut states = vec![cpu.save_state()];
+
+        // Step through several frames, recording at the start of each
+        for _ in 0..10 {
+            if !scheduler.in_frame {
+                rewind.record(&cpu, &scheduler);
+            }
+            scheduler.step(&mut cpu).unwrap();
+            states.push(cpu.save_state());
+        }
+        states.pop();
+
+        while let Some(state) = states.pop() {
+            assert!(rewind
+                .step_back_instruction(&mut cpu, &mut scheduler)
+                .unwrap());
+            assert_eq!(cpu.save_state(), state);
+            assert_eq!(scheduler.cycles, states.len() as u64);
+        }
+
+        assert!(!rewind
+            .step_back_instruction(&mut cpu, &mut scheduler)
+            .unwrap());
+    }
+
+    #[test]
+    fn replays_the_same_frames_after_rewinding() {
+        let mut cpu = counting_cpu();
+        let mut scheduler = Scheduler::new(100);
+        let mut rewind = Rewind::default();
+
+        for _ in 0..5 {
+            rewind.record(&cpu, &scheduler);
+            scheduler.run_frame(&mut cpu).unwrap();
+        }
+        let ahead = (cpu.save_state(), scheduler.cycles);
+
+        rewind.step_back_frame(&mut cpu, &mut scheduler).unwrap();
+        rewind.step_back_frame(&mut cpu, &mut scheduler).unwrap();
+        assert_eq!((scheduler.frame, rewind.len()), (3, 4));
+
+        for _ in 0..2 {
+            rewind.record(&cpu, &scheduler);
+            scheduler.run_frame(&mut cpu).unwrap();
+        }
+
+        assert_eq!((cpu.save_state(), scheduler.cycles), ahead);
+        assert_eq!(rewind.len(), 5);
+    }
+
+    #[test]
+    fn drops_the_oldest_frames_past_capacity() {
+        let mut cpu = counting_cpu();
+        let mut scheduler = Scheduler::new(100);
+        let mut rewind = Rewind::new(6, 3);
+
+        for _ in 0..20 {
+            rewind.record(&cpu, &scheduler);
+            scheduler.run_frame(&mut cpu).unwrap();
+        }
+
+        // Every remaining delta still has its keyframe
+        assert!(rewind.len() <= 6);
+        assert!(matches!(
+            rewind.entries.front().unwrap().snapshot,
+            Snapshot::Keyframe(_)
+        ));
+
+        let mut frames = 0;
+        while rewind.step_back_frame(&mut cpu, &mut scheduler).unwrap() {
+            frames += 1;
+        }
+        assert_eq!(scheduler.frame, 20 - frames);
+    }
+}
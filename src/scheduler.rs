@@ -0,0 +1,295 @@
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The delay and sound timers count down at 60Hz, so emulated time advances in
+// frames of 1/60th of a second.
+pub const FRAMES_PER_SECOND: u32 = 60;
+
+// A reasonable default speed for most CHIP-8 programs
+pub const DEFAULT_INSTRUCTIONS_PER_SECOND: u32 = 700;
+
+// Receives every frame as it ends, after the timers have ticked, for
+// recording or analysing the display over time.
+pub trait FrameHook {
+    fn frame(&mut self, cpu: &CPU, frame: u64) -> Result<(), EmulatorError>;
+
+    // Called once the hook is done with, to complete any output
+    fn finish(&mut self) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+}
+
+// Drives a CPU in frames: each frame runs the instructions that fit into 1/60th
+// of a second of emulated time and then ticks the timers once. Timer rate is
+// therefore tied to emulated time rather than to how fast the host can execute
+// instructions.
+pub struct Scheduler {
+    pub instructions_per_second: u32,
+
+    // Number of frames and instructions run so far
+    pub frame: u64,
+    pub cycles: u64,
+
+    // Stop after this many instructions have been run
+    pub max_cycles: Option<u64>,
+
+    // Called at the end of every frame
+    pub frame_hook: Option<Box<dyn FrameHook>>,
+
+    // Instructions per second rarely divide evenly into 60 frames. The
+    // leftover is carried from frame to frame so that the average rate is
+    // exact and every run with the same rate executes the same instructions
+    // in the same frames.
+    pub(crate) remainder: u32,
+
+    // Instructions left to run in the current frame, when one has been
+    // started by stepping
+    pub(crate) pending: u32,
+    pub(crate) in_frame: bool,
+
+    // Wall-clock deadline for the next frame, used when pacing in real time
+    next_deadline: Option<Instant>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new(DEFAULT_INSTRUCTIONS_PER_SECOND)
+    }
+}
+
+impl Scheduler {
+    // Rates below one instruction a second are raised to one, as a frame that
+    // can never run an instruction would leave `step` waiting forever.
+    pub fn new(instructions_per_second: u32) -> Scheduler {
+        Scheduler {
+            instructions_per_second: instructions_per_second.max(1),
+            frame: 0,
+            cycles: 0,
+            max_cycles: None,
+            frame_hook: None,
+            remainder: 0,
+            pending: 0,
+            in_frame: false,
+            next_deadline: None,
+        }
+    }
+
+    // Run exactly one frame of emulated time without regard to the wall
+    // clock, or the rest of the current one if it was started by `step`.
+    // Returns true once the program has run off the end of memory or the
+    // cycle limit has been reached.
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> Result<bool, EmulatorError> {
+        if !self.in_frame {
+            self.start_frame();
+        }
+
+        while self.pending > 0 {
+            if self.execute(cpu)? {
+                return Ok(true);
+            }
+        }
+
+        self.end_frame(cpu)?;
+
+        Ok(false)
+    }
+
+    // Run a single instruction, ticking the timers after the last instruction
+    // of a frame exactly as `run_frame` would. Returns true under the same
+    // conditions as `run_frame`.
+    pub fn step(&mut self, cpu: &mut CPU) -> Result<bool, EmulatorError> {
+        loop {
+            if !self.in_frame {
+                self.start_frame();
+            }
+
+            // At low rates some frames run no instructions at all
+            if self.pending == 0 {
+                self.end_frame(cpu)?;
+                continue;
+            }
+
+            let reached_end = self.execute(cpu)?;
+            if !reached_end && self.pending == 0 {
+                self.end_frame(cpu)?;
+            }
+
+            return Ok(reached_end);
+        }
+    }
+
+    fn start_frame(&mut self) {
+        self.remainder += self.instructions_per_second.max(1);
+        self.pending = self.remainder / FRAMES_PER_SECOND;
+        self.remainder %= FRAMES_PER_SECOND;
+        self.in_frame = true;
+    }
+
+    fn end_frame(&mut self, cpu: &mut CPU) -> Result<(), EmulatorError> {
+        cpu.tick_timers();
+        self.frame += 1;
+        self.in_frame = false;
+
+        match &mut self.frame_hook {
+            Some(hook) => hook.frame(cpu, self.frame),
+            None => Ok(()),
+        }
+    }
+
+    // Remove the frame hook, letting it complete its output.
+    pub fn finish_frame_hook(&mut self) -> Result<(), EmulatorError> {
+        match self.frame_hook.take() {
+            Some(mut hook) => hook.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn execute(&mut self, cpu: &mut CPU) -> Result<bool, EmulatorError> {
+        if self.max_cycles.is_some_and(|max| self.cycles >= max) {
+            return Ok(true);
+        }
+
+        self.cycles += 1;
+        self.pending -= 1;
+
+        cpu.fetch_decode_execute()
+    }
+
+    // Run one frame, then sleep until it is time for the next one so that
+    // emulation proceeds at real speed.
+    pub fn run_frame_realtime(&mut self, cpu: &mut CPU) -> Result<bool, EmulatorError> {
+        let reached_end = self.run_frame(cpu)?;
+        self.wait_for_next_frame();
+
+        Ok(reached_end)
+    }
+
+    // Sleep until it is time for the next frame.
+    pub fn wait_for_next_frame(&mut self) {
+        let frame_length = Duration::from_secs(1) / FRAMES_PER_SECOND;
+        let now = Instant::now();
+        let deadline = self.next_deadline.unwrap_or(now) + frame_length;
+
+        if deadline > now {
+            thread::sleep(deadline - now);
+            self.next_deadline = Some(deadline);
+        } else {
+            // The host fell behind; start counting again from now rather than
+            // running a burst of frames to catch up.
+            self.next_deadline = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A CPU running `jump 0x200` forever
+    fn looping_cpu() -> CPU {
+        let mut cpu = CPU::default();
+        cpu.load_program_bytes(&[0x12, 0x00]).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn spreads_instructions_evenly_over_frames() {
+        let mut cpu = looping_cpu();
+        let mut scheduler = Scheduler::new(700);
+        let mut counts = Vec::new();
+
+        for _ in 0..FRAMES_PER_SECOND {
+            let before = scheduler.cycles;
+            assert!(!scheduler.run_frame(&mut cpu).unwrap());
+            counts.push(scheduler.cycles - before);
+        }
+
+        assert_eq!(scheduler.cycles, 700);
+        assert_eq!(scheduler.frame, FRAMES_PER_SECOND as u64);
+        assert!(counts.iter().all(|&count| count == 11 || count == 12));
+    }
+
+    #[test]
+    fn ticks_timers_once_per_frame() {
+        let mut cpu = looping_cpu();
+        cpu.delay_timer = 10;
+        cpu.sound_timer = 2;
+        let mut scheduler = Scheduler::new(5000);
+
+        for _ in 0..3 {
+            scheduler.run_frame(&mut cpu).unwrap();
+        }
+
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (7, 0));
+    }
+
+    #[test]
+    fn stepping_matches_running_frames() {
+        let mut stepped = looping_cpu();
+        let mut stepping = Scheduler::new(90);
+        stepped.delay_timer = 100;
+
+        let mut framed = looping_cpu();
+        let mut framing = Scheduler::new(90);
+        framed.delay_timer = 100;
+
+        for _ in 0..10 {
+            framing.run_frame(&mut framed).unwrap();
+        }
+        while stepping.frame < 10 {
+            stepping.step(&mut stepped).unwrap();
+        }
+
+        assert_eq!(stepping.cycles, framing.cycles);
+        assert_eq!(stepped.delay_timer, framed.delay_timer);
+    }
+
+    #[test]
+    fn runs_at_least_one_instruction_a_second() {
+        let mut cpu = looping_cpu();
+        let mut scheduler = Scheduler::new(0);
+
+        assert!(!scheduler.step(&mut cpu).unwrap());
+        assert_eq!((scheduler.cycles, scheduler.frame), (1, 60));
+    }
+
+    #[test]
+    fn stops_at_the_cycle_limit() {
+        let mut cpu = looping_cpu();
+        let mut scheduler = Scheduler::new(700);
+        scheduler.max_cycles = Some(20);
+
+        assert!(!scheduler.run_frame(&mut cpu).unwrap());
+        assert!(scheduler.run_frame(&mut cpu).unwrap());
+        assert_eq!(scheduler.cycles, 20);
+    }
+
+    struct Frames(Rc<RefCell<Vec<(u64, u16)>>>);
+
+    impl FrameHook for Frames {
+        fn frame(&mut self, cpu: &CPU, frame: u64) -> Result<(), EmulatorError> {
+            self.0.borrow_mut().push((frame, cpu.delay_timer));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn calls_the_frame_hook_after_the_timers_tick() {
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = looping_cpu();
+        cpu.delay_timer = 5;
+        let mut scheduler = Scheduler::new(700);
+        scheduler.frame_hook = Some(Box::new(Frames(frames.clone())));
+
+        scheduler.run_frame(&mut cpu).unwrap();
+        scheduler.run_frame(&mut cpu).unwrap();
+        scheduler.finish_frame_hook().unwrap();
+        scheduler.run_frame(&mut cpu).unwrap();
+
+        assert_eq!(*frames.borrow(), vec![(1, 4), (2, 3)]);
+    }
+}
@@ -0,0 +1,474 @@
+use crate::cpu::machine::Machine;
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Pixels are enlarged this many times unless told otherwise
+pub const DEFAULT_SCALE: usize = 4;
+
+// The most pixels can be enlarged while the widest display, 128 pixels, stays
+// within the 65535 pixels a GIF can be wide
+pub const MAX_SCALE: usize = u16::MAX as usize / 128;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// Deflate's stored blocks hold at most this many bytes
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// File formats screenshots can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    // Netpbm bitmap, one bit per pixel
+    Pbm,
+    // Netpbm pixmap, 24-bit colour
+    Ppm,
+    Png,
+}
+
+impl Format {
+    // Pick a format from a file's extension.
+    pub fn from_filename(filename: &str) -> Option<Format> {
+        let extension = Path::new(filename).extension()?.to_str()?;
+
+        match extension.to_ascii_lowercase().as_str() {
+            "pbm" => Some(Format::Pbm),
+            "ppm" => Some(Format::Ppm),
+            "png" => Some(Format::Png),
+            _ => None,
+        }
+    }
+}
+
+// Colours for the pixels of the display. With two colours a pixel lit in any
+// plane takes the second one. With four, a pixel takes the colour numbered by
+// the planes it is lit in: the second for the first plane, the third for the
+// second plane and the fourth for both.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub colours: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            colours: vec![[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF]],
+        }
+    }
+}
+
+impl Palette {
+    // White on black, with greys for XO-CHIP's second plane
+    pub fn for_machine(machine: Machine) -> Palette {
+        match machine {
+            Machine::XoChip => Palette {
+                colours: vec![
+                    [0x00, 0x00, 0x00],
+                    [0xFF, 0xFF, 0xFF],
+                    [0xAA, 0xAA, 0xAA],
+                    [0x55, 0x55, 0x55],
+                ],
+            },
+            _ => Palette::default(),
+        }
+    }
+
+    // Parse two or four comma-separated colours written as `RRGGBB` hex,
+    // with or without a leading `#`.
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let colours = text
+            .split(',')
+            .map(|colour| {
+                let hex = colour.trim().trim_start_matches('#');
+                match u32::from_str_radix(hex, 16) {
+                    Ok(value) if hex.len() == 6 => {
+                        Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+                    }
+                    _ => Err(format!("expected a colour as RRGGBB, got {}", colour)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match colours.len() {
+            2 | 4 => Ok(Palette { colours }),
+            count => Err(format!("expected 2 or 4 colours, got {}", count)),
+        }
+    }
+
+    // The colour a framebuffer pixel is shown in, by its index in the palette
+    fn index(&self, pixel: u8) -> u8 {
+        match self.colours.len() {
+            4 => pixel & 0b11,
+            _ => (pixel != 0) as u8,
+        }
+    }
+}
+
+// A picture of the display, held as palette indices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+    pub palette: Palette,
+}
+
+impl Image {
+    // Capture the framebuffer being displayed, in high or low resolution,
+    // with every pixel enlarged to a `scale` by `scale` square.
+    pub fn capture(cpu: &CPU, scale: usize, palette: Palette) -> Image {
+        let (gfx, width, height) = cpu.display();
+        let mut pixels = Vec::with_capacity(width * height * scale * scale);
+
+        for row in gfx.chunks(width) {
+            let line: Vec<u8> = row
+                .iter()
+                .flat_map(|&pixel| std::iter::repeat_n(palette.index(pixel), scale))
+                .collect();
+
+            for _ in 0..scale {
+                pixels.extend_from_slice(&line);
+            }
+        }
+
+        Image {
+            width: width * scale,
+            height: height * scale,
+            pixels,
+            palette,
+        }
+    }
+
+    // Write the image to a file in the format its extension names.
+    pub fn save(&self, filename: &str) -> Result<(), EmulatorError> {
+        let format = Format::from_filename(filename).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "screenshots must end in .png, .pbm or .ppm",
+            )
+        })?;
+
+        fs::write(filename, self.encode(format))?;
+
+        Ok(())
+    }
+
+    pub fn encode(&self, format: Format) -> Vec<u8> {
+        match format {
+            Format::Pbm => self.pbm(),
+            Format::Ppm => self.ppm(),
+            Format::Png => self.png(),
+        }
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.pixels.chunks(self.width)
+    }
+
+    fn colour(&self, index: u8) -> [u8; 3] {
+        self.palette.colours[index as usize]
+    }
+
+    // Bitmaps have no colours, only black and white. Pixels whose colour is
+    // closer to black are written as black.
+    fn pbm(&self) -> Vec<u8> {
+        let mut output = format!("P4\n{} {}\n", self.width, self.height).into_bytes();
+
+        let black: Vec<bool> = self
+            .palette
+            .colours
+            .iter()
+            .map(|[r, g, b]| (*r as u32 * 299 + *g as u32 * 587 + *b as u32 * 114) < 128_000)
+            .collect();
+
+        for row in self.rows() {
+            for bits in row.chunks(8) {
+                let byte = bits.iter().enumerate().fold(0, |byte, (bit, &index)| {
+                    byte | (black[index as usize] as u8) << (7 - bit)
+                });
+                output.push(byte);
+            }
+        }
+
+        output
+    }
+
+    fn ppm(&self) -> Vec<u8> {
+        let mut output = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+
+        for &index in &self.pixels {
+            output.extend_from_slice(&self.colour(index));
+        }
+
+        output
+    }
+
+    // An 8-bit indexed colour PNG. The image data is stored rather than
+    // compressed, which keeps the encoder small at the cost of file size.
+    fn png(&self) -> Vec<u8> {
+        let mut output = PNG_SIGNATURE.to_vec();
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // Bit depth 8, colour type 3 (indexed), default compression,
+        // filtering and no interlacing
+        header.extend_from_slice(&[8, 3, 0, 0, 0]);
+        chunk(&mut output, b"IHDR", &header);
+
+        let palette: Vec<u8> = self.palette.colours.iter().flatten().copied().collect();
+        chunk(&mut output, b"PLTE", &palette);
+
+        // Every row starts with its filter type, which is none
+        let mut scanlines = Vec::with_capacity((self.width + 1) * self.height);
+        for row in self.rows() {
+            scanlines.push(0);
+            scanlines.extend_from_slice(row);
+        }
+        chunk(&mut output, b"IDAT", &zlib_stored(&scanlines));
+
+        chunk(&mut output, b"IEND", &[]);
+
+        output
+    }
+}
+
+fn chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = output.len();
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+
+    let crc = crc32(&output[start..]);
+    output.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Wrap data in a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no preset dictionary, and a check value
+    // making the header a multiple of 31
+    let mut output = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        output.extend_from_slice(&[1, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+
+        output.push(last as u8);
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+
+    output
+}
+
+// CRC-32 as used by PNG and zlib, with the reversed polynomial 0xEDB88320
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+// The CRC of every byte value, worked out once when compiling
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+
+    while n < table.len() {
+        let mut c = n as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            bit += 1;
+        }
+
+        table[n] = c;
+        n += 1;
+    }
+
+    table
+}
+
+pub fn adler32(bytes: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % MODULUS;
+        (a, (b + a) % MODULUS)
+    });
+
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn image(width: usize, height: usize, pixels: &[u8]) -> Image {
+        Image {
+            width,
+            height,
+            pixels: pixels.to_vec(),
+            palette: Palette::default(),
+        }
+    }
+
+    // Walk the chunks of a PNG, checking their CRCs, and return the image
+    // header along with the scanlines stored in its stored deflate blocks and
+    // how many blocks held them.
+    fn decode_png(png: &[u8]) -> (Vec<u8>, Vec<u8>, usize) {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+
+        let mut position = 8;
+        let mut header = Vec::new();
+        let mut stream = Vec::new();
+        let mut kinds = Vec::new();
+
+        while position < png.len() {
+            let length = u32::from_be_bytes(png[position..position + 4].try_into().unwrap());
+            let end = position + 8 + length as usize;
+            let crc = u32::from_be_bytes(png[end..end + 4].try_into().unwrap());
+            assert_eq!(crc32(&png[position + 4..end]), crc);
+
+            let kind = &png[position + 4..position + 8];
+            match kind {
+                b"IHDR" => header = png[position + 8..end].to_vec(),
+                b"IDAT" => stream.extend_from_slice(&png[position + 8..end]),
+                _ => (),
+            }
+            kinds.push(String::from_utf8_lossy(kind).into_owned());
+            position = end + 4;
+        }
+        assert_eq!(kinds, ["IHDR", "PLTE", "IDAT", "IEND"]);
+
+        // Stored blocks, each a final flag, a length and its complement
+        assert_eq!(stream[..2], [0x78, 0x01]);
+        let mut data = Vec::new();
+        let mut blocks = 0;
+        let mut offset = 2;
+
+        loop {
+            let last = stream[offset];
+            let length = u16::from_le_bytes([stream[offset + 1], stream[offset + 2]]);
+            let complement = u16::from_le_bytes([stream[offset + 3], stream[offset + 4]]);
+            assert_eq!(length, !complement);
+
+            offset += 5;
+            data.extend_from_slice(&stream[offset..offset + length as usize]);
+            offset += length as usize;
+            blocks += 1;
+
+            if last == 1 {
+                break;
+            }
+            assert_eq!(last, 0);
+        }
+
+        assert_eq!(stream[offset..], adler32(&data).to_be_bytes());
+
+        (header, data, blocks)
+    }
+
+    #[test]
+    fn computes_known_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn encodes_bitmaps() {
+        // Rows are padded to a whole byte, and dark colours are set bits
+        let pixels = [1, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        assert_eq!(
+            image(10, 2, &pixels).encode(Format::Pbm),
+            b"P4\n10 2\n\x5F\x80\xFF\xC0"
+        );
+    }
+
+    #[test]
+    fn encodes_pixmaps() {
+        let mut image = image(2, 2, &[0, 1, 2, 3]);
+        image.palette = Palette::for_machine(Machine::XoChip);
+
+        let mut expected = b"P6\n2 2\n255\n".to_vec();
+        expected.extend_from_slice(&[0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF]);
+        expected.extend_from_slice(&[0xAA, 0xAA, 0xAA, 0x55, 0x55, 0x55]);
+
+        assert_eq!(image.encode(Format::Ppm), expected);
+    }
+
+    #[test]
+    fn encodes_small_pngs() {
+        let png = image(3, 2, &[1, 0, 1, 0, 1, 0]).encode(Format::Png);
+        let (header, data, blocks) = decode_png(&png);
+
+        assert_eq!(header, [0, 0, 0, 3, 0, 0, 0, 2, 8, 3, 0, 0, 0]);
+        assert_eq!(data, [0, 1, 0, 1, 0, 0, 1, 0]);
+        assert_eq!(blocks, 1);
+    }
+
+    #[test]
+    fn splits_large_pngs_into_stored_blocks() {
+        // 300 rows of 301 bytes with their filter types
+        let pixels: Vec<u8> = (0..300 * 300).map(|index| (index % 7 == 0) as u8).collect();
+        let (_, data, blocks) = decode_png(&image(300, 300, &pixels).encode(Format::Png));
+
+        assert_eq!(data.len(), 300 * 301);
+        assert_eq!(blocks, 2);
+        for (row, scanline) in data.chunks(301).enumerate() {
+            assert_eq!(scanline[0], 0);
+            assert_eq!(scanline[1..], pixels[row * 300..(row + 1) * 300]);
+        }
+    }
+
+    #[test]
+    fn captures_the_display_scaled_up() {
+        let mut cpu = CPU::default();
+        cpu.gfx[1] = 1;
+
+        let image = Image::capture(&cpu, 2, Palette::default());
+
+        assert_eq!((image.width, image.height), (128, 64));
+        assert_eq!(image.pixels[..4], [0, 0, 1, 1]);
+        assert_eq!(image.pixels[128..132], [0, 0, 1, 1]);
+        assert_eq!(image.pixels.iter().filter(|&&pixel| pixel == 1).count(), 4);
+    }
+
+    #[test]
+    fn parses_palettes_and_formats() {
+        assert_eq!(
+            Palette::parse("#102030, FFFFFF").unwrap().colours,
+            [[0x10, 0x20, 0x30], [0xFF, 0xFF, 0xFF]]
+        );
+        assert_eq!(
+            Palette::parse("000000,FFFFFF,AAAAAA"),
+            Err(String::from("expected 2 or 4 colours, got 3"))
+        );
+        assert_eq!(
+            Palette::parse("000000,white"),
+            Err(String::from("expected a colour as RRGGBB, got white"))
+        );
+        assert_eq!(Format::from_filename("shot.PNG"), Some(Format::Png));
+        assert_eq!(Format::from_filename("shot.gif"), None);
+    }
+}
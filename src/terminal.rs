@@ -0,0 +1,354 @@
+use crate::cpu::CPU;
+use crate::input::{Keymap, Keypad};
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// Terminals only report key presses, never releases, and auto-repeat only
+// starts after a delay. A key is treated as held for this many frames after
+// the last byte received for it.
+const HOLD_FRAMES: u64 = 10;
+
+const ESCAPE: u8 = 0x1B;
+const CTRL_C: u8 = 0x03;
+const CTRL_L: u8 = 0x0C;
+const CTRL_P: u8 = 0x10;
+const CTRL_S: u8 = 0x13;
+const BACKSPACE: u8 = 0x7F;
+const CTRL_H: u8 = 0x08;
+
+// Requests the user made of the frontend rather than of the running program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    // Escape or Ctrl-C
+    Quit,
+    // Ctrl-S
+    SaveState,
+    // Ctrl-L
+    RestoreState,
+    // Ctrl-P
+    Screenshot,
+    // Backspace, for as long as it is held
+    Rewind,
+}
+
+// How framebuffer pixels are packed into character cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    // Each cell covers 1x2 pixels using the upper and lower half block
+    // characters, giving a 64x16 cell display in low resolution.
+    HalfBlock,
+    // Each cell covers 2x4 pixels using braille patterns, giving a 32x8 cell
+    // display in low resolution.
+    Braille,
+}
+
+impl Style {
+    fn cell_size(&self) -> (usize, usize) {
+        match self {
+            Style::HalfBlock => (1, 2),
+            Style::Braille => (2, 4),
+        }
+    }
+
+    // Pack the pixels under a cell into a bit pattern, one bit per pixel.
+    fn pattern(&self, gfx: &[u8], width: usize, column: usize, row: usize) -> u8 {
+        let pixel = |x: usize, y: usize| (gfx[y * width + x] != 0) as u8;
+
+        match self {
+            Style::HalfBlock => {
+                let (x, y) = (column, row * 2);
+                pixel(x, y) | pixel(x, y + 1) << 1
+            }
+            Style::Braille => {
+                // Braille dots are numbered down the left column first, with
+                // the bottom row added later to the standard.
+                let (x, y) = (column * 2, row * 4);
+                pixel(x, y)
+                    | pixel(x, y + 1) << 1
+                    | pixel(x, y + 2) << 2
+                    | pixel(x + 1, y) << 3
+                    | pixel(x + 1, y + 1) << 4
+                    | pixel(x + 1, y + 2) << 5
+                    | pixel(x, y + 3) << 6
+                    | pixel(x + 1, y + 3) << 7
+            }
+        }
+    }
+
+    fn character(&self, pattern: u8) -> char {
+        match self {
+            Style::HalfBlock => [' ', '▀', '▄', '█'][pattern as usize],
+            Style::Braille => std::char::from_u32(0x2800 + pattern as u32).unwrap_or(' '),
+        }
+    }
+}
+
+// A frontend that draws the framebuffer with ANSI escape codes and reads the
+// keypad from raw keyboard input. Creating one switches the terminal into raw
+// mode on the alternate screen; dropping it restores the original settings.
+pub struct Terminal {
+    style: Style,
+
+    // Saved `stty -g` settings to restore on exit
+    saved_settings: String,
+
+    // The pattern last drawn in every cell, so that only cells that changed
+    // need to be redrawn. `None` forces a redraw.
+    cells: Vec<Option<u8>>,
+
+    // Resolution of the framebuffer last drawn
+    resolution: (usize, usize),
+
+    input: Receiver<Vec<u8>>,
+    keymap: Keymap,
+    keypad: Keypad,
+    frame: u64,
+    held_until: [u64; 16],
+    rewind_held_until: u64,
+}
+
+impl Terminal {
+    pub fn new(style: Style, keymap: Keymap) -> io::Result<Terminal> {
+        let saved_settings = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+
+        // Reading stdin blocks, so it happens on a thread of its own and is
+        // handed over through a channel.
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 64];
+
+            while let Ok(count) = io::stdin().read(&mut buffer) {
+                if count == 0 || sender.send(buffer[..count].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut terminal = Terminal {
+            style,
+            saved_settings: saved_settings.trim().to_string(),
+            cells: Vec::new(),
+            resolution: (0, 0),
+            input,
+            keymap,
+            keypad: Keypad::default(),
+            frame: 0,
+            held_until: [0; 16],
+            rewind_held_until: 0,
+        };
+
+        // Switch to the alternate screen, clear it and hide the cursor
+        terminal.write(b"\x1B[?1049h\x1B[2J\x1B[?25l")?;
+
+        Ok(terminal)
+    }
+
+    // Drain pending keyboard input into the CPU's keypad. Returns the last
+    // action asked for since the previous call, if any.
+    pub fn poll_input(&mut self, cpu: &mut CPU) -> Option<Action> {
+        self.frame += 1;
+        let mut action = None;
+
+        loop {
+            match self.input.try_recv() {
+                Ok(bytes) => {
+                    let (bytes, escape) = split_escape_sequences(&bytes);
+
+                    if escape || bytes.contains(&CTRL_C) {
+                        return Some(Action::Quit);
+                    }
+
+                    for byte in bytes {
+                        match byte {
+                            CTRL_S => action = Some(Action::SaveState),
+                            CTRL_L => action = Some(Action::RestoreState),
+                            CTRL_P => action = Some(Action::Screenshot),
+                            BACKSPACE | CTRL_H => self.rewind_held_until = self.frame + HOLD_FRAMES,
+                            _ => {
+                                if let Some(key) = self.keymap.lookup(byte as char) {
+                                    self.held_until[key as usize] = self.frame + HOLD_FRAMES;
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Some(Action::Quit),
+            }
+        }
+
+        for (key, held_until) in self.held_until.iter().enumerate() {
+            if *held_until > self.frame {
+                self.keypad.press(key as u8);
+            } else {
+                self.keypad.release(key as u8);
+            }
+        }
+
+        self.keypad.apply(cpu);
+
+        if action.is_none() && self.rewind_held_until > self.frame {
+            action = Some(Action::Rewind);
+        }
+
+        action
+    }
+
+    // Redraw every cell whose pixels changed since the previous call.
+    pub fn draw(&mut self, gfx: &[u8], width: usize, height: usize) -> io::Result<()> {
+        let (cell_width, cell_height) = self.style.cell_size();
+        let columns = width / cell_width;
+        let rows = height / cell_height;
+
+        let mut output = String::new();
+
+        // Start over with a blank screen when the resolution changes
+        if self.resolution != (width, height) {
+            self.resolution = (width, height);
+            self.cells = vec![None; columns * rows];
+            output.push_str("\x1B[2J");
+        }
+
+        for row in 0..rows {
+            // Whether the cursor already sits where the next cell goes, which
+            // saves repositioning it for runs of changed cells.
+            let mut cursor_in_place = false;
+
+            for column in 0..columns {
+                let pattern = self.style.pattern(gfx, width, column, row);
+                let cell = &mut self.cells[row * columns + column];
+
+                if *cell == Some(pattern) {
+                    cursor_in_place = false;
+                    continue;
+                }
+
+                if !cursor_in_place {
+                    output.push_str(&format!("\x1B[{};{}H", row + 1, column + 1));
+                }
+
+                output.push(self.style.character(pattern));
+                *cell = Some(pattern);
+                cursor_in_place = true;
+            }
+        }
+
+        if !output.is_empty() {
+            self.write(output.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    // Show a line of text below the display, replacing the previous one.
+    pub fn status(&mut self, message: &str) -> io::Result<()> {
+        let rows = self.resolution.1 / self.style.cell_size().1;
+
+        self.write(format!("\x1B[{};1H\x1B[2K{}", rows + 2, message).as_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+
+        handle.write_all(bytes)?;
+        handle.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        // Show the cursor and leave the alternate screen
+        let _ = self.write(b"\x1B[?25h\x1B[?1049l");
+        let _ = stty(&[&self.saved_settings]);
+    }
+}
+
+// Run stty against the terminal attached to stdin, returning its output.
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::other("stdin is not a terminal"));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Remove the arrow and function key sequences terminals send, which start
+// with an escape byte, from a read. Any other escape byte is the Escape key
+// itself. Returns the remaining bytes and whether Escape was pressed.
+fn split_escape_sequences(bytes: &[u8]) -> (Vec<u8>, bool) {
+    let mut remaining = Vec::with_capacity(bytes.len());
+    let mut escape = false;
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] != ESCAPE {
+            remaining.push(bytes[index]);
+            index += 1;
+            continue;
+        }
+
+        index += match bytes.get(index + 1) {
+            // Control sequences run up to a final byte from @ to ~
+            Some(b'[') => bytes[index + 2..]
+                .iter()
+                .position(|byte| (0x40..=0x7E).contains(byte))
+                .map_or(bytes.len() - index, |end| end + 3),
+            // F1 to F4 are sent as ESC O and a letter
+            Some(b'O') if index + 2 < bytes.len() => 3,
+            _ => {
+                escape = true;
+                1
+            }
+        };
+    }
+
+    (remaining, escape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_a_lone_escape() {
+        assert_eq!(split_escape_sequences(&[ESCAPE]), (vec![], true));
+        assert_eq!(split_escape_sequences(b"12"), (b"12".to_vec(), false));
+    }
+
+    #[test]
+    fn drops_arrow_and_function_keys() {
+        assert_eq!(
+            split_escape_sequences(b"\x1B[A1\x1B[15~2\x1BOP"),
+            (b"12".to_vec(), false)
+        );
+    }
+
+    #[test]
+    fn keeps_keys_typed_after_escape() {
+        assert_eq!(split_escape_sequences(b"\x1Bq4"), (b"q4".to_vec(), true));
+        assert_eq!(split_escape_sequences(b"w\x1B"), (b"w".to_vec(), true));
+    }
+
+    #[test]
+    fn renders_half_blocks() {
+        let mut gfx = [0; 64 * 32];
+        gfx[0] = 1;
+        gfx[64 + 1] = 1;
+
+        assert_eq!(Style::HalfBlock.pattern(&gfx, 64, 0, 0), 0b01);
+        assert_eq!(Style::HalfBlock.pattern(&gfx, 64, 1, 0), 0b10);
+        assert_eq!(Style::HalfBlock.pattern(&gfx, 64, 2, 0), 0b00);
+        assert_eq!(Style::HalfBlock.character(0b10), '▄');
+        assert_eq!(Style::Braille.pattern(&gfx, 64, 0, 0), 0b0001_0001);
+    }
+}
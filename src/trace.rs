@@ -0,0 +1,309 @@
+use crate::cpu::instruction::Instruction;
+use crate::cpu::CPU;
+use std::fmt;
+use std::io::{self, Write};
+
+// A register whose value can be reported as changed by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    Sp,
+    DelayTimer,
+    SoundTimer,
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Sp => write!(f, "SP"),
+            Register::DelayTimer => write!(f, "DT"),
+            Register::SoundTimer => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub register: Register,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// A range of memory read or written by an instruction. Fetching instructions
+// is not counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub access: Access,
+    pub start: u16,
+    pub length: u16,
+}
+
+// One executed instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    // Address the instruction was fetched from
+    pub pc: u16,
+    pub opcode: u16,
+    pub instruction: Instruction,
+    // Where execution continues afterwards
+    pub next_pc: u16,
+    // Every register the instruction changed, in register order
+    pub changes: Vec<RegisterChange>,
+}
+
+// Receives an event for every instruction the CPU executes.
+pub trait TraceSink {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()>;
+
+    // Building events has a cost, so sinks that discard them can opt out
+    // entirely.
+    fn enabled(&self) -> bool {
+        true
+    }
+}
+
+// Discards every event. This is what a CPU starts with.
+pub struct NullSink;
+
+impl TraceSink for NullSink {
+    fn record(&mut self, _event: &TraceEvent) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+// Writes one human-readable line per instruction, e.g.
+//
+//   0x0200  6A02  Constant             SetConstant { x: 10, nn: 2 }  VA: 0x00 -> 0x02
+pub struct LogSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> LogSink<W> {
+    pub fn new(writer: W) -> LogSink<W> {
+        LogSink { writer }
+    }
+}
+
+impl<W: Write> TraceSink for LogSink<W> {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        write!(
+            self.writer,
+            "{:#06X}  {:04X}  {:<20} {:?}",
+            event.pc,
+            event.opcode,
+            event.instruction.category(),
+            event.instruction
+        )?;
+
+        for change in &event.changes {
+            write!(
+                self.writer,
+                "  {}: {:#04X} -> {:#04X}",
+                change.register, change.old, change.new
+            )?;
+        }
+
+        writeln!(self.writer)
+    }
+}
+
+// Writes one JSON object per instruction, one per line, e.g.
+//
+//   {"pc":512,"opcode":27138,"instruction":"SetConstant","mnemonic":"LD",
+//    "operands":{"x":10,"nn":2},"category":"Constant","next_pc":514,
+//    "changes":[{"register":"VA","old":0,"new":2}]}
+//
+// (shown wrapped here; each event is written on a single line). The mnemonic
+// is the operation from Cowgod's reference, and is shared by instructions
+// that differ in their operands.
+pub struct JsonSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer: W) -> JsonSink<W> {
+        JsonSink { writer }
+    }
+}
+
+impl<W: Write> TraceSink for JsonSink<W> {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let changes: Vec<String> = event
+            .changes
+            .iter()
+            .map(|change| {
+                format!(
+                    "{{\"register\":\"{}\",\"old\":{},\"new\":{}}}",
+                    change.register, change.old, change.new
+                )
+            })
+            .collect();
+
+        let operands: Vec<String> = event
+            .instruction
+            .operands()
+            .iter()
+            .map(|(name, value)| format!("\"{}\":{}", name, value))
+            .collect();
+
+        writeln!(
+            self.writer,
+            "{{\"pc\":{},\"opcode\":{},\"instruction\":\"{}\",\"mnemonic\":\"{}\",\"operands\":{{{}}},\"category\":\"{}\",\"next_pc\":{},\"changes\":[{}]}}",
+            event.pc,
+            event.opcode,
+            event.instruction.name(),
+            event.instruction.mnemonic(),
+            operands.join(","),
+            escape_json(event.instruction.category()),
+            event.next_pc,
+            changes.join(",")
+        )
+    }
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// The registers tracked for changes, captured before an instruction runs.
+pub(crate) struct Registers {
+    v: [u8; 16],
+    i: u16,
+    sp: u16,
+    delay_timer: u16,
+    sound_timer: u16,
+}
+
+impl Registers {
+    pub(crate) fn capture(cpu: &CPU) -> Registers {
+        Registers {
+            v: cpu.v,
+            i: cpu.i,
+            sp: cpu.sp,
+            delay_timer: cpu.delay_timer,
+            sound_timer: cpu.sound_timer,
+        }
+    }
+
+    // List the registers whose values differ in `cpu` from this capture.
+    pub(crate) fn changes(&self, cpu: &CPU) -> Vec<RegisterChange> {
+        let mut changes = Vec::new();
+        let mut compare = |register, old: u16, new: u16| {
+            if old != new {
+                changes.push(RegisterChange { register, old, new });
+            }
+        };
+
+        for x in 0..16 {
+            compare(Register::V(x as u8), self.v[x] as u16, cpu.v[x] as u16);
+        }
+
+        compare(Register::I, self.i, cpu.i);
+        compare(Register::Sp, self.sp, cpu.sp);
+        compare(Register::DelayTimer, self.delay_timer, cpu.delay_timer);
+        compare(Register::SoundTimer, self.sound_timer, cpu.sound_timer);
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(instruction: Instruction) -> TraceEvent {
+        TraceEvent {
+            pc: 0x200,
+            opcode: instruction.opcode(),
+            instruction,
+            next_pc: 0x202,
+            changes: vec![RegisterChange {
+                register: Register::V(0xA),
+                old: 0,
+                new: 2,
+            }],
+        }
+    }
+
+    fn json(instruction: Instruction) -> String {
+        let mut output = Vec::new();
+        JsonSink::new(&mut output)
+            .record(&event(instruction))
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn writes_json_with_typed_operands() {
+        assert_eq!(
+            json(Instruction::SetConstant { x: 10, nn: 2 }),
+            "{\"pc\":512,\"opcode\":27138,\"instruction\":\"SetConstant\",\"mnemonic\":\"LD\",\
+             \"operands\":{\"x\":10,\"nn\":2},\"category\":\"Constant\",\"next_pc\":514,\
+             \"changes\":[{\"register\":\"VA\",\"old\":0,\"new\":2}]}\n"
+        );
+
+        assert!(json(Instruction::ClearScreen)
+            .contains("\"instruction\":\"ClearScreen\",\"mnemonic\":\"CLS\",\"operands\":{}"));
+    }
+
+    #[test]
+    fn writes_log_lines() {
+        let mut output = Vec::new();
+        LogSink::new(&mut output)
+            .record(&event(Instruction::SetConstant { x: 10, nn: 2 }))
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "0x0200  6A02  Constant             SetConstant { x: 10, nn: 2 }  VA: 0x00 -> 0x02\n"
+        );
+    }
+
+    #[test]
+    fn lists_changed_registers() {
+        let mut cpu = CPU::default();
+        let before = Registers::capture(&cpu);
+        cpu.v[3] = 7;
+        cpu.i = 0x300;
+
+        assert_eq!(
+            before.changes(&cpu),
+            vec![
+                RegisterChange {
+                    register: Register::V(3),
+                    old: 0,
+                    new: 7
+                },
+                RegisterChange {
+                    register: Register::I,
+                    old: 0,
+                    new: 0x300
+                },
+            ]
+        );
+    }
+}